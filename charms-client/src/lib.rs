@@ -1,6 +1,7 @@
-use crate::tx::extract_and_verify_spell;
+use crate::tx::{extract_and_verify_spell, SpellError};
 use bitcoin::hashes::Hash;
 use charms_data::{App, Charms, Data, Transaction, TxId, UtxoId};
+use core::fmt;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet};
 
@@ -19,6 +20,14 @@ pub const V2: u32 = 2u32;
 /// Current version of the protocol.
 pub const CURRENT_VERSION: u32 = V2;
 
+/// Spell verification key for mock (dev) proofs: see [`tx::mock_proof`].
+///
+/// Spells with mock proofs commit to this value instead of the `charms-spell-checker`
+/// verification key. Verifying spells with `MOCK_SPELL_VK` explicitly enables accepting mock
+/// proofs (e.g. on regtest). Real proofs never commit to this value, so spells with real proofs
+/// can't be built on top of mock ones.
+pub const MOCK_SPELL_VK: &str = "mock";
+
 /// Maps the index of the charm's app (in [`NormalizedSpell`].`app_public_inputs`) to the charm's
 /// data.
 pub type NormalizedCharms = BTreeMap<usize, Data>;
@@ -65,69 +74,167 @@ pub struct NormalizedSpell {
 }
 
 /// Extract spells from previous transactions.
+/// Maps transaction IDs to the extracted spell (or the reason there's no correct spell in the
+/// transaction) and the number of transaction outputs.
 pub fn prev_spells(
     prev_txs: &Vec<bitcoin::Transaction>,
     spell_vk: &str,
-) -> BTreeMap<TxId, (Option<NormalizedSpell>, usize)> {
+) -> BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)> {
     prev_txs
         .iter()
         .map(|tx| {
             let tx_id = TxId(tx.compute_txid().to_byte_array());
             (
                 tx_id,
-                (
-                    extract_and_verify_spell(tx, spell_vk)
-                        .map_err(|e| {
-                            eprintln!("no correct spell in tx {}: {}", tx_id, e);
-                        })
-                        .ok(),
-                    tx.output.len(),
-                ),
+                (extract_and_verify_spell(tx, spell_vk), tx.output.len()),
             )
         })
         .collect()
 }
 
-/// Check if the spell is well-formed.
+/// Well-formedness rule violated by a spell. See [`well_formed_violations`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WellFormedViolation {
+    /// The spell version is not [`CURRENT_VERSION`].
+    UnsupportedVersion(u32),
+    /// The spell does not list its inputs (`tx.ins` is `None`).
+    NoInputs,
+    /// A charm in output `out` refers to an app index not in `app_public_inputs`.
+    AppIndexOutOfRange { out: usize, app_index: usize },
+    /// A charm in output `out` has empty data.
+    EmptyCharm { out: usize, app_index: usize },
+    /// The UTXO is spent more than once.
+    DuplicateInput(UtxoId),
+    /// The UTXO is both referenced and spent.
+    RefAlsoSpent(UtxoId),
+    /// The spent UTXO is not created by any of the pre-requisite transactions.
+    InputNotCreatedByPrevTx(UtxoId),
+    /// The referenced UTXO is not created by any of the pre-requisite transactions.
+    RefNotCreatedByPrevTx(UtxoId),
+}
+
+impl fmt::Display for WellFormedViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WellFormedViolation::UnsupportedVersion(version) => write!(
+                f,
+                "spell version {} is not the current version {}",
+                version, CURRENT_VERSION
+            ),
+            WellFormedViolation::NoInputs => write!(f, "no tx.ins"),
+            WellFormedViolation::AppIndexOutOfRange { out, app_index } => write!(
+                f,
+                "output {}: charm app index {} is not in app_public_inputs",
+                out, app_index
+            ),
+            WellFormedViolation::EmptyCharm { out, app_index } => {
+                write!(f, "output {}: charm of app {} is empty", out, app_index)
+            }
+            WellFormedViolation::DuplicateInput(utxo_id) => {
+                write!(f, "input {} is spent more than once", utxo_id)
+            }
+            WellFormedViolation::RefAlsoSpent(utxo_id) => {
+                write!(f, "reference {} is also spent", utxo_id)
+            }
+            WellFormedViolation::InputNotCreatedByPrevTx(utxo_id) => {
+                write!(f, "input {} is not created by prev transactions", utxo_id)
+            }
+            WellFormedViolation::RefNotCreatedByPrevTx(utxo_id) => {
+                write!(
+                    f,
+                    "reference {} is not created by prev transactions",
+                    utxo_id
+                )
+            }
+        }
+    }
+}
+
+/// Check if the spell is well-formed by the rules of the deployed `charms-spell-checker` binary.
+///
+/// These rules are part of the spell checker's verification key: changing them requires a new
+/// spell checker build. [`well_formed_violations`] checks stricter rules, which `charms spell
+/// prove` enforces natively before proving.
 pub fn well_formed(
     spell: &NormalizedSpell,
-    prev_spells: &BTreeMap<TxId, (Option<NormalizedSpell>, usize)>,
+    prev_spells: &BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)>,
 ) -> bool {
-    if spell.version != CURRENT_VERSION {
-        eprintln!(
-            "spell version {} is not the current version {}",
-            spell.version, CURRENT_VERSION
-        );
-        return false;
-    }
+    // accepts an output index equal to the output count
     let created_by_prev_spells = |utxo_id: &UtxoId| -> bool {
         prev_spells
             .get(&utxo_id.0)
-            .and_then(|(_, num_tx_outs)| Some(utxo_id.1 as usize <= *num_tx_outs))
-            == Some(true)
+            .is_some_and(|(_, num_tx_outs)| (utxo_id.1 as usize) <= *num_tx_outs)
     };
-    if !spell
-        .tx
-        .outs
-        .iter()
-        .all(|n_charm| n_charm.keys().all(|i| i < &spell.app_public_inputs.len()))
-    {
-        eprintln!("charm app index higher than app_public_inputs.len()");
-        return false;
+    spell.version == CURRENT_VERSION
+        && spell.tx.outs.iter().all(|n_charms| {
+            n_charms
+                .keys()
+                .all(|&app_index| app_index < spell.app_public_inputs.len())
+        })
+        && spell
+            .tx
+            .ins
+            .as_ref()
+            .is_some_and(|tx_ins| tx_ins.iter().all(created_by_prev_spells))
+        && spell.tx.refs.iter().all(created_by_prev_spells)
+}
+
+/// Return the list of well-formedness rules violated by the spell (empty if the spell is
+/// well-formed). Any spell without violations is also [`well_formed`].
+pub fn well_formed_violations(
+    spell: &NormalizedSpell,
+    prev_spells: &BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)>,
+) -> Vec<WellFormedViolation> {
+    let mut violations = vec![];
+
+    if spell.version != CURRENT_VERSION {
+        violations.push(WellFormedViolation::UnsupportedVersion(spell.version));
+    }
+
+    for (out, n_charms) in spell.tx.outs.iter().enumerate() {
+        for (&app_index, data) in n_charms {
+            if app_index >= spell.app_public_inputs.len() {
+                violations.push(WellFormedViolation::AppIndexOutOfRange { out, app_index });
+            }
+            if data.is_empty() {
+                violations.push(WellFormedViolation::EmptyCharm { out, app_index });
+            }
+        }
     }
+
     // check that UTXOs we're spending or referencing in this tx
     // are created by pre-req transactions
+    let created_by_prev_spells = |utxo_id: &UtxoId| -> bool {
+        prev_spells
+            .get(&utxo_id.0)
+            .is_some_and(|(_, num_tx_outs)| (utxo_id.1 as usize) < *num_tx_outs)
+    };
+
     let Some(tx_ins) = &spell.tx.ins else {
-        eprintln!("no tx.ins");
-        return false;
+        violations.push(WellFormedViolation::NoInputs);
+        return violations;
     };
-    if !tx_ins.iter().all(created_by_prev_spells)
-        || !spell.tx.refs.iter().all(created_by_prev_spells)
-    {
-        eprintln!("input or reference UTXOs are not created by prev transactions");
-        return false;
+    let mut spent = BTreeSet::new();
+    for utxo_id in tx_ins {
+        if !spent.insert(utxo_id) {
+            violations.push(WellFormedViolation::DuplicateInput(utxo_id.clone()));
+        }
+        if !created_by_prev_spells(utxo_id) {
+            violations.push(WellFormedViolation::InputNotCreatedByPrevTx(
+                utxo_id.clone(),
+            ));
+        }
+    }
+    for utxo_id in &spell.tx.refs {
+        if spent.contains(utxo_id) {
+            violations.push(WellFormedViolation::RefAlsoSpent(utxo_id.clone()));
+        }
+        if !created_by_prev_spells(utxo_id) {
+            violations.push(WellFormedViolation::RefNotCreatedByPrevTx(utxo_id.clone()));
+        }
     }
-    true
+
+    violations
 }
 
 /// Return the list of apps in the spell.
@@ -138,12 +245,13 @@ pub fn apps(spell: &NormalizedSpell) -> Vec<App> {
 /// Convert normalized spell to [`charms_data::Transaction`].
 pub fn to_tx(
     spell: &NormalizedSpell,
-    prev_spells: &BTreeMap<TxId, (Option<NormalizedSpell>, usize)>,
+    prev_spells: &BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)>,
 ) -> Transaction {
     let from_utxo_id = |utxo_id: &UtxoId| -> (UtxoId, Charms) {
-        let (prev_spell_opt, _) = &prev_spells[&utxo_id.0];
-        let charms = prev_spell_opt
+        let (prev_spell_result, _) = &prev_spells[&utxo_id.0];
+        let charms = prev_spell_result
             .as_ref()
+            .ok()
             .and_then(|prev_spell| {
                 prev_spell
                     .tx
@@ -177,6 +285,98 @@ pub fn charms(spell: &NormalizedSpell, n_charms: &NormalizedCharms) -> Charms {
         .collect()
 }
 
+/// Problems found by [`check_spell`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpellCheckReport {
+    /// Well-formedness rules violated by the spell.
+    pub well_formed_violations: Vec<WellFormedViolation>,
+    /// Transactions the spell spends outputs of, missing from the pre-requisite transactions.
+    pub missing_prev_txs: BTreeSet<TxId>,
+    /// Pre-requisite transactions the spell does not spend outputs of.
+    pub unexpected_prev_txs: BTreeSet<TxId>,
+    /// Apps whose contracts are not satisfied by the transaction, with the reason.
+    pub failed_apps: BTreeMap<App, String>,
+}
+
+impl SpellCheckReport {
+    /// Return `true` if no problems were found: the spell is correct.
+    pub fn is_ok(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl fmt::Display for SpellCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ok() {
+            return writeln!(f, "spell is correct");
+        }
+        if !self.well_formed_violations.is_empty() {
+            writeln!(f, "spell is not well-formed:")?;
+            for violation in &self.well_formed_violations {
+                writeln!(f, "  - {}", violation)?;
+            }
+        }
+        if !self.missing_prev_txs.is_empty() || !self.unexpected_prev_txs.is_empty() {
+            writeln!(f, "spell input txids don't match prev txs:")?;
+            for txid in &self.missing_prev_txs {
+                writeln!(f, "  - {}: spent, but not in prev txs", txid)?;
+            }
+            for txid in &self.unexpected_prev_txs {
+                writeln!(f, "  - {}: in prev txs, but not spent", txid)?;
+            }
+        }
+        if !self.failed_apps.is_empty() {
+            writeln!(f, "app contracts failed:")?;
+            for (app, reason) in &self.failed_apps {
+                writeln!(f, "  - {}: {}", app, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check if the spell is correct, reporting all problems found.
+///
+/// Performs the checks of the `charms-spell-checker` binary, with the stricter
+/// [`well_formed_violations`] rules instead of [`well_formed`]. App contracts are checked by
+/// `check_app` (given the app, the transaction and the app's public input), which returns the
+/// reason the contract is not satisfied. App contracts are only checked if the spell is
+/// well-formed.
+pub fn check_spell(
+    spell: &NormalizedSpell,
+    prev_spells: &BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)>,
+    check_app: impl Fn(&App, &Transaction, &Data) -> Result<(), String>,
+) -> SpellCheckReport {
+    let mut report = SpellCheckReport {
+        well_formed_violations: well_formed_violations(spell, prev_spells),
+        ..Default::default()
+    };
+    let Some(prev_txids) = spell.tx.prev_txids() else {
+        return report;
+    };
+    let prev_spell_txids: BTreeSet<&TxId> = prev_spells.keys().collect();
+    report.missing_prev_txs = prev_txids
+        .difference(&prev_spell_txids)
+        .map(|&&txid| txid)
+        .collect();
+    report.unexpected_prev_txs = prev_spell_txids
+        .difference(&prev_txids)
+        .map(|&&txid| txid)
+        .collect();
+    if !report.well_formed_violations.is_empty() {
+        return report;
+    }
+
+    let tx = to_tx(spell, prev_spells);
+    for (app, x) in &spell.app_public_inputs {
+        if let Err(reason) = check_app(app, &tx, x) {
+            report.failed_apps.insert(app.clone(), reason);
+        }
+    }
+
+    report
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SpellProverInput {
     pub self_spell_vk: String,
@@ -188,6 +388,242 @@ pub struct SpellProverInput {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    type PrevSpells = BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)>;
+
+    /// Well-formed spell with its prev spells: inputs and references are distinct outputs of the
+    /// prev transactions, and output charms are non-empty and refer to the spell's apps.
+    fn well_formed_spell() -> impl Strategy<Value = (NormalizedSpell, PrevSpells)> {
+        (
+            prop::collection::btree_map(any::<[u8; 32]>().prop_map(TxId), 1..4usize, 1..4),
+            1..4usize,
+        )
+            .prop_flat_map(|(num_tx_outs, num_apps)| {
+                let utxo_ids: Vec<UtxoId> = num_tx_outs
+                    .iter()
+                    .flat_map(|(txid, &n)| (0..n as u32).map(|vout| UtxoId(*txid, vout)))
+                    .collect();
+                let len = utxo_ids.len();
+                let n_charms = prop::collection::btree_map(
+                    0..num_apps,
+                    any::<u64>().prop_map(|v| Data::from(&v)),
+                    0..=num_apps,
+                );
+                (
+                    Just(num_tx_outs),
+                    Just(utxo_ids).prop_shuffle(),
+                    1..=len,
+                    0..=len,
+                    prop::collection::vec(n_charms, 0..4),
+                    Just(num_apps),
+                )
+            })
+            .prop_map(
+                |(num_tx_outs, utxo_ids, num_ins, num_refs, outs, num_apps)| {
+                    let (ins, rest) = utxo_ids.split_at(num_ins);
+                    let spell = NormalizedSpell {
+                        version: CURRENT_VERSION,
+                        tx: NormalizedTransaction {
+                            ins: Some(ins.to_vec()),
+                            refs: rest.iter().take(num_refs).cloned().collect(),
+                            outs,
+                        },
+                        app_public_inputs: (0..num_apps)
+                            .map(|i| {
+                                let app = App {
+                                    tag: charms_data::TOKEN,
+                                    identity: charms_data::B32([i as u8; 32]),
+                                    vk: charms_data::B32([0; 32]),
+                                };
+                                (app, Data::empty())
+                            })
+                            .collect(),
+                    };
+                    let prev_spells = num_tx_outs
+                        .into_iter()
+                        .map(|(txid, n)| (txid, (Err(SpellError::NoEnvelope("no spell")), n)))
+                        .collect();
+                    (spell, prev_spells)
+                },
+            )
+    }
+
+    #[proptest]
+    fn accepts_well_formed(#[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells)) {
+        let (spell, prev_spells) = input;
+        prop_assert_eq!(well_formed_violations(&spell, &prev_spells), vec![]);
+        prop_assert!(well_formed(&spell, &prev_spells));
+    }
+
+    #[proptest]
+    fn rejects_other_versions(
+        #[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells),
+        #[filter(#version != CURRENT_VERSION)] version: u32,
+    ) {
+        let (mut spell, prev_spells) = input;
+        spell.version = version;
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::UnsupportedVersion(version)]
+        );
+    }
+
+    #[proptest]
+    fn rejects_missing_inputs(
+        #[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (mut spell, prev_spells) = input;
+        spell.tx.ins = None;
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::NoInputs]
+        );
+    }
+
+    #[proptest]
+    fn rejects_nonexistent_outputs(
+        #[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (mut spell, prev_spells) = input;
+        let ins = spell.tx.ins.as_mut().unwrap();
+        let txid = ins[0].0;
+        // the first output index past the end
+        let utxo_id = UtxoId(txid, prev_spells[&txid].1 as u32);
+        ins[0] = utxo_id.clone();
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::InputNotCreatedByPrevTx(utxo_id)]
+        );
+        // the deployed spell checker misses it: it is only caught natively
+        prop_assert!(well_formed(&spell, &prev_spells));
+    }
+
+    #[proptest]
+    fn rejects_unknown_refs(
+        #[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells),
+        utxo_id: ([u8; 32], u32),
+    ) {
+        let (mut spell, prev_spells) = input;
+        let utxo_id = UtxoId(TxId(utxo_id.0), utxo_id.1);
+        prop_assume!(!prev_spells.contains_key(&utxo_id.0));
+        spell.tx.refs.insert(utxo_id.clone());
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::RefNotCreatedByPrevTx(utxo_id)]
+        );
+    }
+
+    #[proptest]
+    fn rejects_duplicate_inputs(
+        #[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (mut spell, prev_spells) = input;
+        let ins = spell.tx.ins.as_mut().unwrap();
+        let utxo_id = ins[0].clone();
+        ins.push(utxo_id.clone());
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::DuplicateInput(utxo_id)]
+        );
+    }
+
+    #[proptest]
+    fn rejects_spent_refs(#[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells)) {
+        let (mut spell, prev_spells) = input;
+        let utxo_id = spell.tx.ins.as_ref().unwrap()[0].clone();
+        spell.tx.refs.insert(utxo_id.clone());
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::RefAlsoSpent(utxo_id)]
+        );
+    }
+
+    #[proptest]
+    fn rejects_unknown_apps(#[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells)) {
+        let (mut spell, prev_spells) = input;
+        let app_index = spell.app_public_inputs.len();
+        spell
+            .tx
+            .outs
+            .push(BTreeMap::from([(app_index, Data::from(&1u64))]));
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::AppIndexOutOfRange {
+                out: spell.tx.outs.len() - 1,
+                app_index
+            }]
+        );
+    }
+
+    #[proptest]
+    fn rejects_empty_charms(#[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells)) {
+        let (mut spell, prev_spells) = input;
+        spell.tx.outs.push(BTreeMap::from([(0, Data::empty())]));
+        prop_assert_eq!(
+            well_formed_violations(&spell, &prev_spells),
+            vec![WellFormedViolation::EmptyCharm {
+                out: spell.tx.outs.len() - 1,
+                app_index: 0
+            }]
+        );
+    }
+
+    #[proptest]
+    fn reports_failed_apps(#[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells)) {
+        let (spell, prev_spells) = input;
+        let failing_app = apps(&spell)[0].clone();
+        let report = check_spell(&spell, &prev_spells, |app, _, _| {
+            match app == &failing_app {
+                true => Err("no way".to_string()),
+                false => Ok(()),
+            }
+        });
+        prop_assert_eq!(report.well_formed_violations, vec![]);
+        prop_assert!(report.missing_prev_txs.is_empty());
+        prop_assert_eq!(
+            report.failed_apps,
+            BTreeMap::from([(failing_app, "no way".to_string())])
+        );
+    }
+
+    #[proptest]
+    fn reports_prev_txs_mismatch(
+        #[strategy(well_formed_spell())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (mut spell, prev_spells) = input;
+        // spend only outputs of the first prev tx: the other prev txs are unexpected
+        let first_txid = spell.tx.ins.as_ref().unwrap()[0].0;
+        spell
+            .tx
+            .ins
+            .as_mut()
+            .unwrap()
+            .retain(|utxo_id| utxo_id.0 == first_txid);
+        spell.tx.refs.clear();
+        let report = check_spell(&spell, &prev_spells, |_, _, _| Ok(()));
+        prop_assert!(report.missing_prev_txs.is_empty());
+        prop_assert_eq!(
+            report.unexpected_prev_txs,
+            prev_spells
+                .keys()
+                .filter(|&txid| txid != &first_txid)
+                .cloned()
+                .collect::<BTreeSet<_>>()
+        );
+
+        // the spent prev tx is missing: the spell is not well-formed, apps are not checked
+        let mut prev_spells = prev_spells;
+        prev_spells.remove(&first_txid);
+        let report = check_spell(&spell, &prev_spells, |_, _, _| Err("no way".to_string()));
+        prop_assert!(!report.is_ok());
+        prop_assert_eq!(&report.missing_prev_txs, &BTreeSet::from([first_txid]));
+        prop_assert!(!report.well_formed_violations.is_empty());
+        prop_assert!(report.failed_apps.is_empty());
+    }
+
     #[test]
     fn dummy() {}
 }
@@ -1,51 +1,131 @@
-use crate::{NormalizedSpell, Proof, CURRENT_VERSION, V0, V0_SPELL_VK, V1, V1_SPELL_VK};
-use anyhow::{anyhow, bail, ensure};
+use crate::{
+    NormalizedSpell, Proof, CURRENT_VERSION, MOCK_SPELL_VK, V0, V0_SPELL_VK, V1, V1_SPELL_VK,
+};
 use bitcoin::{
-    hashes::{serde::Serialize, Hash},
+    hashes::{serde::Serialize, sha256, Hash},
     opcodes::all::{OP_ENDIF, OP_IF},
     script::{Instruction, PushBytes},
     TxIn,
 };
 use charms_data::{util, TxId, UtxoId};
+use core::fmt;
 use sp1_primitives::io::SP1PublicValues;
 use sp1_verifier::Groth16Verifier;
 
+/// Error extracting or verifying a spell from a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpellError {
+    /// The transaction does not carry a spell envelope (e.g. it's a regular Bitcoin transaction).
+    NoEnvelope(&'static str),
+    /// The spell envelope is present, but is not well-formed.
+    InvalidEnvelope(&'static str),
+    /// The spell data is not a correctly CBOR-encoded `(NormalizedSpell, Proof)` tuple.
+    MalformedCbor(String),
+    /// The spell's protocol version is not supported.
+    UnsupportedVersion(u32),
+    /// The spell has more outputs than the transaction.
+    OutputCountMismatch { spell_outs: usize, tx_outs: usize },
+    /// The spell lists its inputs: it must inherit them from the transaction.
+    InputsNotInherited,
+    /// The spell proof does not verify.
+    ProofInvalid(String),
+}
+
+impl fmt::Display for SpellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpellError::NoEnvelope(reason) => write!(f, "no spell envelope: {}", reason),
+            SpellError::InvalidEnvelope(reason) => write!(f, "invalid spell envelope: {}", reason),
+            SpellError::MalformedCbor(e) => write!(f, "could not parse spell and proof: {}", e),
+            SpellError::UnsupportedVersion(version) => {
+                write!(f, "unsupported spell version: {}", version)
+            }
+            SpellError::OutputCountMismatch {
+                spell_outs,
+                tx_outs,
+            } => write!(
+                f,
+                "spell has {} outputs, but the transaction only has {}",
+                spell_outs, tx_outs
+            ),
+            SpellError::InputsNotInherited => {
+                write!(f, "spell must inherit inputs from the enchanted tx")
+            }
+            SpellError::ProofInvalid(e) => write!(f, "could not verify spell proof: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SpellError {}
+
 /// Extract a [`NormalizedSpell`] from a transaction and verify it.
 /// Incorrect spells are rejected.
+///
+/// Mock proofs are only accepted if `spell_vk` is [`MOCK_SPELL_VK`].
 pub fn extract_and_verify_spell(
     tx: &bitcoin::Transaction,
     spell_vk: &str,
-) -> anyhow::Result<NormalizedSpell> {
+) -> Result<NormalizedSpell, SpellError> {
     let Some((spell_tx_in, tx_ins)) = tx.input.split_last() else {
-        bail!("transaction does not have inputs")
+        return Err(SpellError::NoEnvelope("transaction does not have inputs"));
     };
 
     let (spell, proof) = parse_spell_and_proof(spell_tx_in)?;
 
-    ensure!(
-        &spell.tx.outs.len() <= &tx.output.len(),
-        "spell tx outs mismatch"
-    );
-    ensure!(
-        &spell.tx.ins.is_none(),
-        "spell must inherit inputs from the enchanted tx"
-    );
+    if spell.tx.outs.len() > tx.output.len() {
+        return Err(SpellError::OutputCountMismatch {
+            spell_outs: spell.tx.outs.len(),
+            tx_outs: tx.output.len(),
+        });
+    }
+    if spell.tx.ins.is_some() {
+        return Err(SpellError::InputsNotInherited);
+    }
 
     let spell = spell_with_ins(spell, tx_ins);
 
     let (spell_vk, groth16_vk) = vks(spell.version, spell_vk)?;
+    let public_values = to_sp1_pv(spell.version, &(spell_vk, &spell));
 
-    Groth16Verifier::verify(
-        &proof,
-        to_sp1_pv(spell.version, &(spell_vk, &spell)).as_slice(),
-        spell_vk,
-        groth16_vk,
-    )
-    .map_err(|e| anyhow!("could not verify spell proof: {}", e))?;
+    match spell_vk {
+        MOCK_SPELL_VK => verify_mock_proof(&proof, public_values.as_slice())?,
+        _ => Groth16Verifier::verify(&proof, public_values.as_slice(), spell_vk, groth16_vk)
+            .map_err(|e| SpellError::ProofInvalid(e.to_string()))?,
+    }
 
     Ok(spell)
 }
 
+/// Marker prefix of mock (dev) proofs.
+pub const MOCK_PROOF_MARKER: &[u8] = b"charms-mock-proof";
+
+/// Create a mock (dev) proof for the spell checker's public values.
+///
+/// A mock proof is **not** a zero-knowledge proof: it is [`MOCK_PROOF_MARKER`] followed by the
+/// SHA-256 hash of the public values, which only binds the proof to the spell. It says nothing
+/// about the spell being correct: check the spell (e.g. with [`check_spell`](crate::check_spell))
+/// before making one.
+pub fn mock_proof(public_values: &[u8]) -> Proof {
+    [
+        MOCK_PROOF_MARKER,
+        sha256::Hash::hash(public_values).as_byte_array(),
+    ]
+    .concat()
+    .into_boxed_slice()
+}
+
+fn verify_mock_proof(proof: &[u8], public_values: &[u8]) -> Result<(), SpellError> {
+    if !proof.starts_with(MOCK_PROOF_MARKER) {
+        return Err(SpellError::ProofInvalid(
+            "not a mock proof: only mock proofs are accepted".to_string(),
+        ));
+    }
+    if proof != mock_proof(public_values).as_ref() {
+        return Err(SpellError::ProofInvalid("mock proof mismatch".to_string()));
+    }
+    Ok(())
+}
+
 fn spell_with_ins(spell: NormalizedSpell, spell_tx_ins: &[TxIn]) -> NormalizedSpell {
     let tx_ins = spell_tx_ins // exclude spell commitment input
         .iter()
@@ -61,31 +141,35 @@ fn spell_with_ins(spell: NormalizedSpell, spell_tx_ins: &[TxIn]) -> NormalizedSp
     spell
 }
 
-pub fn parse_spell_and_proof(spell_tx_in: &TxIn) -> anyhow::Result<(NormalizedSpell, Proof)> {
-    ensure!(
-        spell_tx_in
-            .witness
-            .taproot_control_block()
-            .ok_or(anyhow!("no control block"))?
-            .len()
-            == 33,
-        "the Taproot tree contains more than one leaf: only a single script is supported"
-    );
-
-    let script = spell_tx_in
-        .witness
-        .tapscript()
-        .ok_or(anyhow!("no spell data in the last input's witness"))?;
+pub fn parse_spell_and_proof(spell_tx_in: &TxIn) -> Result<(NormalizedSpell, Proof), SpellError> {
+    let Some(script) = spell_tx_in.witness.tapscript() else {
+        return Err(SpellError::NoEnvelope(
+            "no spell data in the last input's witness",
+        ));
+    };
 
     let mut instructions = script.instructions();
 
-    ensure!(instructions.next() == Some(Ok(Instruction::PushBytes(PushBytes::empty()))));
-    ensure!(instructions.next() == Some(Ok(Instruction::Op(OP_IF))));
+    if instructions.next() != Some(Ok(Instruction::PushBytes(PushBytes::empty())))
+        || instructions.next() != Some(Ok(Instruction::Op(OP_IF)))
+    {
+        return Err(SpellError::NoEnvelope("no envelope in the script"));
+    }
     let Some(Ok(Instruction::PushBytes(push_bytes))) = instructions.next() else {
-        bail!("no spell data")
+        return Err(SpellError::NoEnvelope("no spell data"));
     };
     if push_bytes.as_bytes() != b"spell" {
-        bail!("no spell marker")
+        return Err(SpellError::NoEnvelope("no spell marker"));
+    }
+
+    if spell_tx_in
+        .witness
+        .taproot_control_block()
+        .is_none_or(|control_block| control_block.len() != 33)
+    {
+        return Err(SpellError::InvalidEnvelope(
+            "the Taproot tree contains more than one leaf: only a single script is supported",
+        ));
     }
 
     let mut spell_data = vec![];
@@ -99,22 +183,22 @@ pub fn parse_spell_and_proof(spell_tx_in: &TxIn) -> anyhow::Result<(NormalizedSp
                 break;
             }
             _ => {
-                bail!("unexpected opcode")
+                return Err(SpellError::InvalidEnvelope("unexpected opcode"));
             }
         }
     }
 
-    let (spell, proof): (NormalizedSpell, Proof) = util::read(spell_data.as_slice())
-        .map_err(|e| anyhow!("could not parse spell and proof: {}", e))?;
+    let (spell, proof): (NormalizedSpell, Proof) =
+        util::read(spell_data.as_slice()).map_err(|e| SpellError::MalformedCbor(e.to_string()))?;
     Ok((spell, proof))
 }
 
-fn vks(spell_version: u32, spell_vk: &str) -> anyhow::Result<(&str, &[u8])> {
+fn vks(spell_version: u32, spell_vk: &str) -> Result<(&str, &[u8]), SpellError> {
     match spell_version {
         CURRENT_VERSION => Ok((spell_vk, *sp1_verifier::GROTH16_VK_BYTES)),
         V1 => Ok((V1_SPELL_VK, *sp1_verifier::GROTH16_VK_BYTES)),
         V0 => Ok((V0_SPELL_VK, V0_GROTH16_VK_BYTES)),
-        _ => bail!("unsupported spell version: {}", spell_version),
+        _ => Err(SpellError::UnsupportedVersion(spell_version)),
     }
 }
 
@@ -140,3 +224,148 @@ fn to_sp1_pv<T: Serialize>(spell_version: u32, t: &T) -> SP1PublicValues {
     }
     pv
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::NormalizedTransaction;
+    use bitcoin::{
+        absolute::LockTime, script::Builder, transaction::Version, Amount, OutPoint, ScriptBuf,
+        TxOut, Witness,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn envelope_tx(spell_data: &[u8], num_outs: usize) -> bitcoin::Transaction {
+        let mut builder = Builder::new()
+            .push_opcode(bitcoin::opcodes::OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"spell");
+        for chunk in spell_data.chunks(520) {
+            builder = builder.push_slice(<&PushBytes>::try_from(chunk).unwrap());
+        }
+        let script = builder.push_opcode(OP_ENDIF).into_script();
+
+        let mut witness = Witness::new();
+        witness.push([0u8; 64]);
+        witness.push(script.as_bytes());
+        witness.push([0xc0u8; 33]);
+
+        bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![
+                TxIn::default(),
+                TxIn {
+                    witness,
+                    ..Default::default()
+                },
+            ],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(1000),
+                    script_pubkey: ScriptBuf::new(),
+                };
+                num_outs
+            ],
+        }
+    }
+
+    fn spell(version: u32, num_outs: usize) -> NormalizedSpell {
+        NormalizedSpell {
+            version,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: BTreeSet::new(),
+                outs: vec![BTreeMap::new(); num_outs],
+            },
+            app_public_inputs: BTreeMap::new(),
+        }
+    }
+
+    fn mock_spell_tx(spell: &NormalizedSpell, num_tx_outs: usize) -> bitcoin::Transaction {
+        let mut spell_with_ins = spell.clone();
+        spell_with_ins.tx.ins = Some(vec![UtxoId(TxId([0; 32]), u32::MAX)]);
+        let proof = mock_proof(&util::write(&(MOCK_SPELL_VK, &spell_with_ins)).unwrap());
+        envelope_tx(&util::write(&(spell, proof)).unwrap(), num_tx_outs)
+    }
+
+    #[test]
+    fn mock_proof_commits_to_public_values() {
+        let proof = mock_proof(b"public values");
+        assert!(proof.starts_with(MOCK_PROOF_MARKER));
+        assert!(verify_mock_proof(&proof, b"public values").is_ok());
+        assert!(verify_mock_proof(&proof, b"other public values").is_err());
+        assert!(verify_mock_proof(&[0u8; 260], b"public values").is_err());
+    }
+
+    #[test]
+    fn extracts_mock_spell() {
+        let tx = mock_spell_tx(&spell(CURRENT_VERSION, 2), 2);
+        let spell = extract_and_verify_spell(&tx, MOCK_SPELL_VK).unwrap();
+        assert_eq!(spell.tx.ins, Some(vec![UtxoId(TxId([0; 32]), u32::MAX)]));
+
+        assert!(matches!(
+            extract_and_verify_spell(&tx, V1_SPELL_VK),
+            Err(SpellError::ProofInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn spell_errors() {
+        let mut no_inputs = envelope_tx(b"", 1);
+        no_inputs.input.clear();
+        assert!(matches!(
+            extract_and_verify_spell(&no_inputs, MOCK_SPELL_VK),
+            Err(SpellError::NoEnvelope(_))
+        ));
+
+        let mut no_witness = envelope_tx(b"", 1);
+        no_witness.input[1].witness = Witness::new();
+        assert!(matches!(
+            extract_and_verify_spell(&no_witness, MOCK_SPELL_VK),
+            Err(SpellError::NoEnvelope(_))
+        ));
+
+        let mut spending_regular_output = envelope_tx(b"", 1);
+        spending_regular_output.input[1].previous_output = OutPoint::null();
+        spending_regular_output.input[1].witness = Witness::from_slice(&[vec![0u8; 64]]);
+        assert!(matches!(
+            extract_and_verify_spell(&spending_regular_output, MOCK_SPELL_VK),
+            Err(SpellError::NoEnvelope(_))
+        ));
+
+        let mut multi_leaf = envelope_tx(b"", 1);
+        let mut witness: Vec<Vec<u8>> = multi_leaf.input[1].witness.to_vec();
+        witness[2] = vec![0xc0; 65];
+        multi_leaf.input[1].witness = Witness::from_slice(&witness);
+        assert!(matches!(
+            extract_and_verify_spell(&multi_leaf, MOCK_SPELL_VK),
+            Err(SpellError::InvalidEnvelope(_))
+        ));
+
+        assert!(matches!(
+            extract_and_verify_spell(&envelope_tx(b"not CBOR", 1), MOCK_SPELL_VK),
+            Err(SpellError::MalformedCbor(_))
+        ));
+
+        assert_eq!(
+            extract_and_verify_spell(&mock_spell_tx(&spell(99, 1), 1), MOCK_SPELL_VK),
+            Err(SpellError::UnsupportedVersion(99))
+        );
+
+        assert_eq!(
+            extract_and_verify_spell(&mock_spell_tx(&spell(CURRENT_VERSION, 3), 2), MOCK_SPELL_VK),
+            Err(SpellError::OutputCountMismatch {
+                spell_outs: 3,
+                tx_outs: 2
+            })
+        );
+
+        let mut with_ins = spell(CURRENT_VERSION, 1);
+        with_ins.tx.ins = Some(vec![]);
+        assert_eq!(
+            extract_and_verify_spell(&mock_spell_tx(&with_ins, 1), MOCK_SPELL_VK),
+            Err(SpellError::InputsNotInherited)
+        );
+    }
+}
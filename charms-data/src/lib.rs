@@ -14,8 +14,11 @@ use serde::{
     ser::SerializeTuple,
     Deserialize, Deserializer, Serialize, Serializer,
 };
+pub mod metadata;
 pub mod util;
 
+pub use metadata::{ref_nft_app, token_metadata, TokenMetadata};
+
 /// Macro to check a condition and return false (early) if it does not hold.
 /// This is useful for checking pre-requisite conditions in predicate-type functions.
 /// Inspired by the `ensure!` macro from the `anyhow` crate.
@@ -552,15 +555,101 @@ fn app_state_multiset<'a>(
         })
 }
 
+/// Amount of a fungible token (charm data of apps with [`TOKEN`] tag).
+///
+/// All arithmetic is checked: token amounts can't overflow or go negative. Serialized the same way
+/// as `u64`.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct TokenAmount(pub u64);
+
+impl TokenAmount {
+    pub const ZERO: Self = Self(0);
+
+    /// Add `other` to `self`, returning `None` on overflow.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    /// Subtract `other` from `self`, returning `None` if `other` is greater than `self`.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    /// Parse a decimal amount (e.g. `"1.5"`) of a token with `decimals` digits after the decimal
+    /// point in its smallest denomination (see [`TokenMetadata::decimals`]): `"1.5"` with
+    /// `decimals == 8` is `150000000`.
+    ///
+    /// Fails if the amount has more (non-zero) digits after the decimal point than `decimals`, or
+    /// overflows.
+    pub fn from_decimal_str(s: &str, decimals: u8) -> Result<Self> {
+        let (int, frac) = s.split_once('.').unwrap_or((s, "0"));
+        ensure!(
+            !int.is_empty()
+                && !frac.is_empty()
+                && int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()),
+            "invalid decimal amount '{}'",
+            s
+        );
+        let frac = frac.trim_end_matches('0');
+        ensure!(
+            frac.len() <= decimals as usize,
+            "amount '{}' has more than {} digits after the decimal point",
+            s,
+            decimals
+        );
+        let digits = format!("{}{:0<width$}", int, frac, width = decimals as usize);
+        let amount = digits
+            .parse::<u64>()
+            .map_err(|_| anyhow!("amount '{}' is too large", s))?;
+        Ok(Self(amount))
+    }
+
+    /// Format the amount with `decimals` digits after the decimal point (the reverse of
+    /// [`TokenAmount::from_decimal_str`]): `150000000` with `decimals == 8` is `"1.50000000"`.
+    pub fn to_decimal_string(self, decimals: u8) -> String {
+        let digits = self.0.to_string();
+        if decimals == 0 {
+            return digits;
+        }
+        let digits = format!("{:0>width$}", digits, width = decimals as usize + 1);
+        let (int, frac) = digits.split_at(digits.len() - decimals as usize);
+        format!("{}.{}", int, frac)
+    }
+}
+
+impl From<u64> for TokenAmount {
+    fn from(amount: u64) -> Self {
+        Self(amount)
+    }
+}
+
+impl From<TokenAmount> for u64 {
+    fn from(amount: TokenAmount) -> Self {
+        amount.0
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// Sum the token amounts in the provided `strings_of_charms`.
+/// Fails if the sum overflows.
 pub fn sum_token_amount<'a>(
     app: &App,
-    strings_of_charms: impl Iterator<Item = &'a Charms>,
-) -> Result<u64> {
+    mut strings_of_charms: impl Iterator<Item = &'a Charms>,
+) -> Result<TokenAmount> {
     ensure!(app.tag == TOKEN);
-    strings_of_charms.fold(Ok(0u64), |amount, charms| match charms.get(app) {
-        Some(state) => Ok(amount? + state.value::<u64>()?),
-        None => amount,
+    strings_of_charms.try_fold(TokenAmount::ZERO, |amount, charms| match charms.get(app) {
+        Some(state) => amount
+            .checked_add(state.value::<TokenAmount>()?)
+            .ok_or(anyhow!("token amount overflow")),
+        None => Ok(amount),
     })
 }
 
@@ -576,6 +665,62 @@ mod tests {
         let _ = TxId::from_str(&s);
     }
 
+    #[test]
+    fn parses_decimal_amounts() {
+        assert_eq!(
+            TokenAmount::from_decimal_str("1.5", 8).unwrap(),
+            TokenAmount(150000000)
+        );
+        assert_eq!(
+            TokenAmount::from_decimal_str("1", 8).unwrap(),
+            TokenAmount(100000000)
+        );
+        assert_eq!(
+            TokenAmount::from_decimal_str("0.00000001", 8).unwrap(),
+            TokenAmount(1)
+        );
+        assert_eq!(
+            TokenAmount::from_decimal_str("1.2300", 2).unwrap(),
+            TokenAmount(123)
+        );
+        assert_eq!(
+            TokenAmount::from_decimal_str("42", 0).unwrap(),
+            TokenAmount(42)
+        );
+
+        for (s, decimals) in [
+            ("0.000000001", 8),
+            ("1.5", 0),
+            ("1.", 8),
+            (".5", 8),
+            ("-1", 8),
+            ("1,5", 8),
+            ("1.5e3", 8),
+            ("", 8),
+            ("18446744073709551616", 0),
+            ("184467440737.09551616", 8),
+        ] {
+            assert!(TokenAmount::from_decimal_str(s, decimals).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn formats_decimal_amounts() {
+        assert_eq!(TokenAmount(1234567).to_decimal_string(0), "1234567");
+        assert_eq!(TokenAmount(1234567).to_decimal_string(8), "0.01234567");
+        assert_eq!(TokenAmount(1234567).to_decimal_string(2), "12345.67");
+        assert_eq!(TokenAmount(0).to_decimal_string(3), "0.000");
+    }
+
+    #[proptest]
+    fn decimal_amounts_roundtrip(amount: u64, #[strategy(0u8..30)] decimals: u8) {
+        let s = TokenAmount(amount).to_decimal_string(decimals);
+        prop_assert_eq!(
+            TokenAmount::from_decimal_str(&s, decimals).unwrap(),
+            TokenAmount(amount)
+        );
+    }
+
     #[proptest]
     fn txid_roundtrip(txid: TxId) {
         let s = txid.to_string();
@@ -645,6 +790,83 @@ mod tests {
         assert_eq!(data.bytes(), buf);
     }
 
+    fn token_tx(token: &App, ins: &[u64], outs: &[u64]) -> Transaction {
+        let charms = |amount: &u64| Charms::from([(token.clone(), Data::from(amount))]);
+        Transaction {
+            ins: ins
+                .iter()
+                .zip(0..)
+                .map(|(amount, i)| (UtxoId(TxId([0; 32]), i), charms(amount)))
+                .collect(),
+            refs: BTreeMap::new(),
+            outs: outs.iter().map(charms).collect(),
+        }
+    }
+
+    #[proptest]
+    fn token_amounts_sum(app: App, amounts: Vec<u32>) {
+        let token = App { tag: TOKEN, ..app };
+        let tx = token_tx(
+            &token,
+            &[],
+            &amounts.iter().map(|&a| a as u64).collect::<Vec<_>>(),
+        );
+        let expected: u64 = amounts.iter().map(|&a| a as u64).sum();
+        prop_assert_eq!(
+            sum_token_amount(&token, tx.outs.iter()).unwrap(),
+            TokenAmount(expected)
+        );
+    }
+
+    #[proptest]
+    fn overflowing_token_amounts_rejected(
+        app: App,
+        #[strategy(1..=u64::MAX)] a: u64,
+        #[strategy(u64::MAX - #a + 1..=u64::MAX)] b: u64,
+        out_amounts: Vec<u64>,
+    ) {
+        let token = App { tag: TOKEN, ..app };
+        // `a + b` overflows: with wrapping arithmetic, inputs would add up to `a + b - 2^64`
+        let wrapped = a.wrapping_add(b);
+        let tx = token_tx(&token, &[a, b], &[wrapped]);
+        prop_assert!(sum_token_amount(&token, tx.ins.values()).is_err());
+        prop_assert!(!token_amounts_balanced(&token, &tx));
+        prop_assert!(!is_simple_transfer(&token, &tx));
+
+        // outputs overflowing is as bad
+        let tx = token_tx(&token, &[wrapped], &[b, a]);
+        prop_assert!(!token_amounts_balanced(&token, &tx));
+
+        let mut outs = out_amounts;
+        outs.extend([a, b]);
+        let tx = token_tx(&token, &[], &outs);
+        prop_assert!(sum_token_amount(&token, tx.outs.iter()).is_err());
+    }
+
+    #[proptest]
+    fn token_amount_checked_arithmetic(a: u64, b: u64) {
+        let (a, b) = (TokenAmount(a), TokenAmount(b));
+        prop_assert_eq!(a.checked_add(b).map(u64::from), a.0.checked_add(b.0));
+        prop_assert_eq!(a.checked_sub(b).map(u64::from), a.0.checked_sub(b.0));
+        let data = Data::from(&a);
+        prop_assert_eq!(data.value::<u64>().unwrap(), a.0);
+    }
+
+    #[test]
+    fn token_amount_is_rejected_if_negative() {
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let tx = Transaction {
+            ins: BTreeMap::new(),
+            refs: BTreeMap::new(),
+            outs: vec![Charms::from([(token.clone(), Data::from(&-1i64))])],
+        };
+        assert!(sum_token_amount(&token, tx.outs.iter()).is_err());
+    }
+
     #[test]
     fn dummy() {}
 }
@@ -0,0 +1,360 @@
+use crate::{App, Charms, Data, Transaction, UtxoId, B32, NFT};
+use anyhow::{anyhow, bail, ensure, Result};
+use ark_std::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use ciborium::Value;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Token metadata: data of the token's reference NFT, as defined in CHIP-0420.
+///
+/// The reference NFT of a token `t/{identity}/{vk}` is the NFT `n/{identity}/{vk}` (see
+/// [`ref_nft_app`]). All fields are optional. Fields not defined in CHIP-0420 are preserved in
+/// `other`.
+///
+/// Deserializing does not validate field values: use [`TokenMetadata::validate`] (or
+/// [`TokenMetadata::from_data`], which does it too).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenMetadata {
+    /// Asset name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Description of the fungible token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Ticker symbol.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ticker: Option<String>,
+    /// Website URL (see [`validate_uri`] for the allowed schemes).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Image URI (see [`validate_uri`] for the allowed schemes).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// SHA-256 hash (64 hex characters) of the resource `image` is pointing to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_hash: Option<String>,
+    /// Number of digits after the decimal point in the smallest denomination of the token.
+    /// `None` means `0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u8>,
+    /// UTXO with upstream data of this NFT.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_ref",
+        deserialize_with = "deserialize_ref"
+    )]
+    pub r#ref: Option<UtxoId>,
+    /// Additional fields.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Data>,
+}
+
+impl TokenMetadata {
+    /// Read (and validate) token metadata from reference NFT data.
+    pub fn from_data(data: &Data) -> Result<Self> {
+        let metadata: Self = data.value()?;
+        metadata.validate()?;
+        Ok(metadata)
+    }
+
+    /// Check that `url` and `image` are valid URIs (see [`validate_uri`]) and `image_hash` is a
+    /// hex-encoded 32-byte hash.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(url) = &self.url {
+            validate_uri(url).map_err(|e| anyhow!("invalid url: {}", e))?;
+        }
+        if let Some(image) = &self.image {
+            validate_uri(image).map_err(|e| anyhow!("invalid image: {}", e))?;
+        }
+        if let Some(image_hash) = &self.image_hash {
+            B32::from_str(image_hash).map_err(|e| anyhow!("invalid image_hash: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// `ref` is written as a string (`txid_hex:index`), the way it appears in spells.
+fn serialize_ref<S: Serializer>(
+    utxo_id: &Option<UtxoId>,
+    serializer: S,
+) -> core::result::Result<S::Ok, S::Error> {
+    match utxo_id {
+        Some(utxo_id) => serializer.serialize_str(&utxo_id.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// `ref` is read from a string (`txid_hex:index`) or bytes (how [`UtxoId`] is serialized to CBOR).
+fn deserialize_ref<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> core::result::Result<Option<UtxoId>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Text(s)) => UtxoId::from_str(&s).map(Some).map_err(de::Error::custom),
+        Some(Value::Bytes(bytes)) => {
+            let bytes: [u8; 36] = bytes
+                .try_into()
+                .map_err(|_| de::Error::custom("invalid utxo_id bytes"))?;
+            Ok(Some(UtxoId::from_bytes(bytes)))
+        }
+        Some(_) => Err(de::Error::custom(
+            "expected a string in format 'txid_hex:index'",
+        )),
+    }
+}
+
+/// Check that `uri` is a valid URI allowed in token metadata: the scheme must be one of `https`,
+/// `ipfs`, `ar` (Arweave) or `data` (on-chain data, a data URL as defined in RFC 2397).
+pub fn validate_uri(uri: &str) -> Result<()> {
+    ensure!(
+        !uri.chars().any(|c| c.is_whitespace() || c.is_control()),
+        "URI must not contain whitespace or control characters"
+    );
+    let Some((scheme, rest)) = uri.split_once(':') else {
+        bail!("missing URI scheme");
+    };
+    match scheme {
+        "https" | "ipfs" | "ar" => {
+            ensure!(
+                rest.strip_prefix("//").is_some_and(|r| !r.is_empty()),
+                "expected {}://...",
+                scheme
+            );
+            Ok(())
+        }
+        "data" => validate_data_url(rest),
+        _ => bail!(
+            "unsupported URI scheme '{}' (expected https, ipfs, ar or data)",
+            scheme
+        ),
+    }
+}
+
+/// Validate the part after `data:` of a data URL: `[<mediatype>][;base64],<data>`
+/// (RFC 2397), where `<mediatype>` is `[type/subtype](;attribute=value)*`.
+fn validate_data_url(url: &str) -> Result<()> {
+    let Some((header, data)) = url.split_once(',') else {
+        bail!("data URL must have ',' before the data");
+    };
+    let mut params: Vec<&str> = header.split(';').collect();
+    let media_type = params.remove(0);
+    let base64 = params.last() == Some(&"base64");
+    if base64 {
+        params.pop();
+    }
+
+    if !media_type.is_empty() {
+        let valid = media_type
+            .split_once('/')
+            .is_some_and(|(t, subtype)| is_token(t) && is_token(subtype));
+        ensure!(valid, "invalid media type '{}'", media_type);
+    }
+    for param in params {
+        let valid = param
+            .split_once('=')
+            .is_some_and(|(attribute, value)| is_token(attribute) && !value.is_empty());
+        ensure!(valid, "invalid media type parameter '{}'", param);
+    }
+    if base64 {
+        ensure!(
+            data.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=')),
+            "invalid base64 data"
+        );
+    }
+    Ok(())
+}
+
+/// RFC 2045 token: non-empty, no spaces, control characters or `tspecials`.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c))
+}
+
+/// The reference NFT (holding [`TokenMetadata`]) of the token `app`: the NFT with the same
+/// identity and vk.
+pub fn ref_nft_app(app: &App) -> App {
+    App {
+        tag: NFT,
+        ..app.clone()
+    }
+}
+
+/// Find the reference NFT of token `app` in `tx` and return its metadata. Reference inputs are
+/// looked at first, then inputs and outputs.
+///
+/// Returns `Ok(None)` if the reference NFT is not in `tx`, and an error if its data is not valid
+/// token metadata.
+pub fn token_metadata(app: &App, tx: &Transaction) -> Result<Option<TokenMetadata>> {
+    let ref_nft = ref_nft_app(app);
+    let data = tx
+        .refs
+        .values()
+        .chain(tx.ins.values())
+        .chain(tx.outs.iter())
+        .find_map(|charms: &Charms| charms.get(&ref_nft));
+    data.map(TokenMetadata::from_data).transpose()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{TxId, TOKEN};
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    fn metadata() -> TokenMetadata {
+        TokenMetadata {
+            name: Some("Toad Token".to_string()),
+            ticker: Some("TOAD".to_string()),
+            url: Some("https://charms.dev".to_string()),
+            image: Some("data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=".to_string()),
+            image_hash: Some(
+                "92077a14998b31367efeec5203a00f1080facdb270cbf055f09b66ae0a273c7d".to_string(),
+            ),
+            decimals: Some(8),
+            r#ref: Some(UtxoId(TxId([1; 32]), 2)),
+            other: BTreeMap::from([("remaining".to_string(), Data::from(&100000u64))]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn roundtrips_through_data() {
+        let metadata = metadata();
+        let data = Data::from(&metadata);
+        assert_eq!(TokenMetadata::from_data(&data).unwrap(), metadata);
+
+        let bytes = crate::util::write(&metadata).unwrap();
+        assert_eq!(
+            crate::util::read::<TokenMetadata, _>(bytes.as_slice()).unwrap(),
+            metadata
+        );
+    }
+
+    #[test]
+    fn reads_ref_as_string_or_bytes() {
+        let utxo_id = UtxoId(TxId([1; 32]), 2);
+        for value in [Data::from(&utxo_id.to_string()), Data::from(&utxo_id)] {
+            let data = Data::from(&BTreeMap::from([("ref", value)]));
+            assert_eq!(
+                TokenMetadata::from_data(&data).unwrap().r#ref,
+                Some(utxo_id.clone())
+            );
+        }
+        let data = Data::from(&BTreeMap::from([("ref", 3)]));
+        assert!(TokenMetadata::from_data(&data).is_err());
+    }
+
+    #[test]
+    fn preserves_unknown_fields() {
+        let data = Data::from(&BTreeMap::from([
+            ("ticker", Data::from(&"TOAD")),
+            ("remaining", Data::from(&30580u64)),
+        ]));
+        let metadata = TokenMetadata::from_data(&data).unwrap();
+        assert_eq!(metadata.ticker.as_deref(), Some("TOAD"));
+        assert_eq!(metadata.other["remaining"], Data::from(&30580u64));
+        let fields = |data: &Data| data.value::<BTreeMap<String, Data>>().unwrap();
+        assert_eq!(fields(&Data::from(&metadata)), fields(&data));
+    }
+
+    #[test]
+    fn validates_uris() {
+        for uri in [
+            "https://charms.dev/toad.png",
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+            "ar://8X5ntMdvJmQ9hLhFnvBcZXumPMtV1QW8iYHZ6aaDy6c",
+            "data:,Hello%2C%20World!",
+            "data:text/plain;charset=US-ASCII,hello",
+            "data:image/png;base64,iVBORw0KGgo=",
+            "data:;base64,SGVsbG8=",
+        ] {
+            assert!(validate_uri(uri).is_ok(), "{}", uri);
+        }
+        for uri in [
+            "http://charms.dev",
+            "https:charms.dev",
+            "https://",
+            "ftp://charms.dev",
+            "charms.dev",
+            "https://charms.dev/a b",
+            "data:text/plain",
+            "data:text,hello",
+            "data:text/plain;charset,hello",
+            "data:image/png;base64,not base64!",
+        ] {
+            assert!(validate_uri(uri).is_err(), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn validates_image_hash() {
+        let mut metadata = metadata();
+        metadata.image_hash = Some("1234".to_string());
+        assert!(metadata.validate().is_err());
+        metadata.image_hash = Some("zz".repeat(32));
+        assert!(metadata.validate().is_err());
+        metadata.image_hash = Some("AB".repeat(32));
+        assert!(metadata.validate().is_ok());
+    }
+
+    #[test]
+    fn finds_token_metadata() {
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let ref_nft = ref_nft_app(&token);
+        assert_eq!(ref_nft.tag, NFT);
+        assert_eq!(ref_nft.identity, token.identity);
+
+        let mut tx = Transaction {
+            ins: BTreeMap::from([(
+                UtxoId(TxId([0; 32]), 0),
+                Charms::from([(token.clone(), Data::from(&1000u64))]),
+            )]),
+            refs: BTreeMap::new(),
+            outs: vec![Charms::from([(token.clone(), Data::from(&1000u64))])],
+        };
+        assert_eq!(token_metadata(&token, &tx).unwrap(), None);
+
+        tx.refs.insert(
+            UtxoId(TxId([0; 32]), 1),
+            Charms::from([(ref_nft.clone(), Data::from(&metadata()))]),
+        );
+        assert_eq!(token_metadata(&token, &tx).unwrap(), Some(metadata()));
+
+        let mut invalid = metadata();
+        invalid.url = Some("http://charms.dev".to_string());
+        tx.refs.insert(
+            UtxoId(TxId([0; 32]), 1),
+            Charms::from([(ref_nft, Data::from(&invalid))]),
+        );
+        assert!(token_metadata(&token, &tx).is_err());
+    }
+
+    #[proptest]
+    fn doesnt_crash(uri: String) {
+        let _ = validate_uri(&uri);
+    }
+
+    #[proptest]
+    fn known_fields_roundtrip(name: Option<String>, decimals: Option<u8>) {
+        let metadata = TokenMetadata {
+            name,
+            decimals,
+            ..Default::default()
+        };
+        prop_assert_eq!(
+            Data::from(&metadata).value::<TokenMetadata>().unwrap(),
+            metadata
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use charms_sdk::data::{
-    app_datas, check, sum_token_amount, App, Data, Transaction, UtxoId, B32, NFT, TOKEN,
+    app_datas, check, sum_token_amount, App, Data, TokenAmount, Transaction, UtxoId, B32, NFT,
+    TOKEN,
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -7,7 +8,7 @@ use sha2::{Digest, Sha256};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NftContent {
     pub ticker: String,
-    pub remaining: u64,
+    pub remaining: TokenAmount,
 }
 
 pub fn app_contract(app: &App, tx: &Transaction, x: &Data, w: &Data) -> bool {
@@ -104,8 +105,13 @@ fn can_mint_token(token_app: &App, tx: &Transaction) -> bool {
         return false;
     };
 
+    let Some(minted_amount) = output_token_amount.checked_sub(input_token_amount) else {
+        eprintln!("output total token amount must be >= input total token amount");
+        return false;
+    };
+
     // can mint no more than what's allowed by the managing NFT state change.
-    output_token_amount - input_token_amount == incoming_supply - outgoing_supply
+    Some(minted_amount) == incoming_supply.checked_sub(outgoing_supply)
 }
 
 #[cfg(test)]
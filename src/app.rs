@@ -1,18 +1,68 @@
-use anyhow::ensure;
+use crate::{
+    cache::{KeyCache, ProverKind},
+    utils::parallel_map,
+};
+use anyhow::{anyhow, ensure};
 use charms_data::{is_simple_transfer, util, App, Data, Transaction, B32};
 use sp1_prover::components::CpuProverComponents;
-use sp1_sdk::{HashableKey, ProverClient, SP1Proof, SP1ProofMode, SP1Stdin, SP1VerifyingKey};
-use std::{collections::BTreeMap, mem};
+use sp1_sdk::{
+    HashableKey, ProverClient, SP1Proof, SP1ProofMode, SP1ProvingKey, SP1Stdin, SP1VerifyingKey,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// App contract function, as passed to `charms_sdk::main!`.
+#[cfg(test)]
+pub(crate) type NativeContract = fn(&App, &Transaction, &Data, &Data) -> bool;
+
+/// App contracts run natively by [`Prover::check`] instead of app binaries, by app VK: tests
+/// have no compiled app binaries.
+#[cfg(test)]
+pub(crate) static NATIVE_CONTRACTS: std::sync::Mutex<BTreeMap<B32, NativeContract>> =
+    std::sync::Mutex::new(BTreeMap::new());
 
 pub struct Prover {
     pub client: Box<dyn sp1_sdk::Prover<CpuProverComponents>>,
+    /// Kind of `client`.
+    pub kind: ProverKind,
+    /// Cache of setup outputs. `None` if there's no cache directory.
+    pub keys: Option<KeyCache>,
 }
 
 impl Prover {
     pub fn vk(&self, binary: &[u8]) -> [u8; 32] {
-        let (_pk, vk) = self.client.setup(&binary);
+        let (_pk, vk) = self.setup(binary);
         app_vk(vk)
     }
+
+    /// Proving and verifying keys for the binary: from the key cache if possible.
+    pub fn setup(&self, binary: &[u8]) -> (SP1ProvingKey, SP1VerifyingKey) {
+        match &self.keys {
+            Some(keys) => keys.setup(self.kind, self.client.as_ref(), binary),
+            None => self.client.setup(binary),
+        }
+    }
+}
+
+/// Default maximum number of app proofs generated concurrently: each one can take several GB of
+/// memory.
+pub const DEFAULT_PROVER_WORKERS: usize = 1;
+
+static PROVER_WORKERS: AtomicUsize = AtomicUsize::new(DEFAULT_PROVER_WORKERS);
+
+/// Maximum number of app proofs generated concurrently: [`DEFAULT_PROVER_WORKERS`] unless set
+/// with [`set_prover_workers`].
+pub fn prover_workers() -> usize {
+    PROVER_WORKERS.load(Ordering::Relaxed)
+}
+
+/// Set the maximum number of app proofs generated concurrently (`--prover-workers`).
+pub fn set_prover_workers(workers: NonZeroUsize) {
+    PROVER_WORKERS.store(workers.get(), Ordering::Relaxed);
 }
 
 fn app_vk(sp1_vk: SP1VerifyingKey) -> [u8; 32] {
@@ -26,9 +76,24 @@ impl Prover {
     pub fn new() -> Self {
         Self {
             client: Box::new(ProverClient::builder().cpu().build()),
+            kind: ProverKind::Cpu,
+            keys: KeyCache::open().ok(),
+        }
+    }
+
+    /// Prover using SP1's mock prover: programs are executed, but proofs are not generated.
+    pub fn mock() -> Self {
+        Self {
+            client: Box::new(ProverClient::builder().mock().build()),
+            kind: ProverKind::Mock,
+            keys: KeyCache::open().ok(),
         }
     }
 
+    /// Generate app contract proofs for the apps in `app_public_inputs` that have binaries in
+    /// `app_binaries`, and write them to `spell_stdin` (in the order of `app_public_inputs`).
+    ///
+    /// Up to [`prover_workers`] proofs are generated concurrently.
     pub(crate) fn prove(
         &self,
         app_binaries: &BTreeMap<B32, Vec<u8>>,
@@ -37,22 +102,38 @@ impl Prover {
         app_private_inputs: BTreeMap<App, Data>,
         spell_stdin: &mut SP1Stdin,
     ) -> anyhow::Result<()> {
-        let pk_vks = app_binaries
+        let workers = prover_workers();
+
+        let apps: Vec<(&App, &Data)> = app_public_inputs
             .iter()
-            .map(|(vk_hash, binary)| {
-                let (pk, vk) = self.client.setup(binary);
-                (vk_hash, (pk, vk))
+            .filter(|(app, _)| match app_binaries.contains_key(&app.vk) {
+                true => true,
+                false => {
+                    eprintln!("app binary not present: {:?}", app);
+                    false
+                }
             })
-            .collect::<BTreeMap<_, _>>();
+            .collect();
 
-        for (app, x) in app_public_inputs {
-            let Some((pk, vk)) = pk_vks.get(&app.vk) else {
-                eprintln!("app binary not present: {:?}", app);
-                continue;
-            };
-            dbg!(app);
+        // set up each binary used by the spell once
+        let vks: Vec<&B32> = apps
+            .iter()
+            .map(|(app, _)| &app.vk)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let pk_vks: BTreeMap<&B32, _> = vks
+            .iter()
+            .copied()
+            .zip(parallel_map(&vks, workers, |&vk_hash| {
+                self.setup(&app_binaries[vk_hash])
+            }))
+            .collect();
+
+        let empty = Data::empty();
+        let app_proofs = parallel_map(&apps, workers, |&(app, x)| -> anyhow::Result<_> {
+            let (pk, _) = &pk_vks[&app.vk];
             let mut app_stdin = SP1Stdin::new();
-            let empty = Data::empty();
             let w = app_private_inputs.get(app).unwrap_or(&empty);
             app_stdin.write_vec(util::write(&(app, &tx, x, w))?);
             let app_proof = self
@@ -63,44 +144,55 @@ impl Prover {
                 unreachable!()
             };
             eprintln!("app proof generated! for {:?}", app);
+            Ok(compressed_proof)
+        });
+
+        for ((app, _), app_proof) in apps.iter().zip(app_proofs) {
+            let (_, vk) = &pk_vks[&app.vk];
+            let compressed_proof = app_proof?;
             spell_stdin.write_proof(*compressed_proof, vk.vk.clone());
         }
 
         Ok(())
     }
 
-    pub(crate) fn run_all(
+    /// Check that the app contract is satisfied by `tx` (natively, without proving): run the
+    /// app binary if provided, otherwise check that `tx` is a simple transfer of the app's charms.
+    pub(crate) fn check(
         &self,
         app_binaries: &BTreeMap<B32, Vec<u8>>,
+        app: &App,
         tx: &Transaction,
-        app_public_inputs: &BTreeMap<App, Data>,
-        app_private_inputs: BTreeMap<App, Data>,
+        x: &Data,
+        w: &Data,
     ) -> anyhow::Result<()> {
-        for (app, x) in app_public_inputs {
-            let mut app_stdin = SP1Stdin::new();
-            let empty = Data::empty();
-            let w = app_private_inputs.get(app).unwrap_or(&empty);
-            app_stdin.write_vec(util::write(&(app, tx, x, w))?);
-            match app_binaries.get(&app.vk) {
-                Some(app_binary) => {
-                    let (committed_values, _report) =
-                        self.client.execute(app_binary, &app_stdin)?;
-
-                    let com: (App, Transaction, Data) =
-                        util::read(committed_values.to_vec().as_slice())?;
-                    ensure!(
-                        (&com.0, &com.1, &com.2) == (app, tx, x),
-                        "committed data mismatch"
-                    );
-                }
-                None => ensure!(is_simple_transfer(app, tx)),
-            }
-
-            dbg!(app);
-
-            eprintln!("app checked!");
+        #[cfg(test)]
+        if let Some(contract) = NATIVE_CONTRACTS.lock().unwrap().get(&app.vk).copied() {
+            // same as the `main` of app binaries made with `charms_sdk::main!`
+            ensure!(
+                is_simple_transfer(app, tx) || contract(app, tx, x, w),
+                "app contract not satisfied"
+            );
+            return Ok(());
         }
-
+        let Some(app_binary) = app_binaries.get(&app.vk) else {
+            ensure!(
+                is_simple_transfer(app, tx),
+                "no app binary, and the transaction is not a simple transfer of the app's charms"
+            );
+            return Ok(());
+        };
+        let mut app_stdin = SP1Stdin::new();
+        app_stdin.write_vec(util::write(&(app, tx, x, w))?);
+        let (committed_values, _report) = self
+            .client
+            .execute(app_binary, &app_stdin)
+            .map_err(|e| anyhow!("app contract execution failed: {}", e))?;
+        let com: (App, Transaction, Data) = util::read(committed_values.to_vec().as_slice())?;
+        ensure!(
+            (&com.0, &com.1, &com.2) == (app, tx, x),
+            "committed data mismatch"
+        );
         Ok(())
     }
 
@@ -112,7 +204,7 @@ impl Prover {
         x: &Data,
         w: &Data,
     ) -> anyhow::Result<()> {
-        let (_pk, vk) = self.client.setup(app_binary);
+        let (_pk, vk) = self.setup(app_binary);
         ensure!(app.vk == B32(app_vk(vk)), "app.vk mismatch");
 
         let mut app_stdin = SP1Stdin::new();
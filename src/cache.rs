@@ -0,0 +1,276 @@
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::{sha256, Hash};
+use charms_data::util;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sp1_prover::components::CpuProverComponents;
+use sp1_sdk::{Prover, SP1ProvingKey, SP1VerifyingKey, SP1_CIRCUIT_VERSION};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Kind of SP1 prover: setup outputs of different provers are cached separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProverKind {
+    Cpu,
+    Mock,
+}
+
+impl ProverKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ProverKind::Cpu => "cpu",
+            ProverKind::Mock => "mock",
+        }
+    }
+}
+
+/// Cache directory: `CHARMS_CACHE_DIR` if set, otherwise `charms` in `XDG_CACHE_HOME` (or in
+/// `~/.cache`).
+pub fn cache_dir() -> Result<PathBuf> {
+    if let Ok(dir) = env::var("CHARMS_CACHE_DIR") {
+        return Ok(dir.into());
+    }
+    let cache_home = match env::var("XDG_CACHE_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(
+            env::var("HOME").map_err(|_| anyhow!("neither CHARMS_CACHE_DIR nor HOME is set"))?,
+        )
+        .join(".cache"),
+    };
+    Ok(cache_home.join("charms"))
+}
+
+/// Content-addressed on-disk cache of SP1 setup outputs (proving and verifying keys), keyed by
+/// the SP1 version, the [kind of prover](ProverKind) and the SHA-256 hash of the program (RISC-V
+/// ELF binary): `keys/{sp1_version}/{prover}/{elf_hash}.keys` in the cache directory.
+#[derive(Clone, Debug)]
+pub struct KeyCache {
+    dir: PathBuf,
+}
+
+/// Cached setup output.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyCacheEntry {
+    /// SP1 version the entry was created with.
+    pub sp1_version: String,
+    /// Kind of prover the entry was created with (e.g. `cpu`).
+    pub prover: String,
+    /// SHA-256 hash of the program (hex-encoded).
+    pub elf_hash: String,
+    /// Size of the entry in bytes.
+    pub size: u64,
+}
+
+impl KeyCache {
+    /// Key cache in the cache directory `dir` (e.g. [`cache_dir`]).
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.join("keys"),
+        }
+    }
+
+    /// Key cache in the default cache directory: see [`cache_dir`].
+    pub fn open() -> Result<Self> {
+        Ok(Self::new(&cache_dir()?))
+    }
+
+    /// Proving and verifying keys for `elf`: cached, or set up with `client` (a `prover` kind of
+    /// prover) and cached.
+    pub fn setup(
+        &self,
+        prover: ProverKind,
+        client: &dyn Prover<CpuProverComponents>,
+        elf: &[u8],
+    ) -> (SP1ProvingKey, SP1VerifyingKey) {
+        self.get_or_insert_with(prover, elf, || client.setup(elf))
+    }
+
+    /// Cached value for `elf` and `prover`, or the value computed by `f` (which is then cached).
+    /// Cache errors are logged, not returned: the cache is only an optimization.
+    fn get_or_insert_with<T: Serialize + DeserializeOwned>(
+        &self,
+        prover: ProverKind,
+        elf: &[u8],
+        f: impl FnOnce() -> T,
+    ) -> T {
+        let path = self.path(prover, &sha256::Hash::hash(elf).to_string());
+        match fs::read(&path) {
+            Ok(bytes) => match util::read(bytes.as_slice()) {
+                Ok(value) => return value,
+                Err(e) => tracing::warn!("ignoring corrupt cache entry {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("error reading {}: {}", path.display(), e),
+        }
+        let value = f();
+        if let Err(e) = write(&path, &value) {
+            tracing::warn!("error writing {}: {}", path.display(), e);
+        }
+        value
+    }
+
+    fn path(&self, prover: ProverKind, elf_hash: &str) -> PathBuf {
+        self.dir
+            .join(SP1_CIRCUIT_VERSION)
+            .join(prover.name())
+            .join(format!("{}.keys", elf_hash))
+    }
+
+    /// List cache entries (for all SP1 versions and provers).
+    pub fn entries(&self) -> Result<Vec<KeyCacheEntry>> {
+        let mut entries = vec![];
+        for version_dir in subdirs(&self.dir)? {
+            for prover_dir in subdirs(&version_dir)? {
+                for path in read_dir(&prover_dir)? {
+                    let Some(elf_hash) = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(|name| name.strip_suffix(".keys"))
+                    else {
+                        continue;
+                    };
+                    entries.push(KeyCacheEntry {
+                        sp1_version: file_name(&version_dir),
+                        prover: file_name(&prover_dir),
+                        elf_hash: elf_hash.to_string(),
+                        size: fs::metadata(&path)?.len(),
+                    });
+                }
+            }
+        }
+        entries.sort_by(|a, b| {
+            (&a.sp1_version, &a.prover, &a.elf_hash).cmp(&(&b.sp1_version, &b.prover, &b.elf_hash))
+        });
+        Ok(entries)
+    }
+
+    /// Remove all cache entries. Returns the removed entries.
+    pub fn clear(&self) -> Result<Vec<KeyCacheEntry>> {
+        let entries = self.entries()?;
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .map_err(|e| anyhow!("error removing {}: {}", self.dir.display(), e))?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Paths of the directory's entries (none if the directory doesn't exist), in no particular
+/// order.
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(anyhow!("error reading {}: {}", dir.display(), e)),
+    }
+}
+
+/// Subdirectories of `dir` (none if it doesn't exist).
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(read_dir(dir)?
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .collect())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned())
+}
+
+/// Write `value` to `path` via a temporary file: readers never see a partially written entry.
+fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let dir = path
+        .parent()
+        .expect("cache entry path should have a parent");
+    fs::create_dir_all(dir)?;
+    let tmp_path = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&tmp_path, util::write(value)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use tempfile::TempDir;
+
+    fn temp_cache() -> (TempDir, KeyCache) {
+        let dir = TempDir::new().unwrap();
+        let cache = KeyCache::new(dir.path());
+        (dir, cache)
+    }
+
+    #[test]
+    fn caches_by_elf_hash() {
+        let (dir, cache) = temp_cache();
+        let setups = Cell::new(0);
+        let setup = |elf: &[u8]| {
+            cache.get_or_insert_with(ProverKind::Cpu, elf, || {
+                setups.set(setups.get() + 1);
+                (elf.len() as u64, format!("keys for {:?}", elf))
+            })
+        };
+
+        assert_eq!(
+            setup(b"elf 1"),
+            (5, "keys for [101, 108, 102, 32, 49]".into())
+        );
+        assert_eq!(
+            setup(b"elf 1"),
+            (5, "keys for [101, 108, 102, 32, 49]".into())
+        );
+        assert_eq!(setups.get(), 1);
+        setup(b"elf 2");
+        assert_eq!(setups.get(), 2);
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries
+            .iter()
+            .all(|entry| entry.sp1_version == SP1_CIRCUIT_VERSION && entry.prover == "cpu"));
+        let elf_hash = sha256::Hash::hash(b"elf 1").to_string();
+        assert!(dir
+            .path()
+            .join("keys")
+            .join(SP1_CIRCUIT_VERSION)
+            .join("cpu")
+            .join(format!("{}.keys", elf_hash))
+            .exists());
+
+        // corrupt entries are replaced
+        fs::write(
+            cache.path(ProverKind::Cpu, &entries[0].elf_hash),
+            b"garbage",
+        )
+        .unwrap();
+        setup(b"elf 1");
+        setup(b"elf 2");
+        assert_eq!(setups.get(), 3);
+
+        // other provers have their own entries
+        let value: (u64, String) = cache.get_or_insert_with(ProverKind::Mock, b"elf 1", || {
+            setups.set(setups.get() + 1);
+            (0, "mock keys".into())
+        });
+        assert_eq!(value, (0, "mock keys".into()));
+        assert_eq!(setups.get(), 4);
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(cache.clear().unwrap(), entries);
+        assert_eq!(cache.entries().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn lists_nothing_without_cache_dir() {
+        let (_dir, cache) = temp_cache();
+        assert_eq!(cache.entries().unwrap(), vec![]);
+        assert_eq!(cache.clear().unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::chain::{unspent_outputs, unspent_txout, ChainSource, Unspent};
+use anyhow::{anyhow, Result};
+use bitcoin::{
+    consensus::encode::{deserialize_hex, serialize_hex},
+    OutPoint, Transaction, TxOut, Txid,
+};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+/// [`ChainSource`] backed by a directory of hex-encoded transactions (`*.hex` files, one
+/// transaction per file). Works without a Bitcoin node.
+///
+/// Broadcast transactions are written to the directory as `<txid>.hex`.
+#[derive(Clone, Debug)]
+pub struct DirChainSource {
+    dir: PathBuf,
+}
+
+impl DirChainSource {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// All transactions in the directory.
+    pub fn txs(&self) -> Result<BTreeMap<Txid, Transaction>> {
+        let mut txs = BTreeMap::new();
+        for entry in fs::read_dir(&self.dir)
+            .map_err(|e| anyhow!("error reading {}: {}", self.dir.display(), e))?
+        {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "hex") {
+                continue;
+            }
+            let tx: Transaction = deserialize_hex(fs::read_to_string(&path)?.trim())
+                .map_err(|e| anyhow!("error parsing {}: {}", path.display(), e))?;
+            txs.insert(tx.compute_txid(), tx);
+        }
+        Ok(txs)
+    }
+}
+
+impl ChainSource for DirChainSource {
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        self.txs()?.remove(txid).ok_or(anyhow!(
+            "transaction {} not found in {}",
+            txid,
+            self.dir.display()
+        ))
+    }
+
+    fn get_txout(&self, out_point: &OutPoint) -> Result<Option<TxOut>> {
+        Ok(unspent_txout(&self.txs()?, out_point))
+    }
+
+    fn list_unspent(&self) -> Result<Vec<Unspent>> {
+        Ok(unspent_outputs(&self.txs()?))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        let txid = tx.compute_txid();
+        fs::write(self.dir.join(format!("{}.hex", txid)), serialize_hex(tx))?;
+        Ok(txid)
+    }
+}
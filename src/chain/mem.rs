@@ -0,0 +1,140 @@
+use crate::chain::{unspent_outputs, unspent_txout, ChainSource, Unspent};
+use anyhow::{anyhow, Result};
+use bitcoin::{OutPoint, Transaction, TxOut, Txid};
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// In-memory [`ChainSource`]: a set of transactions. Useful for tests.
+///
+/// Broadcast transactions are added to the set.
+#[derive(Debug, Default)]
+pub struct MemChainSource {
+    txs: Mutex<BTreeMap<Txid, Transaction>>,
+}
+
+impl MemChainSource {
+    /// Create a chain source containing `txs`.
+    pub fn new(txs: impl IntoIterator<Item = Transaction>) -> Self {
+        Self {
+            txs: Mutex::new(txs.into_iter().map(|tx| (tx.compute_txid(), tx)).collect()),
+        }
+    }
+}
+
+impl ChainSource for MemChainSource {
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        let txs = self.txs.lock().unwrap();
+        txs.get(txid)
+            .cloned()
+            .ok_or(anyhow!("transaction {} not found", txid))
+    }
+
+    fn get_txout(&self, out_point: &OutPoint) -> Result<Option<TxOut>> {
+        let txs = self.txs.lock().unwrap();
+        Ok(unspent_txout(&txs, out_point))
+    }
+
+    fn list_unspent(&self) -> Result<Vec<Unspent>> {
+        let txs = self.txs.lock().unwrap();
+        Ok(unspent_outputs(&txs))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        let txid = tx.compute_txid();
+        self.txs.lock().unwrap().insert(txid, tx.clone());
+        Ok(txid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chain::get_prev_txs;
+    use bitcoin::{
+        absolute::LockTime, hashes::Hash, transaction::Version, Amount, ScriptBuf, TxIn,
+    };
+    use std::collections::BTreeSet;
+
+    fn tx(inputs: &[OutPoint], values: &[u64]) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|&previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|&value| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn tracks_spent_outputs() {
+        let funding = tx(&[OutPoint::new(Txid::all_zeros(), 0)], &[1000, 2000]);
+        let funding_txid = funding.compute_txid();
+        let chain = MemChainSource::new([funding]);
+        assert_eq!(chain.list_unspent().unwrap().len(), 2);
+
+        let spending = tx(&[OutPoint::new(funding_txid, 1)], &[1500]);
+        let spending_txid = chain.broadcast(&spending).unwrap();
+
+        let unspent: BTreeSet<OutPoint> = chain
+            .list_unspent()
+            .unwrap()
+            .iter()
+            .map(|u| u.out_point)
+            .collect();
+        assert_eq!(
+            unspent,
+            BTreeSet::from([
+                OutPoint::new(funding_txid, 0),
+                OutPoint::new(spending_txid, 0)
+            ])
+        );
+        assert!(chain
+            .get_txout(&OutPoint::new(funding_txid, 1))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            chain
+                .get_txout(&OutPoint::new(funding_txid, 0))
+                .unwrap()
+                .map(|tx_out| tx_out.value),
+            Some(Amount::from_sat(1000))
+        );
+        assert!(chain
+            .get_txout(&OutPoint::new(funding_txid, 2))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn prev_txs() {
+        let tx_a = tx(&[OutPoint::new(Txid::all_zeros(), 0)], &[1000, 2000]);
+        let tx_b = tx(&[OutPoint::new(Txid::all_zeros(), 1)], &[3000]);
+        let spending = tx(
+            &[
+                OutPoint::new(tx_a.compute_txid(), 0),
+                OutPoint::new(tx_b.compute_txid(), 0),
+                OutPoint::new(tx_a.compute_txid(), 1),
+            ],
+            &[5500],
+        );
+        let chain = MemChainSource::new([tx_a.clone(), tx_b.clone()]);
+
+        let prev_txs = get_prev_txs(&chain, &spending).unwrap();
+        assert_eq!(prev_txs.len(), 2);
+        assert!(prev_txs.contains(&tx_a));
+        assert!(prev_txs.contains(&tx_b));
+
+        let unknown = tx(&[OutPoint::new(spending.compute_txid(), 0)], &[5000]);
+        assert!(get_prev_txs(&chain, &unknown).is_err());
+    }
+}
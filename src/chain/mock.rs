@@ -0,0 +1,1197 @@
+use crate::chain::{ChainSource, Unspent, Wallet};
+use anyhow::{anyhow, bail, ensure, Result};
+use bitcoin::{
+    absolute::LockTime,
+    bip32::{ChildNumber, DerivationPath, Fingerprint},
+    hashes::{sha256, Hash, HashEngine},
+    key::{Keypair, Secp256k1, TapTweak},
+    secp256k1::{Message, SecretKey},
+    sighash::{Prevouts, SighashCache},
+    transaction::Version,
+    Address, Amount, BlockHash, Network, OutPoint, Psbt, ScriptBuf, TapNodeHash, TapSighashType,
+    Transaction, TxIn, TxOut, Txid, Witness, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// In-process Bitcoin chain simulator: UTXO set, mempool and blocks, plus a wallet.
+///
+/// Transactions are validated for input existence (no spending of missing or already spent
+/// outputs) and amounts (outputs must not exceed inputs). Scripts and signatures are **not**
+/// checked: wallet signing is a no-op (except for [`Wallet::sign_key_spend`]).
+///
+/// Can be persisted to a JSON file, so that the CLI can run against it (`--mock-chain`).
+#[derive(Debug, Default)]
+pub struct MockChain {
+    state: Mutex<MockChainState>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct MockChainState {
+    /// All known transactions with the height of the block they're in (`None` if in mempool).
+    txs: BTreeMap<Txid, (Transaction, Option<u32>)>,
+    /// Unspent outputs (including outputs of mempool transactions).
+    utxos: BTreeMap<OutPoint, TxOut>,
+    /// Transactions waiting to be mined, in order of submission.
+    mempool: Vec<Txid>,
+    /// Mined blocks: block at height `h` is `blocks[h - 1]`.
+    blocks: Vec<Vec<Txid>>,
+    /// Scripts controlled by the wallet.
+    wallet_scripts: BTreeSet<ScriptBuf>,
+    /// Number of wallet keys derived so far.
+    wallet_keys: u32,
+    /// Wallet outputs locked with [`Wallet::lock_unspent`].
+    #[serde(default)]
+    locked: BTreeSet<OutPoint>,
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the chain persisted in the file at `path`, or start a new one if the file does not
+    /// exist. Changes are saved to the file.
+    pub fn open(path: &Path) -> Result<Self> {
+        let state = match path.exists() {
+            true => serde_json::from_slice(&fs::read(path)?)
+                .map_err(|e| anyhow!("error reading mock chain {}: {}", path.display(), e))?,
+            false => MockChainState::default(),
+        };
+        Ok(Self {
+            state: Mutex::new(state),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut MockChainState) -> Result<T>) -> Result<T> {
+        let mut state = self.state.lock().unwrap();
+        let result = f(&mut state)?;
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_vec(&*state)?)?;
+        }
+        Ok(result)
+    }
+
+    /// Height of the last mined block (`0` if no blocks have been mined).
+    pub fn tip_height(&self) -> u32 {
+        self.state.lock().unwrap().blocks.len() as u32
+    }
+
+    /// Transactions in the mempool, in order of submission.
+    pub fn mempool(&self) -> Vec<Txid> {
+        self.state.lock().unwrap().mempool.clone()
+    }
+
+    /// Transactions in the block at `height`.
+    pub fn block(&self, height: u32) -> Option<Vec<Transaction>> {
+        let state = self.state.lock().unwrap();
+        let txids = state.blocks.get((height as usize).checked_sub(1)?)?;
+        Some(txids.iter().map(|txid| state.txs[txid].0.clone()).collect())
+    }
+
+    /// Hash of the block at `height`: commits to the transactions in the block and the hash of
+    /// the previous block.
+    pub fn block_hash(&self, height: u32) -> Option<BlockHash> {
+        let state = self.state.lock().unwrap();
+        let blocks = state.blocks.get(..(height as usize).checked_sub(1)? + 1)?;
+        Some(
+            blocks
+                .iter()
+                .fold(BlockHash::all_zeros(), |prev_hash, txids| {
+                    let mut engine = BlockHash::engine();
+                    engine.input(prev_hash.as_byte_array());
+                    for txid in txids {
+                        engine.input(txid.as_byte_array());
+                    }
+                    BlockHash::from_engine(engine)
+                }),
+        )
+    }
+
+    /// Get a new address controlled by the wallet.
+    pub fn new_address(&self) -> Result<Address> {
+        self.update(|state| {
+            let secp256k1 = Secp256k1::new();
+            let (public_key, _) = state.new_keypair()?.x_only_public_key();
+            let address = Address::p2tr(&secp256k1, public_key, None, Network::Regtest);
+            state.wallet_scripts.insert(address.script_pubkey());
+            Ok(address)
+        })
+    }
+
+    /// Create (out of thin air) and mine an output of `value` to a new wallet address.
+    pub fn fund(&self, value: Amount) -> Result<OutPoint> {
+        let address = self.new_address()?;
+        self.update(|state| {
+            let height = state.blocks.len() as u32 + 1;
+            let coinbase_tx = Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: ScriptBuf::builder()
+                        .push_int(height as i64)
+                        .push_int(state.txs.len() as i64)
+                        .into_script(),
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey: address.script_pubkey(),
+                }],
+            };
+            let txid = coinbase_tx.compute_txid();
+            state.add_tx(coinbase_tx);
+            state.mine();
+            Ok(OutPoint::new(txid, 0))
+        })
+    }
+
+    /// Validate `tx` and add it to the mempool.
+    pub fn submit(&self, tx: &Transaction) -> Result<Txid> {
+        self.update(|state| {
+            state.validate(tx)?;
+            Ok(state.add_tx(tx.clone()))
+        })
+    }
+
+    /// Mine a block with all transactions in the mempool. Returns the height of the new block.
+    pub fn mine(&self) -> Result<u32> {
+        self.update(|state| Ok(state.mine()))
+    }
+
+    /// Simulate a chain reorganization: disconnect the last `depth` blocks (their transactions go
+    /// back to the mempool) and evict transactions `evicted` from the mempool (their outputs must
+    /// be unspent). Mine to build the new chain.
+    pub fn reorg(&self, depth: u32, evicted: &[Txid]) -> Result<()> {
+        self.update(|state| {
+            let mut new_state = state.clone();
+            let height = (new_state.blocks.len() as u32)
+                .checked_sub(depth)
+                .ok_or(anyhow!("can't disconnect {} blocks", depth))?;
+            let mut txids: Vec<Txid> = new_state
+                .blocks
+                .drain(height as usize..)
+                .flatten()
+                .collect();
+            for txid in &txids {
+                if let Some((_, tx_height)) = new_state.txs.get_mut(txid) {
+                    *tx_height = None;
+                }
+            }
+            txids.append(&mut new_state.mempool);
+            new_state.mempool = txids;
+            for txid in evicted {
+                new_state.evict(txid)?;
+            }
+            *state = new_state;
+            Ok(())
+        })
+    }
+}
+
+impl MockChainState {
+    fn wallet_keypair(index: u32) -> Result<Keypair> {
+        let secp256k1 = Secp256k1::new();
+        let seed = sha256::Hash::hash(&index.to_le_bytes());
+        Ok(Keypair::from_secret_key(
+            &secp256k1,
+            &SecretKey::from_slice(seed.as_byte_array())?,
+        ))
+    }
+
+    fn new_keypair(&mut self) -> Result<Keypair> {
+        let keypair = Self::wallet_keypair(self.wallet_keys)?;
+        self.wallet_keys += 1;
+        Ok(keypair)
+    }
+
+    fn validate(&self, tx: &Transaction) -> Result<()> {
+        let txid = tx.compute_txid();
+        ensure!(
+            !self.txs.contains_key(&txid),
+            "transaction {} already exists",
+            txid
+        );
+        ensure!(!tx.input.is_empty(), "transaction {} has no inputs", txid);
+        ensure!(!tx.output.is_empty(), "transaction {} has no outputs", txid);
+
+        let mut spent = BTreeSet::new();
+        let mut amount_in = Amount::ZERO;
+        for tx_in in &tx.input {
+            let out_point = &tx_in.previous_output;
+            ensure!(
+                spent.insert(out_point),
+                "transaction {} spends {} twice",
+                txid,
+                out_point
+            );
+            let Some(tx_out) = self.utxos.get(out_point) else {
+                bail!(
+                    "transaction {} spends missing or already spent output {}",
+                    txid,
+                    out_point
+                );
+            };
+            amount_in = amount_in
+                .checked_add(tx_out.value)
+                .ok_or(anyhow!("input amount overflow"))?;
+        }
+
+        let amount_out = tx
+            .output
+            .iter()
+            .try_fold(Amount::ZERO, |total, tx_out| {
+                total.checked_add(tx_out.value)
+            })
+            .ok_or(anyhow!("output amount overflow"))?;
+        ensure!(
+            amount_out <= amount_in,
+            "transaction {} outputs ({}) exceed inputs ({})",
+            txid,
+            amount_out,
+            amount_in
+        );
+        Ok(())
+    }
+
+    fn add_tx(&mut self, tx: Transaction) -> Txid {
+        let txid = tx.compute_txid();
+        for tx_in in &tx.input {
+            self.utxos.remove(&tx_in.previous_output);
+            self.locked.remove(&tx_in.previous_output);
+        }
+        for (tx_out, vout) in tx.output.iter().zip(0..) {
+            self.utxos.insert(OutPoint::new(txid, vout), tx_out.clone());
+        }
+        self.mempool.push(txid);
+        self.txs.insert(txid, (tx, None));
+        txid
+    }
+
+    /// Remove a mempool transaction, restoring the outputs it spends.
+    fn evict(&mut self, txid: &Txid) -> Result<()> {
+        ensure!(
+            self.mempool.contains(txid),
+            "transaction {} is not in the mempool",
+            txid
+        );
+        let (tx, _) = self.txs[txid].clone();
+        for vout in 0..tx.output.len() as u32 {
+            let out_point = OutPoint::new(*txid, vout);
+            ensure!(
+                self.utxos.contains_key(&out_point),
+                "output {} is spent",
+                out_point
+            );
+        }
+        for vout in 0..tx.output.len() as u32 {
+            self.utxos.remove(&OutPoint::new(*txid, vout));
+        }
+        for tx_in in &tx.input {
+            let out_point = tx_in.previous_output;
+            if let Some(tx_out) = self
+                .txs
+                .get(&out_point.txid)
+                .and_then(|(prev_tx, _)| prev_tx.output.get(out_point.vout as usize))
+            {
+                self.utxos.insert(out_point, tx_out.clone());
+            }
+        }
+        self.mempool.retain(|mempool_txid| mempool_txid != txid);
+        self.txs.remove(txid);
+        Ok(())
+    }
+
+    fn mine(&mut self) -> u32 {
+        let height = self.blocks.len() as u32 + 1;
+        let txids: Vec<Txid> = self.mempool.drain(..).collect();
+        for txid in &txids {
+            if let Some((_, tx_height)) = self.txs.get_mut(txid) {
+                *tx_height = Some(height);
+            }
+        }
+        self.blocks.push(txids);
+        height
+    }
+
+    fn confirmations(&self, txid: &Txid) -> u32 {
+        match self.txs.get(txid) {
+            Some((_, Some(height))) => self.blocks.len() as u32 - height + 1,
+            _ => 0,
+        }
+    }
+}
+
+impl ChainSource for MockChain {
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        let state = self.state.lock().unwrap();
+        state
+            .txs
+            .get(txid)
+            .map(|(tx, _)| tx.clone())
+            .ok_or(anyhow!("transaction {} not found", txid))
+    }
+
+    fn get_txout(&self, out_point: &OutPoint) -> Result<Option<TxOut>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.utxos.get(out_point).cloned())
+    }
+
+    fn list_unspent(&self) -> Result<Vec<Unspent>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .utxos
+            .iter()
+            .filter(|(_, tx_out)| state.wallet_scripts.contains(&tx_out.script_pubkey))
+            .map(|(out_point, tx_out)| Unspent {
+                out_point: *out_point,
+                value: tx_out.value,
+                script_pubkey: tx_out.script_pubkey.clone(),
+                confirmations: state.confirmations(&out_point.txid),
+                locked: state.locked.contains(out_point),
+            })
+            .collect())
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.submit(tx)
+    }
+}
+
+impl Wallet for MockChain {
+    fn new_change_address(&self) -> Result<Address> {
+        self.new_address()
+    }
+
+    fn sign_tx(&self, tx: &Transaction, _prev_outs: &[(OutPoint, TxOut)]) -> Result<Transaction> {
+        Ok(tx.clone())
+    }
+
+    fn new_refund_key(&self) -> Result<XOnlyPublicKey> {
+        self.update(|state| Ok(state.new_keypair()?.x_only_public_key().0))
+    }
+
+    /// Unlike [`Wallet::sign_tx`], produces a real signature.
+    fn sign_key_spend(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prev_outs: &[TxOut],
+        internal_key: XOnlyPublicKey,
+        merkle_root: Option<TapNodeHash>,
+    ) -> Result<Transaction> {
+        let wallet_keys = self.state.lock().unwrap().wallet_keys;
+        let keypair = (0..wallet_keys)
+            .map(MockChainState::wallet_keypair)
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .find(|keypair| keypair.x_only_public_key().0 == internal_key)
+            .ok_or(anyhow!("key {} is not in the wallet", internal_key))?;
+
+        let secp256k1 = Secp256k1::new();
+        let tweaked = keypair.tap_tweak(&secp256k1, merkle_root).to_inner();
+        let sighash = SighashCache::new(tx).taproot_key_spend_signature_hash(
+            input_index,
+            &Prevouts::All(prev_outs),
+            TapSighashType::Default,
+        )?;
+        let signature =
+            secp256k1.sign_schnorr(&Message::from_digest_slice(sighash.as_ref())?, &tweaked);
+
+        let mut tx = tx.clone();
+        tx.input[input_index].witness = Witness::from_slice(&[signature.as_ref()]);
+        Ok(tx)
+    }
+
+    fn lock_unspent(&self, out_points: &[OutPoint]) -> Result<()> {
+        self.update(|state| {
+            for out_point in out_points {
+                ensure!(
+                    state
+                        .utxos
+                        .get(out_point)
+                        .is_some_and(|tx_out| state.wallet_scripts.contains(&tx_out.script_pubkey)),
+                    "output {} is not an unspent wallet output",
+                    out_point
+                );
+            }
+            state.locked.extend(out_points);
+            Ok(())
+        })
+    }
+
+    /// Wallet keys have no master key: their origin is fingerprint `00000000` and path `m/<n>`
+    /// for the `n`-th key.
+    fn add_key_origins(&self, psbt: &mut Psbt) -> Result<()> {
+        let secp256k1 = Secp256k1::new();
+        let wallet_keys = self.state.lock().unwrap().wallet_keys;
+        let keys = (0..wallet_keys)
+            .map(|n| Ok((n, MockChainState::wallet_keypair(n)?.x_only_public_key().0)))
+            .collect::<Result<Vec<_>>>()?;
+        for input in psbt.inputs.iter_mut() {
+            let Some(tx_out) = &input.witness_utxo else {
+                continue;
+            };
+            let Some(&(n, key)) = keys.iter().find(|(_, key)| {
+                tx_out.script_pubkey
+                    == Address::p2tr(&secp256k1, *key, None, Network::Regtest).script_pubkey()
+            }) else {
+                continue;
+            };
+            let path = DerivationPath::from(vec![ChildNumber::from_normal_idx(n)?]);
+            input.tap_internal_key = Some(key);
+            input
+                .tap_key_origins
+                .insert(key, (vec![], (Fingerprint::default(), path)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::{
+        app,
+        chain::get_prev_txs,
+        spell::{align_spell_to_tx, prove_spell_tx, ProofMode, Spell},
+        tx::{
+            add_spell, commit_spend_info, from_spell, may_carry_charms, recover_commit_tx,
+            txs_by_txid,
+        },
+    };
+    use bitcoin::FeeRate;
+    use charms_client::{
+        prev_spells, to_tx,
+        tx::{extract_and_verify_spell, mock_proof},
+        MOCK_SPELL_VK,
+    };
+    use charms_data::{
+        app_datas, is_simple_transfer, sum_token_amount, util, App, Charms, Data, TokenAmount, B32,
+        NFT, TOKEN,
+    };
+    use tempfile::TempDir;
+
+    fn tx(inputs: &[OutPoint], outputs: &[(u64, &ScriptBuf)]) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|&previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|&(value, script_pubkey)| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn validates_txs() {
+        let chain = MockChain::new();
+        let funding = chain.fund(Amount::from_sat(10_000)).unwrap();
+        let script = chain.new_address().unwrap().script_pubkey();
+
+        let missing_input = OutPoint::new(Txid::all_zeros(), 0);
+        assert!(chain
+            .submit(&tx(&[missing_input], &[(1000, &script)]))
+            .is_err());
+        assert!(chain.submit(&tx(&[funding], &[(10_001, &script)])).is_err());
+        assert!(chain
+            .submit(&tx(&[funding, funding], &[(1000, &script)]))
+            .is_err());
+
+        let txid = chain.submit(&tx(&[funding], &[(9_000, &script)])).unwrap();
+        assert_eq!(chain.mempool(), vec![txid]);
+        assert!(chain.get_txout(&funding).unwrap().is_none());
+
+        // double spend
+        assert!(chain.submit(&tx(&[funding], &[(8_000, &script)])).is_err());
+
+        // spending an output of a mempool transaction
+        let child_txid = chain
+            .submit(&tx(&[OutPoint::new(txid, 0)], &[(8_000, &script)]))
+            .unwrap();
+
+        let height = chain.mine().unwrap();
+        assert_eq!(height, chain.tip_height());
+        assert_eq!(chain.block(height).unwrap().len(), 2);
+        assert!(chain.mempool().is_empty());
+
+        let unspent = chain.list_unspent().unwrap();
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].out_point, OutPoint::new(child_txid, 0));
+        assert_eq!(unspent[0].value, Amount::from_sat(8_000));
+        assert_eq!(unspent[0].confirmations, 1);
+    }
+
+    #[test]
+    fn reorgs() {
+        let chain = MockChain::new();
+        let funding = chain.fund(Amount::from_sat(10_000)).unwrap();
+        let script = chain.new_address().unwrap().script_pubkey();
+        let txid = chain.submit(&tx(&[funding], &[(9_000, &script)])).unwrap();
+        let child_txid = chain
+            .submit(&tx(&[OutPoint::new(txid, 0)], &[(8_000, &script)]))
+            .unwrap();
+        let height = chain.mine().unwrap();
+        let hash = chain.block_hash(height).unwrap();
+
+        // the parent's output is spent by the child
+        assert!(chain.reorg(1, &[txid]).is_err());
+        assert_eq!(chain.block_hash(height), Some(hash));
+
+        chain.reorg(1, &[child_txid]).unwrap();
+        assert_eq!(chain.tip_height(), height - 1);
+        assert_eq!(chain.mempool(), vec![txid]);
+        assert!(chain.get_transaction(&child_txid).is_err());
+        assert!(chain.get_txout(&OutPoint::new(txid, 0)).unwrap().is_some());
+
+        assert_eq!(chain.mine().unwrap(), height);
+        assert_ne!(chain.block_hash(height), Some(hash));
+        assert_eq!(chain.block(height).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn persists_state() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("chain.json");
+        let funding = {
+            let chain = MockChain::open(&path).unwrap();
+            chain.fund(Amount::from_sat(10_000)).unwrap()
+        };
+        let chain = MockChain::open(&path).unwrap();
+
+        assert_eq!(chain.tip_height(), 1);
+        let unspent = chain.list_unspent().unwrap();
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].out_point, funding);
+    }
+
+    #[test]
+    fn locks_wallet_outputs() {
+        let chain = MockChain::new();
+        let funding = chain.fund(Amount::from_sat(10_000)).unwrap();
+        let other = chain.fund(Amount::from_sat(10_000)).unwrap();
+        let foreign_script = ScriptBuf::new_op_return([]);
+        let txid = chain
+            .submit(&tx(&[other], &[(9_000, &foreign_script)]))
+            .unwrap();
+
+        // not a wallet output, already spent
+        assert!(chain.lock_unspent(&[OutPoint::new(txid, 0)]).is_err());
+        assert!(chain.lock_unspent(&[other]).is_err());
+
+        chain.lock_unspent(&[funding]).unwrap();
+        let unspent = chain.list_unspent().unwrap();
+        assert_eq!(unspent.len(), 1);
+        assert!(unspent[0].locked);
+
+        // locked outputs can still be spent, which releases the lock
+        let script = chain.new_address().unwrap().script_pubkey();
+        let txid = chain.submit(&tx(&[funding], &[(9_000, &script)])).unwrap();
+        let unspent = chain.list_unspent().unwrap();
+        assert_eq!(unspent[0].out_point, OutPoint::new(txid, 0));
+        assert!(!unspent[0].locked);
+        assert!(chain.state.lock().unwrap().locked.is_empty());
+    }
+
+    #[test]
+    fn adds_key_origins() {
+        let chain = MockChain::new();
+        chain.new_address().unwrap();
+        let funding = chain.fund(Amount::from_sat(10_000)).unwrap();
+        let foreign_out_point = OutPoint::new(Txid::all_zeros(), 0);
+        let script = chain.new_address().unwrap().script_pubkey();
+        let mut psbt =
+            Psbt::from_unsigned_tx(tx(&[funding, foreign_out_point], &[(9_000, &script)])).unwrap();
+        psbt.inputs[0].witness_utxo = chain.get_txout(&funding).unwrap();
+        psbt.inputs[1].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(1_000),
+            script_pubkey: ScriptBuf::new_op_return([]),
+        });
+
+        chain.add_key_origins(&mut psbt).unwrap();
+        // the funding output is locked by the second wallet key
+        let key = MockChainState::wallet_keypair(1)
+            .unwrap()
+            .x_only_public_key()
+            .0;
+        assert_eq!(psbt.inputs[0].tap_internal_key, Some(key));
+        assert_eq!(
+            psbt.inputs[0].tap_key_origins[&key],
+            (vec![], (Fingerprint::default(), "m/1".parse().unwrap()))
+        );
+        assert_eq!(psbt.inputs[1].tap_internal_key, None);
+        assert!(psbt.inputs[1].tap_key_origins.is_empty());
+    }
+
+    /// Create the commit and spell transactions for `spell` the way `charms wallet cast` does, but
+    /// with a mock proof made without checking the spell. The funding transaction is broadcast and
+    /// mined. For tests of code consuming spells (e.g. the index): spells are proven by
+    /// [`prove_and_cast`].
+    pub(crate) fn spell_txs(chain: &MockChain, spell: &str) -> Result<[Transaction; 2]> {
+        let spell: Spell = serde_yaml::from_str(spell)?;
+        let tx = from_spell(&spell);
+        let prev_txs = txs_by_txid(get_prev_txs(chain, &tx)?)?;
+
+        let (norm_spell, _) = spell.normalized()?;
+        let mut norm_spell = align_spell_to_tx(norm_spell, &tx)?;
+        let proof = mock_proof(&util::write(&(MOCK_SPELL_VK, &norm_spell))?);
+        norm_spell.tx.ins = None;
+        let spell_data = util::write(&(&norm_spell, proof))?;
+
+        let funding = chain.fund(Amount::from_sat(10_000))?;
+        let funding_txout = chain.get_txout(&funding)?.unwrap();
+        let change_address = chain.new_change_address()?;
+
+        add_spell(
+            tx,
+            &spell_data,
+            &[(funding, funding_txout)],
+            change_address.script_pubkey(),
+            Some(chain.new_refund_key()?),
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &prev_txs,
+        )
+    }
+
+    /// Cast `spell` on `chain`: commit and spell transactions (see [`spell_txs`]) are broadcast and
+    /// mined.
+    pub(crate) fn cast(chain: &MockChain, spell: &str) -> Result<Transaction> {
+        let [commit_tx, spell_tx] = spell_txs(chain, spell)?;
+        chain.broadcast(&chain.sign_tx(&commit_tx, &[])?)?;
+        chain.broadcast(&spell_tx)?;
+        chain.mine()?;
+        Ok(spell_tx)
+    }
+
+    /// Cast `spell` on `chain` the way `charms wallet cast --mock` does: the spell is proven with
+    /// [`prove_spell_tx`] in [`ProofMode::Mock`], then the commit and spell transactions are
+    /// broadcast and mined. App contracts are run natively (see [`app::NATIVE_CONTRACTS`]).
+    fn prove_and_cast(chain: &MockChain, spell: &str) -> Result<Transaction> {
+        let spell: Spell = serde_yaml::from_str(spell)?;
+        let tx = from_spell(&spell);
+        let prev_txs = txs_by_txid(get_prev_txs(chain, &tx)?)?;
+        let funding = chain.fund(Amount::from_sat(10_000))?;
+        let funding_txout = chain.get_txout(&funding)?.unwrap();
+
+        let [commit_tx, spell_tx] = prove_spell_tx(
+            spell,
+            tx,
+            BTreeMap::new(),
+            prev_txs,
+            vec![(funding, funding_txout)],
+            chain.new_change_address()?.to_string(),
+            Some(chain.new_refund_key()?),
+            2.0,
+            ProofMode::Mock,
+        )?;
+        chain.broadcast(&chain.sign_tx(&commit_tx, &[])?)?;
+        chain.broadcast(&spell_tx)?;
+        chain.mine()?;
+        Ok(spell_tx)
+    }
+
+    /// Extract and verify the (mock-proven) spell from `tx` and return the Charms transaction it
+    /// creates.
+    fn charms_tx(chain: &MockChain, tx: &Transaction) -> charms_data::Transaction {
+        let spell = extract_and_verify_spell(tx, MOCK_SPELL_VK).unwrap();
+        let prev_spells = prev_spells(&get_prev_txs(chain, tx).unwrap(), MOCK_SPELL_VK);
+        to_tx(&spell, &prev_spells)
+    }
+
+    const TOAD_VK: B32 = B32([7; 32]);
+
+    /// Content of toad NFTs.
+    #[derive(Deserialize)]
+    struct ToadNft {
+        remaining: TokenAmount,
+    }
+
+    /// The contract of the toad-token example app. An NFT is minted by spending the UTXO whose ID
+    /// (passed in `w`) hashes to the NFT identity. Tokens are minted by decreasing the `remaining`
+    /// supply in the NFT.
+    fn toad_contract(app: &App, tx: &charms_data::Transaction, _x: &Data, w: &Data) -> bool {
+        let nft = App {
+            tag: NFT,
+            ..app.clone()
+        };
+        let token = App {
+            tag: TOKEN,
+            ..app.clone()
+        };
+        let can_mint_nft = || {
+            let Ok(utxo_id) = w.value::<String>() else {
+                return false;
+            };
+            B32(sha256::Hash::hash(utxo_id.as_bytes()).to_byte_array()) == nft.identity
+                && tx.ins.keys().any(|in_utxo| in_utxo.to_string() == utxo_id)
+                && app_datas(&nft, tx.outs.iter()).count() == 1
+        };
+        let can_mint_token = || {
+            let (Some(incoming), Some(outgoing)) = (
+                remaining(&nft, tx.ins.values()),
+                remaining(&nft, tx.outs.iter()),
+            ) else {
+                return false;
+            };
+            let (Ok(ins), Ok(outs)) = (
+                sum_token_amount(&token, tx.ins.values()),
+                sum_token_amount(&token, tx.outs.iter()),
+            ) else {
+                return false;
+            };
+            outs.checked_sub(ins).is_some()
+                && outs.checked_sub(ins) == incoming.checked_sub(outgoing)
+        };
+        match app.tag {
+            NFT => can_mint_nft() || can_mint_token(),
+            TOKEN => can_mint_token(),
+            _ => false,
+        }
+    }
+
+    /// Remaining token supply in the toad NFT `nft` among `charms`.
+    fn remaining<'a>(
+        nft: &'a App,
+        charms: impl Iterator<Item = &'a Charms>,
+    ) -> Option<TokenAmount> {
+        app_datas(nft, charms).find_map(|data| Some(data.value::<ToadNft>().ok()?.remaining))
+    }
+
+    /// Toad NFT and token apps, for the NFT minted by spending `utxo` (see [`toad_contract`]).
+    fn toad_apps(utxo: &OutPoint) -> (App, App) {
+        app::NATIVE_CONTRACTS
+            .lock()
+            .unwrap()
+            .insert(TOAD_VK, toad_contract);
+        let identity = B32(sha256::Hash::hash(utxo.to_string().as_bytes()).to_byte_array());
+        let nft = App {
+            tag: NFT,
+            identity: identity.clone(),
+            vk: TOAD_VK,
+        };
+        let token = App {
+            tag: TOKEN,
+            identity,
+            vk: TOAD_VK,
+        };
+        (nft, token)
+    }
+
+    /// Spell minting the toad NFT of `nft` by spending `in_utxo`, with `remaining` supply.
+    fn mint_nft_spell(nft: &App, in_utxo: &OutPoint, address: &str, remaining: u64) -> String {
+        format!(
+            r#"
+version: 2
+apps:
+  $00: {nft}
+private_inputs:
+  $00: "{in_utxo}"
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {address}
+    sats: 1000
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: {remaining}
+"#
+        )
+    }
+
+    #[test]
+    fn spell_lifecycle() {
+        let chain = MockChain::new();
+        let address = || chain.new_address().unwrap().to_string();
+
+        // mint the NFT
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let (nft, token) = toad_apps(&in_utxo);
+        let mint_nft_tx =
+            prove_and_cast(&chain, &mint_nft_spell(&nft, &in_utxo, &address(), 100000)).unwrap();
+        let mint_nft = charms_tx(&chain, &mint_nft_tx);
+        assert!(mint_nft.ins.values().all(|charms| charms.is_empty()));
+        assert!(mint_nft.outs[0].contains_key(&nft));
+        assert!(may_carry_charms(&mint_nft_tx, 0));
+        assert!(!may_carry_charms(&mint_nft_tx, 1)); // change
+
+        // mint the token, updating the NFT state
+        let mint_token_tx = prove_and_cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {nft}
+  $01: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: 100000
+outs:
+  - address: {addr_0}
+    sats: 1000
+    charms:
+      $01: 69420
+  - address: {addr_1}
+    sats: 1000
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: 30580
+"#,
+                in_utxo = OutPoint::new(mint_nft_tx.compute_txid(), 0),
+                addr_0 = address(),
+                addr_1 = address(),
+            ),
+        )
+        .unwrap();
+        let mint_token = charms_tx(&chain, &mint_token_tx);
+        assert!(mint_token.ins.values().next().unwrap().contains_key(&nft));
+        assert_eq!(
+            sum_token_amount(&token, mint_token.ins.values()).unwrap(),
+            TokenAmount(0)
+        );
+        assert_eq!(
+            sum_token_amount(&token, mint_token.outs.iter()).unwrap(),
+            TokenAmount(69420)
+        );
+
+        // transfer the token
+        let transfer_tx = prove_and_cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $01: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms:
+      $01: 69420
+outs:
+  - address: {addr_0}
+    sats: 1000
+    charms:
+      $01: 420
+  - address: {addr_1}
+    sats: 1000
+    charms:
+      $01: 69000
+"#,
+                in_utxo = OutPoint::new(mint_token_tx.compute_txid(), 0),
+                addr_0 = address(),
+                addr_1 = address(),
+            ),
+        )
+        .unwrap();
+        let transfer = charms_tx(&chain, &transfer_tx);
+        assert_eq!(
+            sum_token_amount(&token, transfer.ins.values()).unwrap(),
+            TokenAmount(69420)
+        );
+        assert!(is_simple_transfer(&token, &transfer));
+
+        // spend both token outputs of the transfer
+        let spend_tx = prove_and_cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $01: {token}
+ins:
+  - utxo_id: {in_utxo_0}
+    charms:
+      $01: 420
+  - utxo_id: {in_utxo_1}
+    charms:
+      $01: 69000
+outs:
+  - address: {addr_0}
+    sats: 1000
+    charms:
+      $01: 69420
+"#,
+                in_utxo_0 = OutPoint::new(transfer_tx.compute_txid(), 0),
+                in_utxo_1 = OutPoint::new(transfer_tx.compute_txid(), 1),
+                addr_0 = address(),
+            ),
+        )
+        .unwrap();
+        let spent = charms_tx(&chain, &spend_tx);
+        assert_eq!(spent.ins.len(), 2);
+        assert!(is_simple_transfer(&token, &spent));
+        assert_eq!(
+            sum_token_amount(&token, spent.outs.iter()).unwrap(),
+            TokenAmount(69420)
+        );
+
+        // mock proofs are only accepted when explicitly enabled
+        assert!(extract_and_verify_spell(&spend_tx, crate::SPELL_VK).is_err());
+
+        let charm_utxo = OutPoint::new(spend_tx.compute_txid(), 0);
+        assert!(chain
+            .list_unspent()
+            .unwrap()
+            .iter()
+            .any(|u| u.out_point == charm_utxo));
+    }
+
+    #[test]
+    fn rejects_incorrect_spells() {
+        let chain = MockChain::new();
+        let address = chain.new_address().unwrap().to_string();
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let (nft, token) = toad_apps(&in_utxo);
+
+        // the NFT identity is not the hash of the spent UTXO
+        let other_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let e =
+            prove_and_cast(&chain, &mint_nft_spell(&nft, &other_utxo, &address, 100)).unwrap_err();
+        assert!(
+            e.to_string().contains("app contract not satisfied"),
+            "{}",
+            e
+        );
+
+        // minting more tokens than the NFT allows
+        let mint_nft_tx =
+            prove_and_cast(&chain, &mint_nft_spell(&nft, &in_utxo, &address, 100)).unwrap();
+        let mint_token = |amount: u64| {
+            prove_and_cast(
+                &chain,
+                &format!(
+                    r#"
+version: 2
+apps:
+  $00: {nft}
+  $01: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: 100
+outs:
+  - address: {address}
+    sats: 1000
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: 90
+      $01: {amount}
+"#,
+                    in_utxo = OutPoint::new(mint_nft_tx.compute_txid(), 0),
+                ),
+            )
+        };
+        let mempool = chain.mempool();
+        let e = mint_token(11).unwrap_err();
+        assert!(
+            e.to_string().contains("app contract not satisfied"),
+            "{}",
+            e
+        );
+        // nothing is broadcast
+        assert_eq!(chain.mempool(), mempool);
+        mint_token(10).unwrap();
+    }
+
+    #[test]
+    fn rejects_double_spends() {
+        let chain = MockChain::new();
+        let address = || chain.new_address().unwrap().to_string();
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let (nft, _) = toad_apps(&in_utxo);
+        let mint_nft_tx =
+            prove_and_cast(&chain, &mint_nft_spell(&nft, &in_utxo, &address(), 100)).unwrap();
+
+        let send_nft = |address: String| {
+            prove_and_cast(
+                &chain,
+                &format!(
+                    r#"
+version: 2
+apps:
+  $00: {nft}
+ins:
+  - utxo_id: {in_utxo}
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: 100
+outs:
+  - address: {address}
+    sats: 1000
+    charms:
+      $00:
+        ticker: TOAD
+        remaining: 100
+"#,
+                    in_utxo = OutPoint::new(mint_nft_tx.compute_txid(), 0),
+                ),
+            )
+        };
+        let send_tx = send_nft(address()).unwrap();
+        let e = send_nft(address()).unwrap_err();
+        assert!(e.to_string().contains("already spent"), "{}", e);
+
+        let nft_utxo = OutPoint::new(send_tx.compute_txid(), 0);
+        assert!(chain.get_txout(&nft_utxo).unwrap().is_some());
+    }
+
+    #[test]
+    fn reorg_drops_spells() {
+        let chain = MockChain::new();
+        let address = chain.new_address().unwrap().to_string();
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let (nft, _) = toad_apps(&in_utxo);
+        let mint_nft_tx =
+            prove_and_cast(&chain, &mint_nft_spell(&nft, &in_utxo, &address, 100)).unwrap();
+        let nft_utxo = OutPoint::new(mint_nft_tx.compute_txid(), 0);
+        assert!(chain.get_txout(&nft_utxo).unwrap().is_some());
+
+        // the block with the spell is replaced by one without it
+        chain.reorg(1, &[mint_nft_tx.compute_txid()]).unwrap();
+        chain.mine().unwrap();
+        assert!(chain.get_txout(&nft_utxo).unwrap().is_none());
+        assert!(!chain.mempool().contains(&mint_nft_tx.compute_txid()));
+        // the UTXO the NFT was minted from is unspent again
+        assert!(chain.get_txout(&in_utxo).unwrap().is_some());
+    }
+
+    #[test]
+    fn recovers_commit_output() {
+        let chain = MockChain::new();
+        let secp256k1 = Secp256k1::new();
+
+        let nft = App {
+            tag: charms_data::NFT,
+            identity: B32(sha256::Hash::hash(b"toad").to_byte_array()),
+            vk: B32([7; 32]),
+        };
+        let in_utxo = chain.fund(Amount::from_sat(1_000)).unwrap();
+        let [commit_tx, spell_tx] = spell_txs(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {nft}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {addr_0}
+    sats: 5000
+    charms:
+      $00:
+        ticker: TOAD
+"#,
+                addr_0 = chain.new_address().unwrap(),
+            ),
+        )
+        .unwrap();
+
+        // the spell tx never makes it to the chain
+        chain.broadcast(&commit_tx).unwrap();
+        chain.mine().unwrap();
+
+        let info = commit_spend_info(&spell_tx).unwrap();
+        assert_eq!(info.out_point, OutPoint::new(commit_tx.compute_txid(), 0));
+        let commit_txout = chain.get_txout(&info.out_point).unwrap().unwrap();
+
+        let script_pubkey = chain.new_change_address().unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let recover_tx =
+            recover_commit_tx(&info, &commit_txout, script_pubkey.clone(), fee_rate).unwrap();
+
+        // not the committed spell output
+        let other_txout = TxOut {
+            script_pubkey: script_pubkey.clone(),
+            ..commit_txout.clone()
+        };
+        assert!(recover_commit_tx(&info, &other_txout, script_pubkey, fee_rate).is_err());
+        let prev_outs = [commit_txout.clone()];
+
+        let foreign_key = Keypair::new(&secp256k1, &mut bitcoin::secp256k1::rand::thread_rng());
+        assert!(chain
+            .sign_key_spend(
+                &recover_tx,
+                0,
+                &prev_outs,
+                foreign_key.x_only_public_key().0,
+                Some(info.merkle_root)
+            )
+            .is_err());
+
+        let signed_tx = chain
+            .sign_key_spend(
+                &recover_tx,
+                0,
+                &prev_outs,
+                info.internal_key,
+                Some(info.merkle_root),
+            )
+            .unwrap();
+
+        // the signature is valid for the commit tx output key
+        let output_key =
+            XOnlyPublicKey::from_slice(&commit_txout.script_pubkey.as_bytes()[2..]).unwrap();
+        let sighash = SighashCache::new(&recover_tx)
+            .taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&prev_outs),
+                TapSighashType::Default,
+            )
+            .unwrap();
+        let signature =
+            bitcoin::secp256k1::schnorr::Signature::from_slice(&signed_tx.input[0].witness[0])
+                .unwrap();
+        secp256k1
+            .verify_schnorr(
+                &signature,
+                &Message::from_digest_slice(sighash.as_ref()).unwrap(),
+                &output_key,
+            )
+            .unwrap();
+        assert!(signed_tx.vsize() <= 111);
+        assert_eq!(
+            commit_txout.value - signed_tx.output[0].value,
+            fee_rate.fee_wu(signed_tx.weight()).unwrap()
+        );
+
+        let recovered = OutPoint::new(chain.broadcast(&signed_tx).unwrap(), 0);
+        chain.mine().unwrap();
+        assert!(chain
+            .list_unspent()
+            .unwrap()
+            .iter()
+            .any(|u| u.out_point == recovered));
+
+        // the spell tx can't be broadcast anymore
+        assert!(chain.broadcast(&spell_tx).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+pub mod dir;
+pub mod mem;
+pub mod mock;
+pub mod rpc;
+
+use anyhow::Result;
+use bitcoin::{
+    Address, Amount, OutPoint, Psbt, ScriptBuf, TapNodeHash, Transaction, TxOut, Txid,
+    XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Unspent transaction output as reported by a [`ChainSource`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Unspent {
+    pub out_point: OutPoint,
+    pub value: Amount,
+    pub script_pubkey: ScriptBuf,
+    pub confirmations: u32,
+    /// Locked by the wallet (see [`Wallet::lock_unspent`]): not to be used to fund transactions.
+    #[serde(default)]
+    pub locked: bool,
+}
+
+/// Source of blockchain data (transactions and UTXOs) for the CLI and the library.
+///
+/// Implemented by [`rpc::RpcChainSource`] (bitcoind via JSON-RPC), [`dir::DirChainSource`] (a
+/// directory of hex-encoded transactions), [`mem::MemChainSource`] (in-memory, for tests) and
+/// [`mock::MockChain`] (in-process chain simulator).
+pub trait ChainSource {
+    /// Get a transaction by its ID.
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction>;
+
+    /// Get an unspent transaction output. Returns `None` if the output does not exist or is
+    /// already spent.
+    fn get_txout(&self, out_point: &OutPoint) -> Result<Option<TxOut>>;
+
+    /// List unspent outputs (controlled by the wallet, if the source has one), including locked
+    /// ones.
+    fn list_unspent(&self) -> Result<Vec<Unspent>>;
+
+    /// Broadcast a (signed) transaction.
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid>;
+}
+
+/// Wallet operations on top of a [`ChainSource`]: needed to fund and sign transactions.
+pub trait Wallet: ChainSource {
+    /// Get a new address to send the change to.
+    fn new_change_address(&self) -> Result<Address>;
+
+    /// Sign the inputs of `tx` the wallet can sign.
+    /// `prev_outs` provides the outputs spent by `tx` that the wallet may not know about (e.g.
+    /// outputs of transactions not yet broadcast).
+    fn sign_tx(&self, tx: &Transaction, prev_outs: &[(OutPoint, TxOut)]) -> Result<Transaction>;
+
+    /// Get a new key controlled by the wallet. Used as the Taproot internal key of commit outputs,
+    /// so that the wallet can recover them (see [`Wallet::sign_key_spend`]).
+    fn new_refund_key(&self) -> Result<XOnlyPublicKey>;
+
+    /// Sign input `input_index` of `tx`: a Taproot key path spend of an output with
+    /// `internal_key` (obtained from [`Wallet::new_refund_key`]) and script tree `merkle_root`.
+    /// `prev_outs` are the outputs spent by all inputs of `tx`, in order.
+    fn sign_key_spend(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prev_outs: &[TxOut],
+        internal_key: XOnlyPublicKey,
+        merkle_root: Option<TapNodeHash>,
+    ) -> Result<Transaction>;
+
+    /// Lock wallet outputs: the wallet will not use them to fund transactions (e.g. when sending
+    /// BTC), but can still sign transactions spending them. Locks persist across restarts and are
+    /// released when the outputs are spent.
+    fn lock_unspent(&self, out_points: &[OutPoint]) -> Result<()>;
+
+    /// Add the origins (master key fingerprint and derivation path) of the wallet keys needed to
+    /// sign the inputs of `psbt` spending wallet outputs (`bip32_derivation`, or
+    /// `tap_internal_key` and `tap_key_origins` for Taproot outputs), so that external signers
+    /// (e.g. hardware wallets) can sign them. Inputs need their witness UTXOs. Does not sign.
+    fn add_key_origins(&self, psbt: &mut Psbt) -> Result<()>;
+}
+
+/// Get the pre-requisite transactions for `tx`: the transactions creating the outputs `tx` spends.
+pub fn get_prev_txs<C: ChainSource + ?Sized>(
+    chain: &C,
+    tx: &Transaction,
+) -> Result<Vec<Transaction>> {
+    let txids: BTreeSet<Txid> = tx
+        .input
+        .iter()
+        .map(|tx_in| tx_in.previous_output.txid)
+        .collect();
+    txids
+        .iter()
+        .map(|txid| chain.get_transaction(txid))
+        .collect()
+}
+
+/// Outputs of `txs` not spent by any of `txs`.
+fn unspent_outputs(txs: &BTreeMap<Txid, Transaction>) -> Vec<Unspent> {
+    let spent: BTreeSet<OutPoint> = txs
+        .values()
+        .flat_map(|tx| tx.input.iter().map(|tx_in| tx_in.previous_output))
+        .collect();
+    txs.iter()
+        .flat_map(|(&txid, tx)| {
+            tx.output
+                .iter()
+                .zip(0..)
+                .map(move |(tx_out, vout)| (OutPoint { txid, vout }, tx_out))
+        })
+        .filter(|(out_point, _)| !spent.contains(out_point))
+        .map(|(out_point, tx_out)| Unspent {
+            out_point,
+            value: tx_out.value,
+            script_pubkey: tx_out.script_pubkey.clone(),
+            confirmations: 0,
+            locked: false,
+        })
+        .collect()
+}
+
+/// Output `out_point` of `txs`, if it exists and is not spent by any of `txs`.
+fn unspent_txout(txs: &BTreeMap<Txid, Transaction>, out_point: &OutPoint) -> Option<TxOut> {
+    let spent = txs.values().any(|tx| {
+        tx.input
+            .iter()
+            .any(|tx_in| &tx_in.previous_output == out_point)
+    });
+    match spent {
+        true => None,
+        false => txs
+            .get(&out_point.txid)
+            .and_then(|tx| tx.output.get(out_point.vout as usize))
+            .cloned(),
+    }
+}
@@ -0,0 +1,261 @@
+use crate::chain::{ChainSource, Unspent, Wallet};
+use anyhow::{anyhow, ensure, Result};
+use bitcoin::{
+    bip32::{DerivationPath, Fingerprint},
+    key::Parity,
+    Address, CompressedPublicKey, OutPoint, Psbt, TapNodeHash, Transaction, TxOut, Txid, Witness,
+    XOnlyPublicKey,
+};
+use bitcoincore_rpc::{
+    json::{AddressType, SignRawTransactionInput},
+    jsonrpc::Error::Rpc,
+    Auth, Client, Error, JsonOutPoint, RpcApi,
+};
+use serde::Deserialize;
+
+/// [`ChainSource`] and [`Wallet`] backed by bitcoind (via JSON-RPC).
+pub struct RpcChainSource {
+    pub client: Client,
+}
+
+impl RpcChainSource {
+    pub fn new(rpc_url: &str, auth: Auth) -> Result<Self> {
+        let client = Client::new(rpc_url, auth)
+            .map_err(|e| anyhow!("could not create bitcoind RPC client: {}", e))?;
+        Ok(Self { client })
+    }
+
+    /// Find the HD wallet origin of `key` (obtained from [`Wallet::new_refund_key`]).
+    fn key_origin(&self, key: XOnlyPublicKey) -> Result<(Fingerprint, DerivationPath)> {
+        let network = self
+            .client
+            .get_blockchain_info()
+            .map_err(|e| anyhow!("getblockchaininfo failed: {}", e))?
+            .chain;
+        for parity in [Parity::Even, Parity::Odd] {
+            let address = Address::p2wpkh(&CompressedPublicKey(key.public_key(parity)), network);
+            let info: AddressKeyOrigin = self
+                .client
+                .call("getaddressinfo", &[address.to_string().into()])
+                .map_err(|e| anyhow!("getaddressinfo failed: {}", e))?;
+            if let AddressKeyOrigin {
+                ismine: true,
+                hdmasterfingerprint: Some(fingerprint),
+                hdkeypath: Some(path),
+            } = info
+            {
+                return Ok((fingerprint, path));
+            }
+        }
+        Err(anyhow!("key {} is not in the wallet", key))
+    }
+}
+
+/// Part of the `getaddressinfo` result: where the address key comes from.
+#[derive(Deserialize)]
+struct AddressKeyOrigin {
+    ismine: bool,
+    hdmasterfingerprint: Option<Fingerprint>,
+    hdkeypath: Option<DerivationPath>,
+}
+
+impl ChainSource for RpcChainSource {
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        match self.client.get_raw_transaction(txid, None) {
+            Ok(tx) => Ok(tx),
+            Err(Error::JsonRpc(Rpc(rpc_error))) if rpc_error.code == -5 => {
+                Err(anyhow!("transaction {} not found", txid))
+            }
+            Err(e) => Err(anyhow!("getrawtransaction {} failed: {}", txid, e)),
+        }
+    }
+
+    fn get_txout(&self, out_point: &OutPoint) -> Result<Option<TxOut>> {
+        let tx_out = self
+            .client
+            .get_tx_out(&out_point.txid, out_point.vout, Some(true))
+            .map_err(|e| anyhow!("gettxout {} failed: {}", out_point, e))?;
+        Ok(tx_out.map(|tx_out| TxOut {
+            value: tx_out.value,
+            script_pubkey: tx_out.script_pub_key.hex.into(),
+        }))
+    }
+
+    /// `listunspent` doesn't report locked outputs: they are looked up separately.
+    fn list_unspent(&self) -> Result<Vec<Unspent>> {
+        let entries = self
+            .client
+            .list_unspent(Some(0), None, None, None, None) // include outputs with 0 confirmations
+            .map_err(|e| anyhow!("listunspent failed: {}", e))?;
+        let mut unspent: Vec<Unspent> = entries
+            .into_iter()
+            .filter(|entry| entry.solvable)
+            .map(|entry| Unspent {
+                out_point: OutPoint::new(entry.txid, entry.vout),
+                value: entry.amount,
+                script_pubkey: entry.script_pub_key,
+                confirmations: entry.confirmations,
+                locked: false,
+            })
+            .collect();
+
+        let locked: Vec<JsonOutPoint> = self
+            .client
+            .call("listlockunspent", &[])
+            .map_err(|e| anyhow!("listlockunspent failed: {}", e))?;
+        for JsonOutPoint { txid, vout } in locked {
+            let Some(tx_out) = self
+                .client
+                .get_tx_out(&txid, vout, Some(true))
+                .map_err(|e| anyhow!("gettxout {}:{} failed: {}", txid, vout, e))?
+            else {
+                continue;
+            };
+            unspent.push(Unspent {
+                out_point: OutPoint::new(txid, vout),
+                value: tx_out.value,
+                script_pubkey: tx_out.script_pub_key.hex.into(),
+                confirmations: tx_out.confirmations,
+                locked: true,
+            });
+        }
+        Ok(unspent)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.client
+            .send_raw_transaction(tx)
+            .map_err(|e| anyhow!("sendrawtransaction failed: {}", e))
+    }
+}
+
+impl Wallet for RpcChainSource {
+    fn new_change_address(&self) -> Result<Address> {
+        let address = self
+            .client
+            .get_raw_change_address(None)
+            .map_err(|e| anyhow!("getrawchangeaddress failed: {}", e))?;
+        Ok(address.assume_checked())
+    }
+
+    fn sign_tx(&self, tx: &Transaction, prev_outs: &[(OutPoint, TxOut)]) -> Result<Transaction> {
+        let utxos: Vec<SignRawTransactionInput> = prev_outs
+            .iter()
+            .map(|(out_point, tx_out)| SignRawTransactionInput {
+                txid: out_point.txid,
+                vout: out_point.vout,
+                script_pub_key: tx_out.script_pubkey.clone(),
+                redeem_script: None,
+                amount: Some(tx_out.value),
+            })
+            .collect();
+        let result = self
+            .client
+            .sign_raw_transaction_with_wallet(tx, Some(&utxos), None)
+            .map_err(|e| anyhow!("signrawtransactionwithwallet failed: {}", e))?;
+        ensure!(
+            result.complete,
+            "could not sign transaction {}: {:?}",
+            tx.compute_txid(),
+            result.errors.unwrap_or_default()
+        );
+        Ok(result.transaction()?)
+    }
+
+    /// The key is the public key of a new P2WPKH wallet address: bitcoind reports public keys of
+    /// such addresses, and signs with their keys when asked (via PSBT key origins).
+    fn new_refund_key(&self) -> Result<XOnlyPublicKey> {
+        let address = self
+            .client
+            .get_new_address(None, Some(AddressType::Bech32))
+            .map_err(|e| anyhow!("getnewaddress failed: {}", e))?
+            .assume_checked();
+        let info = self
+            .client
+            .get_address_info(&address)
+            .map_err(|e| anyhow!("getaddressinfo failed: {}", e))?;
+        let public_key = info
+            .pubkey
+            .ok_or(anyhow!("no public key for address {}", address))?;
+        Ok(public_key.inner.x_only_public_key().0)
+    }
+
+    fn sign_key_spend(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prev_outs: &[TxOut],
+        internal_key: XOnlyPublicKey,
+        merkle_root: Option<TapNodeHash>,
+    ) -> Result<Transaction> {
+        ensure!(
+            prev_outs.len() == tx.input.len(),
+            "need exactly one prev output per tx input"
+        );
+        let key_origin = self.key_origin(internal_key)?;
+
+        let mut unsigned_tx = tx.clone();
+        for tx_in in unsigned_tx.input.iter_mut() {
+            tx_in.witness = Witness::new();
+        }
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)?;
+        for (input, prev_out) in psbt.inputs.iter_mut().zip(prev_outs) {
+            input.witness_utxo = Some(prev_out.clone());
+        }
+        let input = &mut psbt.inputs[input_index];
+        input.tap_internal_key = Some(internal_key);
+        input.tap_merkle_root = merkle_root;
+        input
+            .tap_key_origins
+            .insert(internal_key, (vec![], key_origin));
+
+        let result = self
+            .client
+            .wallet_process_psbt(&psbt.to_string(), Some(true), None, None)
+            .map_err(|e| anyhow!("walletprocesspsbt failed: {}", e))?;
+        let psbt: Psbt = result.psbt.parse()?;
+        let signature = psbt.inputs[input_index].tap_key_sig.ok_or(anyhow!(
+            "could not sign input {} of transaction {}",
+            input_index,
+            tx.compute_txid()
+        ))?;
+
+        let mut tx = tx.clone();
+        tx.input[input_index].witness = Witness::from_slice(&[signature.to_vec()]);
+        Ok(tx)
+    }
+
+    /// Locks are persistent: bitcoind keeps them across restarts (needs Bitcoin Core 23.0 or
+    /// later).
+    fn lock_unspent(&self, out_points: &[OutPoint]) -> Result<()> {
+        if out_points.is_empty() {
+            return Ok(());
+        }
+        let outputs: Vec<JsonOutPoint> = out_points.iter().map(|&o| o.into()).collect();
+        // unlock: false, persistent: true
+        let locked: bool = self
+            .client
+            .call(
+                "lockunspent",
+                &[false.into(), serde_json::to_value(outputs)?, true.into()],
+            )
+            .map_err(|e| {
+                anyhow!(
+                    "lockunspent failed (persistent locks need Bitcoin Core 23.0 or later): {}",
+                    e
+                )
+            })?;
+        ensure!(locked, "could not lock outputs");
+        Ok(())
+    }
+
+    fn add_key_origins(&self, psbt: &mut Psbt) -> Result<()> {
+        // sign: false, bip32derivs: true
+        let result = self
+            .client
+            .wallet_process_psbt(&psbt.to_string(), Some(false), None, Some(true))
+            .map_err(|e| anyhow!("walletprocesspsbt failed: {}", e))?;
+        *psbt = result.psbt.parse()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+use crate::{
+    cache::{cache_dir, KeyCache},
+    cli,
+};
+use anyhow::Result;
+
+pub fn list(json: bool) -> Result<()> {
+    let dir = cache_dir()?;
+    eprintln!("cache dir: {}", dir.display());
+    let entries = KeyCache::new(&dir).entries()?;
+    cli::print_output(&entries, json)
+}
+
+pub fn clear() -> Result<()> {
+    let entries = KeyCache::open()?.clear()?;
+    eprintln!(
+        "removed {} cache entries ({} bytes)",
+        entries.len(),
+        entries.iter().map(|entry| entry.size).sum::<u64>()
+    );
+    Ok(())
+}
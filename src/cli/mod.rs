@@ -1,13 +1,20 @@
 pub mod app;
+pub mod cache;
 pub mod server;
 pub mod spell;
 pub mod tx;
 pub mod wallet;
 
-use clap::{Args, CommandFactory, Parser, Subcommand};
+use crate::chain::{
+    dir::DirChainSource, mock::MockChain, rpc::RpcChainSource, ChainSource, Wallet,
+};
+use anyhow::bail;
+use bitcoincore_rpc::Auth;
+use charms_data::App;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::{generate, Shell};
 use serde::Serialize;
-use std::{io, net::IpAddr, path::PathBuf};
+use std::{io, net::IpAddr, num::NonZeroUsize, path::PathBuf};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -39,6 +46,127 @@ pub struct ServerConfig {
     /// the format is `__cookie__:password`.
     #[arg(long, env)]
     rpc_password: String,
+
+    /// Accept mock (dev) proofs instead of Groth16 proofs (e.g. on regtest).
+    #[arg(long)]
+    mock: bool,
+
+    /// Directory to keep the charms index in. Enables indexing: blocks are scanned for spells,
+    /// and the outputs carrying charms are tracked (see `/index`, `/utxos/{txid:vout}`,
+    /// `/apps/{app}`, `/apps/{app}/holders` and `/addresses/{address}/charms`).
+    /// Also enables `/events`: notifications about spells and charms (Server-Sent Events).
+    #[arg(long)]
+    index_dir: Option<PathBuf>,
+
+    /// Height of the block to start indexing from: charms created in earlier blocks are not
+    /// indexed.
+    #[arg(long, default_value = "0")]
+    index_start_height: u32,
+
+    /// Directory to keep proving jobs in. Enables the proving service: `POST /spells/prove`
+    /// queues a job to prove a spell, `GET /jobs/{id}` returns its status and result.
+    /// Jobs interrupted by a restart are run again.
+    #[arg(long)]
+    jobs_dir: Option<PathBuf>,
+
+    /// Maximum number of proving jobs run concurrently.
+    #[arg(long, default_value = "1")]
+    prove_jobs: usize,
+
+    /// How long finished proving jobs (and their results) are kept, in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "86400")]
+    job_retention: u64,
+
+    /// Paths to app binaries (RISC-V ELF files) that proving jobs can use by VK (`app_vks`),
+    /// without uploading them.
+    #[arg(long, value_delimiter = ',')]
+    app_bins: Vec<PathBuf>,
+
+    /// Accept app binaries uploaded with proving jobs (`app_bins`). Each one is set up (which is
+    /// slow) and its keys are cached on disk. Only enable for trusted clients: otherwise, jobs can
+    /// only use the binaries passed with `--app-bins`.
+    #[arg(long)]
+    allow_app_uploads: bool,
+
+    #[command(flatten)]
+    prover: ProverParams,
+}
+
+#[derive(Args)]
+pub struct ProverParams {
+    /// Maximum number of app proofs generated concurrently. Each one can take several GB of
+    /// memory. Set via CHARMS_PROVER_WORKERS env var.
+    #[arg(long, env = "CHARMS_PROVER_WORKERS", default_value = "1")]
+    prover_workers: NonZeroUsize,
+}
+
+impl ProverParams {
+    /// Apply the parameters to app proving.
+    pub(crate) fn apply(&self) {
+        crate::app::set_prover_workers(self.prover_workers);
+    }
+}
+
+#[derive(Args)]
+pub struct ChainParams {
+    /// Directory with hex-encoded transactions (`*.hex` files) to use instead of bitcoind.
+    #[arg(long)]
+    chain_dir: Option<PathBuf>,
+
+    /// Mock chain state file (JSON) to use instead of bitcoind (and its wallet).
+    /// Created if it does not exist.
+    #[arg(long, conflicts_with = "chain_dir")]
+    mock_chain: Option<PathBuf>,
+
+    /// bitcoind RPC URL (including the wallet path, e.g. `/wallet/testwallet`, if more than one
+    /// wallet is loaded). Set via RPC_URL env var.
+    #[arg(long, env, default_value = "http://127.0.0.1:48332")]
+    rpc_url: String,
+
+    /// bitcoind RPC user. Recommended to set via RPC_USER env var.
+    #[arg(long, env, default_value = "__cookie__")]
+    rpc_user: String,
+
+    /// bitcoind RPC password. Recommended to set via RPC_PASSWORD env var.
+    #[arg(long, env)]
+    rpc_password: Option<String>,
+
+    /// Path to the .cookie file in the bitcoind data directory. Used instead of `rpc-user` and
+    /// `rpc-password` if provided. Set via RPC_COOKIE_FILE env var.
+    #[arg(long, env)]
+    rpc_cookie_file: Option<PathBuf>,
+}
+
+impl ChainParams {
+    /// bitcoind RPC client.
+    pub(crate) fn rpc(&self) -> anyhow::Result<RpcChainSource> {
+        let auth = match (&self.rpc_cookie_file, &self.rpc_password) {
+            (Some(cookie_file), _) => Auth::CookieFile(cookie_file.clone()),
+            (None, Some(rpc_password)) => {
+                Auth::UserPass(self.rpc_user.clone(), rpc_password.clone())
+            }
+            (None, None) => bail!("either --rpc-cookie-file or --rpc-password must be provided"),
+        };
+        RpcChainSource::new(&self.rpc_url, auth)
+    }
+
+    /// Chain source: the `chain-dir` directory or the `mock-chain` if provided, bitcoind
+    /// otherwise.
+    pub(crate) fn chain_source(&self) -> anyhow::Result<Box<dyn ChainSource>> {
+        match (&self.chain_dir, &self.mock_chain) {
+            (Some(dir), _) => Ok(Box::new(DirChainSource::new(dir.clone()))),
+            (None, Some(path)) => Ok(Box::new(MockChain::open(path)?)),
+            (None, None) => Ok(Box::new(self.rpc()?)),
+        }
+    }
+
+    /// Wallet: the `mock-chain` if provided, bitcoind otherwise.
+    pub(crate) fn wallet(&self) -> anyhow::Result<Box<dyn Wallet>> {
+        match &self.mock_chain {
+            Some(path) => Ok(Box::new(MockChain::open(path)?)),
+            None => Ok(Box::new(self.rpc()?)),
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -70,6 +198,14 @@ pub enum Commands {
         command: WalletCommands,
     },
 
+    /// Inspect and clear the cache of proving and verifying keys.
+    /// The cache directory is `$CHARMS_CACHE_DIR`, or `charms` in `$XDG_CACHE_HOME` (or in
+    /// `~/.cache`).
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
     /// Generate shell completion scripts
     Completions {
         /// Shell to generate completions for
@@ -88,9 +224,12 @@ pub struct SpellProveParams {
     #[arg(long)]
     tx: Option<String>,
 
-    /// Pre-requisite transactions (hex-encoded) separated by commas (`,`).
+    /// Pre-requisite transactions separated by commas (`,`): hex-encoded transactions, files with
+    /// hex-encoded transactions or directories of `*.hex` files.
     /// These are the transactions that create the UTXOs that the `tx` (and the spell) spends.
     /// If the spell has any reference UTXOs, the transactions creating them must also be included.
+    /// The transaction creating the funding UTXO should also be included (required with `--psbt`
+    /// or without `--funding-utxo-value`).
     #[arg(long, value_delimiter = ',')]
     prev_txs: Vec<String>,
 
@@ -98,22 +237,46 @@ pub struct SpellProveParams {
     #[arg(long, value_delimiter = ',')]
     app_bins: Vec<PathBuf>,
 
-    /// UTXO ID of the funding transaction output (txid:vout).
-    /// This UTXO will be spent to pay the fees (at the `fee-rate` per vB) for the commit and spell
-    /// transactions. The rest of the value will be returned to the `change-address`.
-    #[arg(long)]
-    funding_utxo_id: String,
-    /// Value of the funding UTXO in sats.
-    #[arg(long)]
-    funding_utxo_value: u64,
+    /// UTXO IDs of the funding transaction outputs (txid:vout) separated by commas (`,`).
+    /// UTXOs selected from these will be spent to pay the fees (at the `fee-rate` per vB) for the
+    /// commit and spell transactions. The rest of the value will be returned to the
+    /// `change-address`.
+    #[arg(long, value_delimiter = ',', required = true)]
+    funding_utxo_id: Vec<String>,
+    /// Values of the funding UTXOs in sats separated by commas (`,`), in the order of
+    /// `funding-utxo-id`. Only needed if the funding txs are not in `--prev-txs`: the funding UTXOs
+    /// are then assumed to be Taproot outputs (for fee computation).
+    #[arg(long, value_delimiter = ',')]
+    funding_utxo_value: Vec<u64>,
 
     /// Address to send the change to.
     #[arg(long)]
     change_address: String,
 
+    /// Refund key (hex-encoded x-only public key) controlled by the user: Taproot internal key of
+    /// the commit tx output. Allows to recover the funds locked in the commit tx output if the
+    /// spell tx never gets confirmed (see `charms tx recover-commit`).
+    /// If not provided, the commit tx output is spendable only by the spell tx.
+    #[arg(long)]
+    refund_key: Option<String>,
+
     /// Fee rate in sats/vB.
     #[arg(long, default_value = "2.0")]
     fee_rate: f64,
+
+    /// Produce a mock (dev) proof instead of a Groth16 proof: fast, but the spell will only be
+    /// accepted where mock proofs are explicitly enabled (e.g. on regtest). The spell is still
+    /// checked first, with app contracts run in SP1's executor.
+    #[arg(long)]
+    mock: bool,
+
+    /// Output unsigned PSBTs (base64-encoded) instead of transactions, to sign them with external
+    /// signers (e.g. hardware wallets). Use `charms tx finalize` to get signed transactions.
+    #[arg(long)]
+    psbt: bool,
+
+    #[command(flatten)]
+    prover: ProverParams,
 }
 
 #[derive(Args)]
@@ -121,9 +284,28 @@ pub struct SpellCheckParams {
     /// Path to spell source file (YAML/JSON).
     #[arg(long, default_value = "/dev/stdin")]
     spell: PathBuf,
+
+    /// Bitcoin transaction (hex-encoded). If not provided, will be created from the spell.
+    #[arg(long)]
+    tx: Option<String>,
+
+    /// Pre-requisite transactions separated by commas (`,`): the transactions creating the UTXOs
+    /// the `tx` (and the spell) spends or references. Each one is a hex-encoded transaction, a
+    /// file with hex-encoded transactions or a directory of `*.hex` files.
+    /// If provided, the check runs offline: the chain (e.g. bitcoind) is not used.
+    #[arg(long, value_delimiter = ',')]
+    prev_txs: Vec<String>,
+
     /// Path to the apps' RISC-V binaries.
     #[arg(long, value_delimiter = ',')]
     app_bins: Vec<PathBuf>,
+
+    /// Accept mock (dev) proofs instead of Groth16 proofs (e.g. on regtest).
+    #[arg(long)]
+    mock: bool,
+
+    #[command(flatten)]
+    chain: ChainParams,
 }
 
 #[derive(Subcommand)]
@@ -145,6 +327,35 @@ pub enum TxCommands {
         /// Output in JSON format (default is YAML).
         #[arg(long)]
         json: bool,
+        /// Accept mock (dev) proofs instead of Groth16 proofs (e.g. on regtest).
+        #[arg(long)]
+        mock: bool,
+    },
+
+    /// Combine signed PSBTs (e.g. produced by `charms spell prove --psbt` and signed by external
+    /// signers) and finalize them. Prints the hex-encoded transactions ready to be broadcast.
+    Finalize {
+        /// Signed PSBTs (base64-encoded) separated by commas (`,`). PSBTs of the same transaction
+        /// are combined.
+        #[arg(long, value_delimiter = ',', required = true)]
+        psbts: Vec<String>,
+    },
+
+    /// Recover the funds locked in the commit tx output of a spell tx that never got confirmed
+    /// (e.g. because of a too low fee rate). Sends them to a new wallet address. The commit tx must
+    /// have been created with a refund key from the wallet (e.g. by `charms wallet cast`).
+    /// Prints the hex-encoded signed transaction.
+    RecoverCommit {
+        /// Hex-encoded spell transaction (spending the commit tx output).
+        #[arg(long)]
+        spell_tx: String,
+
+        /// Fee rate in sats/vB.
+        #[arg(long, default_value = "2.0")]
+        fee_rate: f64,
+
+        #[command(flatten)]
+        chain: ChainParams,
     },
 }
 
@@ -176,6 +387,20 @@ pub enum AppCommands {
     },
 }
 
+#[derive(Subcommand)]
+pub enum CacheCommands {
+    /// List cached keys: the SP1 version and kind of prover (e.g. `cpu`) they were created with,
+    /// the SHA-256 hash of the program (RISC-V ELF binary) and the size of the entry.
+    List {
+        /// Output in JSON format (default is YAML).
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Remove all cached keys.
+    Clear,
+}
+
 #[derive(Subcommand)]
 pub enum WalletCommands {
     /// List outputs with charms in the user's wallet.
@@ -185,6 +410,18 @@ pub enum WalletCommands {
     /// commit transaction. Signs both the commit and spell transactions with the user's wallet.
     /// Returns the hex-encoded signed commit and spell transactions.
     Cast(#[command(flatten)] WalletCastParams),
+    /// Lock outputs that may carry charms in the user's wallet, so that the wallet does not spend
+    /// them in ordinary transactions (which would destroy the charms).
+    /// Prints the newly locked outputs.
+    Lock(#[command(flatten)] WalletLockParams),
+    /// Show balances of the user's wallet per app: token amounts and numbers of NFTs.
+    /// Token names, tickers and decimals come from their reference NFTs (see CHIP-0420).
+    Balance(#[command(flatten)] WalletBalanceParams),
+    /// Send tokens or an NFT to an address.
+    /// Selects the wallet's outputs with the app's charms, creates the spell (sending token
+    /// change back to the wallet) and casts it (see `cast`).
+    /// Returns the hex-encoded signed commit and spell transactions.
+    Send(#[command(flatten)] WalletSendParams),
 }
 
 #[derive(Args)]
@@ -192,6 +429,13 @@ pub struct WalletListParams {
     /// Output in JSON format (default is YAML)
     #[arg(long)]
     json: bool,
+
+    /// Accept mock (dev) proofs instead of Groth16 proofs (e.g. on regtest).
+    #[arg(long)]
+    mock: bool,
+
+    #[command(flatten)]
+    chain: ChainParams,
 }
 
 #[derive(Args)]
@@ -202,12 +446,117 @@ pub struct WalletCastParams {
     /// Path to the apps' RISC-V binaries.
     #[arg(long, value_delimiter = ',')]
     app_bins: Vec<PathBuf>,
-    /// Funding UTXO ID (`txid:vout`).
+    /// Funding UTXO IDs (`txid:vout`) separated by commas (`,`). UTXOs selected from these will be
+    /// spent to pay the fees. If not provided, funding UTXOs are selected from the wallet's UTXOs
+    /// without charms.
+    #[arg(long, value_delimiter = ',')]
+    funding_utxo_id: Vec<String>,
+    /// Fee rate in sats/vB.
+    #[arg(long, default_value = "2.0")]
+    fee_rate: f64,
+
+    /// Produce a mock (dev) proof instead of a Groth16 proof: fast, but the spell will only be
+    /// accepted where mock proofs are explicitly enabled (e.g. on regtest). The spell is still
+    /// checked first, with app contracts run in SP1's executor.
+    #[arg(long)]
+    mock: bool,
+
+    /// Output unsigned PSBTs (base64-encoded) instead of transactions, to sign them with external
+    /// signers (e.g. hardware wallets). Use `charms tx finalize` to get signed transactions.
+    #[arg(long)]
+    psbt: bool,
+
+    #[command(flatten)]
+    prover: ProverParams,
+
+    #[command(flatten)]
+    chain: ChainParams,
+}
+
+#[derive(Args)]
+pub struct WalletLockParams {
+    /// Output in JSON format (default is YAML)
     #[arg(long)]
-    funding_utxo_id: String,
+    json: bool,
+
+    /// Keep running: look for new outputs with charms and lock them every WATCH seconds
+    /// (30 if no value is given).
+    #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "30")]
+    watch: Option<u64>,
+
+    #[command(flatten)]
+    chain: ChainParams,
+}
+
+#[derive(Args)]
+pub struct WalletBalanceParams {
+    /// Output format.
+    #[arg(long, value_enum, default_value = "yaml")]
+    format: OutputFormat,
+
+    /// UTXO IDs (`txid:vout`) of reference NFTs (see CHIP-0420) not in the wallet, separated by
+    /// commas (`,`): token metadata is looked up there too.
+    #[arg(long, value_delimiter = ',')]
+    ref_nfts: Vec<String>,
+
+    /// Accept mock (dev) proofs instead of Groth16 proofs (e.g. on regtest).
+    #[arg(long)]
+    mock: bool,
+
+    #[command(flatten)]
+    chain: ChainParams,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Yaml,
+    Json,
+    Table,
+}
+
+#[derive(Args)]
+pub struct WalletSendParams {
+    /// App (`tag/identity/vk`) of the token or NFT to send.
+    #[arg(long, value_parser = parse_app)]
+    app: App,
+    /// Amount of tokens to send. Required for tokens (apps with tag `t`), not allowed for NFTs.
+    #[arg(long)]
+    amount: Option<u64>,
+    /// Address to send to.
+    #[arg(long)]
+    to: String,
+    /// Path to the app's RISC-V binary.
+    #[arg(long, value_delimiter = ',')]
+    app_bins: Vec<PathBuf>,
+    /// Funding UTXO IDs (`txid:vout`) separated by commas (`,`). UTXOs selected from these will be
+    /// spent to pay the fees. If not provided, funding UTXOs are selected from the wallet's UTXOs
+    /// without charms.
+    #[arg(long, value_delimiter = ',')]
+    funding_utxo_id: Vec<String>,
     /// Fee rate in sats/vB.
     #[arg(long, default_value = "2.0")]
     fee_rate: f64,
+
+    /// Accept mock (dev) proofs of the wallet's charms, and produce a mock (dev) proof for the
+    /// spell (e.g. on regtest). The spell is still checked first, with app contracts run in SP1's
+    /// executor.
+    #[arg(long)]
+    mock: bool,
+
+    /// Output unsigned PSBTs (base64-encoded) instead of transactions, to sign them with external
+    /// signers (e.g. hardware wallets). Use `charms tx finalize` to get signed transactions.
+    #[arg(long)]
+    psbt: bool,
+
+    #[command(flatten)]
+    prover: ProverParams,
+
+    #[command(flatten)]
+    chain: ChainParams,
+}
+
+fn parse_app(s: &str) -> anyhow::Result<App> {
+    Ok(serde_json::from_value(s.into())?)
 }
 
 pub async fn run() -> anyhow::Result<()> {
@@ -220,7 +569,13 @@ pub async fn run() -> anyhow::Result<()> {
             SpellCommands::Prove(params) => spell::prove(params),
         },
         Commands::Tx { command } => match command {
-            TxCommands::ShowSpell { tx, json } => tx::tx_show_spell(tx, json),
+            TxCommands::ShowSpell { tx, json, mock } => tx::tx_show_spell(tx, json, mock),
+            TxCommands::Finalize { psbts } => tx::tx_finalize(psbts),
+            TxCommands::RecoverCommit {
+                spell_tx,
+                fee_rate,
+                chain,
+            } => tx::tx_recover_commit(spell_tx, fee_rate, chain),
         },
         Commands::App { command } => match command {
             AppCommands::New { name } => app::new(&name),
@@ -231,6 +586,13 @@ pub async fn run() -> anyhow::Result<()> {
         Commands::Wallet { command } => match command {
             WalletCommands::List(params) => wallet::list(params),
             WalletCommands::Cast(params) => wallet::cast(params),
+            WalletCommands::Lock(params) => wallet::lock(params),
+            WalletCommands::Balance(params) => wallet::balance(params),
+            WalletCommands::Send(params) => wallet::send(params),
+        },
+        Commands::Cache { command } => match command {
+            CacheCommands::List { json } => cache::list(json),
+            CacheCommands::Clear => cache::clear(),
         },
         Commands::Completions { shell } => generate_completions(shell),
     }
@@ -1,18 +1,52 @@
-use crate::{cli::ServerConfig, spell::Spell, tx::norm_spell};
-use anyhow::Result;
+use crate::{
+    app,
+    chain::rpc::RpcChainSource,
+    cli,
+    cli::{
+        parse_app,
+        spell::{prove_spell, ProveSpellInputs},
+        ServerConfig,
+    },
+    events::{Event, EventFilter, Watcher},
+    index,
+    index::{AppInfo, CharmUtxo, Holder, Index, IndexStatus},
+    jobs::{Job, Jobs, ProveRequest},
+    spell::{ProofMode, Spell},
+    tx,
+    tx::norm_spell,
+};
+use anyhow::{anyhow, ensure, Result};
 use axum::{
     body::Body,
-    extract::Path,
+    extract::{DefaultBodyLimit, Path, Query},
     http::{header, HeaderValue, StatusCode},
     middleware::{self, Next},
-    response::Response,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Response,
+    },
     routing::MethodRouter,
     Json, Router,
 };
-use bitcoin::{consensus::encode::deserialize_hex, Transaction};
+use bitcoin::{
+    address::NetworkUnchecked,
+    base64::{engine::general_purpose::STANDARD as BASE64, Engine},
+    consensus::encode::deserialize_hex,
+    Address, Network, Transaction, XOnlyPublicKey,
+};
 use bitcoincore_rpc::{jsonrpc::Error::Rpc, Auth, Client, RpcApi};
+use charms_data::{App, UtxoId, B32};
+use futures_util::{stream, Stream};
 use serde::{Deserialize, Serialize};
-use std::{str::FromStr, sync::OnceLock};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    str::FromStr,
+    sync::{Arc, OnceLock, RwLock},
+    thread,
+    time::Duration,
+};
+use tokio::sync::broadcast;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 // Types
@@ -21,7 +55,56 @@ struct DecodeSpell {
     tx_hex: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct ProveResponse {
+    job_id: String,
+}
+
+/// Body of error responses to `POST /spells/prove`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ProveError {
+    error: String,
+    /// VKs (hex-encoded) in `app_vks` the server has no app binaries for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    unknown_app_vks: Vec<String>,
+}
+
+impl ProveError {
+    fn new(error: &str) -> Self {
+        Self {
+            error: error.to_string(),
+            unknown_app_vks: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EventsQuery {
+    app: Option<String>,
+    address: Option<String>,
+}
+
 static RPC: OnceLock<Client> = OnceLock::new();
+static PROOF_MODE: OnceLock<ProofMode> = OnceLock::new();
+static INDEX: OnceLock<Arc<RwLock<Index>>> = OnceLock::new();
+static NETWORK: OnceLock<Network> = OnceLock::new();
+static JOBS: OnceLock<Arc<Jobs>> = OnceLock::new();
+static EVENTS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+/// App binaries proving jobs can use by VK.
+static APP_BINARIES: OnceLock<BTreeMap<B32, Vec<u8>>> = OnceLock::new();
+static ALLOW_APP_UPLOADS: OnceLock<bool> = OnceLock::new();
+
+/// How often to check for new blocks to index (and new transactions in the mempool).
+const INDEX_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of events kept for subscribers that are slow to receive them.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Maximum size of a `POST /spells/prove` request with app binaries (`--allow-app-uploads`).
+const MAX_PROVE_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximum size of a `POST /spells/prove` request without app binaries.
+const MAX_PROVE_REQUEST_SIZE_NO_UPLOADS: usize = 4 * 1024 * 1024;
 
 pub async fn server(
     ServerConfig {
@@ -30,6 +113,15 @@ pub async fn server(
         rpc_url,
         rpc_user,
         rpc_password,
+        mock,
+        index_dir,
+        index_start_height,
+        jobs_dir,
+        prove_jobs,
+        job_retention,
+        app_bins,
+        allow_app_uploads,
+        prover,
     }: ServerConfig,
 ) -> Result<()> {
     // Initialize tracing
@@ -40,18 +132,86 @@ pub async fn server(
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let index_source = RpcChainSource::new(
+        &rpc_url,
+        Auth::UserPass(rpc_user.clone(), rpc_password.clone()),
+    )?;
     RPC.set(bitcoind_client(rpc_url, rpc_user, rpc_password))
         .expect("Should set RPC client");
+    let mode = ProofMode::new(mock);
+    PROOF_MODE.set(mode).expect("Should set proof mode");
+    prover.apply();
 
-    // Build router with CORS middleware
-    let app = Router::new()
-        .route(
-            "/spells/{txid}",
-            MethodRouter::new()
-                .get(get_spell_handler)
-                .put(put_spell_handler),
-        )
-        .layer(middleware::from_fn(cors_middleware));
+    let mut app = Router::new().route(
+        "/spells/{txid}",
+        MethodRouter::new()
+            .get(get_spell_handler)
+            .put(put_spell_handler),
+    );
+
+    if let Some(index_dir) = index_dir {
+        let network = index_source
+            .client
+            .get_blockchain_info()
+            .map_err(|e| anyhow!("getblockchaininfo failed: {}", e))?
+            .chain;
+        NETWORK.set(network).expect("Should set network");
+        let index = Index::open(&index_dir, mode.spell_vk(), index_start_height)?;
+        let watcher = Watcher::new(&index);
+        let index = INDEX.get_or_init(|| Arc::new(RwLock::new(index))).clone();
+        let events = EVENTS
+            .get_or_init(|| broadcast::channel(EVENTS_CAPACITY).0)
+            .clone();
+        thread::spawn(move || index_blocks(&index, &index_source, watcher, &events));
+        app = app
+            .route("/index", MethodRouter::new().get(get_index_handler))
+            .route(
+                "/utxos/{utxo_id}",
+                MethodRouter::new().get(get_utxo_handler),
+            )
+            .route(
+                "/apps/{tag}/{identity}/{vk}",
+                MethodRouter::new().get(get_app_handler),
+            )
+            .route(
+                "/apps/{tag}/{identity}/{vk}/holders",
+                MethodRouter::new().get(get_holders_handler),
+            )
+            .route(
+                "/addresses/{address}/charms",
+                MethodRouter::new().get(get_address_charms_handler),
+            )
+            .route("/events", MethodRouter::new().get(events_handler));
+    }
+
+    if let Some(jobs_dir) = jobs_dir {
+        ensure!(prove_jobs > 0, "--prove-jobs must be positive");
+        let app_binaries = cli::app::binaries_by_vk(&app::Prover::new(), app_bins)?;
+        APP_BINARIES
+            .set(app_binaries)
+            .expect("Should set app binaries");
+        ALLOW_APP_UPLOADS
+            .set(allow_app_uploads)
+            .expect("Should set app uploads");
+        let jobs = Jobs::open(&jobs_dir, Duration::from_secs(job_retention))?;
+        let jobs = JOBS.get_or_init(|| Arc::new(jobs)).clone();
+        jobs.run(prove_jobs, prove_job);
+        let max_request_size = match allow_app_uploads {
+            true => MAX_PROVE_REQUEST_SIZE,
+            false => MAX_PROVE_REQUEST_SIZE_NO_UPLOADS,
+        };
+        app = app
+            .route(
+                "/spells/prove",
+                MethodRouter::new()
+                    .post(prove_spell_handler)
+                    .layer(DefaultBodyLimit::max(max_request_size)),
+            )
+            .route("/jobs/{id}", MethodRouter::new().get(get_job_handler));
+    }
+
+    // Add CORS middleware
+    let app = app.layer(middleware::from_fn(cors_middleware));
 
     // Run server
     let addr = format!("{}:{}", ip_addr, port);
@@ -72,7 +232,7 @@ async fn cors_middleware(request: axum::http::Request<Body>, next: Next) -> Resp
     );
     headers.insert(
         header::ACCESS_CONTROL_ALLOW_METHODS,
-        HeaderValue::from_static("GET, PUT, OPTIONS"),
+        HeaderValue::from_static("GET, PUT, POST, OPTIONS"),
     );
     headers.insert(
         header::ACCESS_CONTROL_ALLOW_HEADERS,
@@ -94,6 +254,278 @@ async fn put_spell_handler(
     decode_spell(&txid, &payload).map(Json)
 }
 
+async fn get_index_handler() -> Json<IndexStatus> {
+    Json(index().read().unwrap().status())
+}
+
+async fn get_utxo_handler(Path(utxo_id): Path<String>) -> Result<Json<CharmUtxo>, StatusCode> {
+    let utxo_id = UtxoId::from_str(&utxo_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let index = index().read().unwrap();
+    index
+        .utxo(&utxo_id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_app_handler(
+    Path(app): Path<(String, String, String)>,
+) -> Result<Json<AppInfo>, StatusCode> {
+    let app = app_from_path(app)?;
+    let index = index().read().unwrap();
+    index
+        .app_info(&app)
+        .map_err(internal_error)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_holders_handler(
+    Path(app): Path<(String, String, String)>,
+) -> Result<Json<Vec<Holder>>, StatusCode> {
+    let app = app_from_path(app)?;
+    let index = index().read().unwrap();
+    index
+        .holders(&app, network())
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn get_address_charms_handler(
+    Path(address): Path<String>,
+) -> Result<Json<Vec<CharmUtxo>>, StatusCode> {
+    let address = Address::<NetworkUnchecked>::from_str(&address)
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        .require_network(network())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let index = index().read().unwrap();
+    let utxos = index.script_utxos(&address.script_pubkey());
+    Ok(Json(utxos.into_iter().cloned().collect()))
+}
+
+async fn prove_spell_handler(
+    Json(request): Json<ProveRequest>,
+) -> Result<(StatusCode, Json<ProveResponse>), (StatusCode, Json<ProveError>)> {
+    let allow_app_uploads = *ALLOW_APP_UPLOADS
+        .get()
+        .expect("app uploads should be set by now");
+    check_prove_request(&request, app_binaries(), allow_app_uploads)
+        .map_err(|(status, error)| (status, Json(error)))?;
+    let submitted = jobs().submit(&request).map_err(|e| {
+        (
+            internal_error(e),
+            Json(ProveError::new("could not queue the job")),
+        )
+    })?;
+    match submitted {
+        Some(job_id) => Ok((StatusCode::ACCEPTED, Json(ProveResponse { job_id }))),
+        None => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ProveError::new("too many queued jobs, try again later")),
+        )),
+    }
+}
+
+/// Check that the server can run a job for `request`: uploaded app binaries are allowed, and
+/// there are binaries for all its `app_vks`.
+fn check_prove_request(
+    request: &ProveRequest,
+    app_binaries: &BTreeMap<B32, Vec<u8>>,
+    allow_app_uploads: bool,
+) -> Result<(), (StatusCode, ProveError)> {
+    if !request.app_bins.is_empty() && !allow_app_uploads {
+        return Err((
+            StatusCode::FORBIDDEN,
+            ProveError::new(
+                "this server does not accept app binaries: use app_vks to refer to its binaries",
+            ),
+        ));
+    }
+    let unknown_app_vks: Vec<String> = request
+        .app_vks
+        .iter()
+        .filter(|vk| B32::from_str(vk).map_or(true, |vk| !app_binaries.contains_key(&vk)))
+        .cloned()
+        .collect();
+    if !unknown_app_vks.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            ProveError {
+                error: "no app binaries for some app_vks".to_string(),
+                unknown_app_vks,
+            },
+        ));
+    }
+    Ok(())
+}
+
+async fn get_job_handler(Path(id): Path<String>) -> Result<Json<Job>, StatusCode> {
+    jobs().get(&id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Stream of events (Server-Sent Events) matching the filters in the query: `app` (e.g.
+/// `t/{identity}/{vk}`) and `address`.
+async fn events_handler(
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, StatusCode> {
+    let app = query
+        .app
+        .as_deref()
+        .map(parse_app)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let script_pubkey = match query.address {
+        Some(address) => Some(
+            Address::<NetworkUnchecked>::from_str(&address)
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+                .require_network(network())
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+                .script_pubkey(),
+        ),
+        None => None,
+    };
+    let filter = EventFilter { app, script_pubkey };
+
+    let receiver = EVENTS
+        .get()
+        .expect("events should be initialized by now")
+        .subscribe();
+    let events = stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if filter.matches(&event) => {
+                        let sse_event = sse::Event::default()
+                            .event(event.name())
+                            .json_data(&event)
+                            .expect("event should serialize to JSON");
+                        return Some((Ok(sse_event), receiver));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("event subscriber lagged behind: {} events dropped", n)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// App from the path segments of `/apps/{tag}/{identity}/{vk}`.
+fn app_from_path((tag, identity, vk): (String, String, String)) -> Result<App, StatusCode> {
+    parse_app(&format!("{}/{}/{}", tag, identity, vk)).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+fn internal_error(e: anyhow::Error) -> StatusCode {
+    tracing::error!("{}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+fn index() -> &'static RwLock<Index> {
+    INDEX.get().expect("index should be initialized by now")
+}
+
+fn network() -> Network {
+    *NETWORK.get().expect("network should be set by now")
+}
+
+fn jobs() -> &'static Jobs {
+    JOBS.get().expect("jobs should be initialized by now")
+}
+
+fn app_binaries() -> &'static BTreeMap<B32, Vec<u8>> {
+    APP_BINARIES
+        .get()
+        .expect("app binaries should be set by now")
+}
+
+/// Run a proving job: prove the spell the way `charms spell prove` does.
+fn prove_job(request: &ProveRequest) -> Result<Vec<String>> {
+    let app_prover = app::Prover::new();
+    let mut binaries = BTreeMap::new();
+    for app_bin in &request.app_bins {
+        let binary = BASE64
+            .decode(app_bin)
+            .map_err(|e| anyhow!("invalid app binary: {}", e))?;
+        binaries.insert(B32(app_prover.vk(&binary)), binary);
+    }
+    for vk in &request.app_vks {
+        let vk = B32::from_str(vk).map_err(|e| anyhow!("invalid app VK {}: {}", vk, e))?;
+        let binary = app_binaries()
+            .get(&vk)
+            .ok_or(anyhow!("app binary {} is not registered", vk))?;
+        binaries.insert(vk, binary.clone());
+    }
+
+    let tx = match &request.tx {
+        Some(tx) => deserialize_hex(tx).map_err(|e| anyhow!("invalid tx: {}", e))?,
+        None => tx::from_spell(&request.spell),
+    };
+    let prev_txs = request
+        .prev_txs
+        .iter()
+        .map(|tx| deserialize_hex(tx).map_err(|e| anyhow!("invalid prev tx: {}", e)))
+        .collect::<Result<_>>()?;
+    let funding_utxos = request
+        .funding_utxos
+        .iter()
+        .map(|utxo_id| cli::tx::parse_outpoint(utxo_id))
+        .collect::<Result<_>>()?;
+    let refund_key = request
+        .refund_key
+        .as_deref()
+        .map(XOnlyPublicKey::from_str)
+        .transpose()
+        .map_err(|e| anyhow!("invalid refund key: {}", e))?;
+
+    prove_spell(ProveSpellInputs {
+        spell: request.spell.clone(),
+        tx,
+        prev_txs,
+        binaries,
+        funding_utxos,
+        funding_utxo_values: request.funding_utxo_values.clone(),
+        change_address: request.change_address.clone(),
+        refund_key,
+        fee_rate: request.fee_rate,
+        mode: *PROOF_MODE.get().expect("proof mode should be set by now"),
+        psbt: request.psbt,
+    })
+}
+
+/// Keep indexing new blocks and publishing events for them and for new mempool transactions.
+fn index_blocks(
+    index: &RwLock<Index>,
+    source: &RpcChainSource,
+    mut watcher: Watcher,
+    events: &broadcast::Sender<Event>,
+) {
+    loop {
+        match index::sync(index, source) {
+            Ok(0) => {}
+            Ok(n) => tracing::info!(
+                "indexed {} blocks, height: {:?}",
+                n,
+                index.read().unwrap().height()
+            ),
+            Err(e) => tracing::error!("indexing failed: {}", e),
+        }
+        match watcher.poll(index, source) {
+            Ok(new_events) => {
+                for event in new_events {
+                    // no subscribers is fine
+                    let _ = events.send(event);
+                }
+            }
+            Err(e) => tracing::error!("watching the mempool failed: {}", e),
+        }
+        thread::sleep(INDEX_POLL_INTERVAL);
+    }
+}
+
 fn bitcoind_client(rpc_url: String, rpc_user: String, rpc_password: String) -> Client {
     Client::new(
         &rpc_url,
@@ -130,8 +562,46 @@ fn decode_spell(txid: &str, request: &DecodeSpell) -> Result<Spell, StatusCode>
 }
 
 fn extract_spell(tx: &Transaction) -> Result<Spell, StatusCode> {
-    match norm_spell(&tx) {
+    let mode = *PROOF_MODE.get().expect("proof mode should be set by now");
+    match norm_spell(&tx, mode) {
         None => Err(StatusCode::NO_CONTENT),
         Some(spell) => Ok(Spell::denormalized(&spell)),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_prove_requests() {
+        let request = |app_bins: &[&str], app_vks: &[String]| -> ProveRequest {
+            serde_json::from_value(serde_json::json!({
+                "spell": { "version": 2, "apps": {}, "ins": [], "outs": [] },
+                "app_bins": app_bins,
+                "app_vks": app_vks,
+                "funding_utxos": [],
+                "change_address": "",
+            }))
+            .unwrap()
+        };
+        let app_binaries = BTreeMap::from([(B32([1; 32]), vec![])]);
+        let vk = |n: u8| B32([n; 32]).to_string();
+
+        assert!(check_prove_request(&request(&[], &[vk(1)]), &app_binaries, false).is_ok());
+        let (status, error) =
+            check_prove_request(&request(&["AA=="], &[]), &app_binaries, false).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(error.unknown_app_vks.is_empty());
+        assert!(check_prove_request(&request(&["AA=="], &[]), &app_binaries, true).is_ok());
+
+        let (status, error) = check_prove_request(
+            &request(&[], &[vk(1), vk(2), "nope".to_string()]),
+            &app_binaries,
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.unknown_app_vks, vec![vk(2), "nope".to_string()]);
+    }
+}
@@ -1,16 +1,28 @@
 use crate::{
-    app, cli,
+    app,
+    chain::dir::DirChainSource,
+    cli,
     cli::{SpellCheckParams, SpellProveParams},
-    spell,
-    spell::Spell,
+    psbt, spell,
+    spell::{ProofMode, Spell},
     tx,
     tx::txs_by_txid,
-    utils, SPELL_VK,
+    utils,
 };
-use anyhow::{ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use bitcoin::{
     consensus::encode::{deserialize_hex, serialize_hex},
-    Transaction,
+    hashes::Hash,
+    Amount, OutPoint, ScriptBuf, Transaction, TxOut, Txid, WitnessProgram, WitnessVersion,
+    XOnlyPublicKey,
+};
+use charms_client::tx::SpellError;
+use charms_data::{TxId, B32};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    str::FromStr,
 };
 
 pub fn prove(
@@ -22,15 +34,28 @@ pub fn prove(
         funding_utxo_id,
         funding_utxo_value,
         change_address,
+        refund_key,
         fee_rate,
+        mock,
+        psbt,
+        prover,
     }: SpellProveParams,
 ) -> Result<()> {
     utils::logger::setup_logger();
+    prover.apply();
 
-    // Parse funding UTXO early: to fail fast
-    let funding_utxo = cli::tx::parse_outpoint(&funding_utxo_id)?;
-
-    ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
+    // Parse funding UTXOs early: to fail fast
+    let funding_out_points = funding_utxo_id
+        .iter()
+        .map(|utxo_id| cli::tx::parse_outpoint(utxo_id))
+        .collect::<Result<Vec<_>>>()?;
+    let refund_key = refund_key
+        .map(|key| XOnlyPublicKey::from_str(&key))
+        .transpose()
+        .map_err(|e| anyhow!("invalid refund key: {}", e))?;
+    if refund_key.is_none() {
+        eprintln!("warning: no --refund-key: funds in the commit tx output can't be recovered if the spell tx is not confirmed");
+    }
 
     let spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
 
@@ -38,40 +63,239 @@ pub fn prove(
         Some(tx) => deserialize_hex::<Transaction>(&tx)?,
         None => tx::from_spell(&spell),
     };
-    let prev_txs = prev_txs
-        .into_iter()
-        .map(|tx| Ok(deserialize_hex::<Transaction>(&tx)?))
-        .collect::<Result<_>>()?;
-    let prev_txs = txs_by_txid(prev_txs)?;
+    let prev_txs = read_prev_txs(&prev_txs)?;
+
+    let app_prover = app::Prover::new();
+    let binaries = cli::app::binaries_by_vk(&app_prover, app_bins)?;
+
+    let output = prove_spell(ProveSpellInputs {
+        spell,
+        tx,
+        prev_txs,
+        binaries,
+        funding_utxos: funding_out_points,
+        funding_utxo_values: funding_utxo_value,
+        change_address,
+        refund_key,
+        fee_rate,
+        mode: ProofMode::new(mock),
+        psbt,
+    })?;
+
+    // Print JSON array of hex-encoded transactions or base64-encoded PSBTs
+    println!("{}", serde_json::to_string(&output)?);
+
+    Ok(())
+}
+
+/// Parsed `charms spell prove` arguments (also accepted by `charms server` proving jobs).
+pub(crate) struct ProveSpellInputs {
+    pub spell: Spell,
+    pub tx: Transaction,
+    pub prev_txs: Vec<Transaction>,
+    pub binaries: BTreeMap<B32, Vec<u8>>,
+    pub funding_utxos: Vec<OutPoint>,
+    /// Values of `funding_utxos` in sats: empty or one for each funding UTXO.
+    pub funding_utxo_values: Vec<u64>,
+    pub change_address: String,
+    pub refund_key: Option<XOnlyPublicKey>,
+    pub fee_rate: f64,
+    pub mode: ProofMode,
+    pub psbt: bool,
+}
+
+/// Prove the spell and make the commit and spell transactions. Returns them hex-encoded or, with
+/// `psbt`, as unsigned base64-encoded PSBTs.
+pub(crate) fn prove_spell(
+    ProveSpellInputs {
+        spell,
+        tx,
+        prev_txs,
+        binaries,
+        funding_utxos: funding_out_points,
+        funding_utxo_values,
+        change_address,
+        refund_key,
+        fee_rate,
+        mode,
+        psbt,
+    }: ProveSpellInputs,
+) -> Result<Vec<String>> {
+    ensure!(
+        funding_utxo_values.is_empty() || funding_utxo_values.len() == funding_out_points.len(),
+        "--funding-utxo-value must be provided for each --funding-utxo-id (or not at all)"
+    );
+    ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
+
+    let mut prev_txs = txs_by_txid(prev_txs)?;
     ensure!(tx
         .input
         .iter()
         .all(|input| prev_txs.contains_key(&input.previous_output.txid)));
+    ensure!(
+        funding_out_points.iter().all(|out_point| tx
+            .input
+            .iter()
+            .all(|tx_in| tx_in.previous_output != *out_point)),
+        "funding UTXOs must not be spent by the spell"
+    );
 
-    let app_prover = app::Prover::new();
-    let binaries = cli::app::binaries_by_vk(&app_prover, app_bins)?;
+    let funding_utxos = funding_out_points
+        .iter()
+        .enumerate()
+        .map(|(i, out_point)| {
+            let funding_txout = funding_txout(
+                &prev_txs,
+                out_point,
+                funding_utxo_values.get(i).copied(),
+                psbt,
+            )?;
+            Ok((*out_point, funding_txout))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Funding txs are only needed for fee computation and to make PSBTs: they must not be passed
+    // to the prover unless the spell spends or references their outputs.
+    remove_funding_txs(&mut prev_txs, &tx, &spell, &funding_out_points);
+    let psbt_prev_txs = prev_txs.clone();
 
     let transactions = spell::prove_spell_tx(
         spell,
         tx,
         binaries,
         prev_txs,
-        funding_utxo,
-        funding_utxo_value,
+        funding_utxos.clone(),
         change_address,
+        refund_key,
         fee_rate,
+        mode,
     )?;
 
-    // Convert transactions to hex and create JSON array
-    let hex_txs: Vec<String> = transactions.iter().map(|tx| serialize_hex(tx)).collect();
+    match psbt {
+        true => {
+            let psbts = psbt::spell_psbts(transactions, &funding_utxos, &psbt_prev_txs)?;
+            Ok(psbts.iter().map(|psbt| psbt.to_string()).collect())
+        }
+        false => Ok(transactions.iter().map(serialize_hex).collect()),
+    }
+}
 
-    // Print JSON array of transaction hexes
-    println!("{}", serde_json::to_string(&hex_txs)?);
+/// Find the funding UTXO in `prev_txs`. If the funding tx is not there, and `value` is provided
+/// (and PSBTs are not needed), the funding UTXO is assumed to be a Taproot output.
+fn funding_txout(
+    prev_txs: &BTreeMap<Txid, Transaction>,
+    funding_utxo: &OutPoint,
+    value: Option<u64>,
+    psbt: bool,
+) -> Result<TxOut> {
+    let funding_txout = prev_txs
+        .get(&funding_utxo.txid)
+        .and_then(|funding_tx| funding_tx.output.get(funding_utxo.vout as usize))
+        .cloned();
 
-    Ok(())
+    match (funding_txout, value) {
+        (Some(funding_txout), Some(value)) => {
+            ensure!(
+                funding_txout.value.to_sat() == value,
+                "funding UTXO {} value is {}, not {} sats",
+                funding_utxo,
+                funding_txout.value,
+                value
+            );
+            Ok(funding_txout)
+        }
+        (Some(funding_txout), None) => Ok(funding_txout),
+        (None, Some(value)) if !psbt => Ok(TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new_witness_program(&WitnessProgram::new(
+                WitnessVersion::V1,
+                &[0; 32],
+            )?),
+        }),
+        (None, _) => bail!(
+            "the funding tx {} must be included in --prev-txs (with --psbt or without --funding-utxo-value)",
+            funding_utxo.txid
+        ),
+    }
 }
 
-pub fn check(SpellCheckParams { spell, app_bins }: SpellCheckParams) -> Result<()> {
+/// Remove funding txs from `prev_txs` unless `tx` or `spell` (via reference UTXOs) need them.
+fn remove_funding_txs(
+    prev_txs: &mut BTreeMap<Txid, Transaction>,
+    tx: &Transaction,
+    spell: &Spell,
+    funding_utxos: &[OutPoint],
+) {
+    for funding_txid in funding_utxos.iter().map(|out_point| out_point.txid) {
+        let spent = tx
+            .input
+            .iter()
+            .any(|tx_in| tx_in.previous_output.txid == funding_txid);
+        let referenced = spell.refs.iter().flatten().any(|input| {
+            input.utxo_id.as_ref().map(|utxo_id| utxo_id.0)
+                == Some(TxId(funding_txid.to_byte_array()))
+        });
+        if !spent && !referenced {
+            prev_txs.remove(&funding_txid);
+        }
+    }
+}
+
+/// Read transactions given as hex strings, files with hex-encoded transactions (separated by
+/// whitespace or commas) or directories of `*.hex` files (one transaction per file).
+fn read_prev_txs(prev_txs: &[String]) -> Result<Vec<Transaction>> {
+    let parse = |hex: &str, source: &str| {
+        deserialize_hex::<Transaction>(hex)
+            .map_err(|e| anyhow!("error parsing prev tx from {}: {}", source, e))
+    };
+    let mut txs = vec![];
+    for prev_tx in prev_txs {
+        let path = Path::new(prev_tx);
+        if path.is_dir() {
+            txs.extend(DirChainSource::new(path.to_path_buf()).txs()?.into_values());
+        } else if path.is_file() {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow!("error reading {}: {}", path.display(), e))?;
+            for hex in content
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|hex| !hex.is_empty())
+            {
+                txs.push(parse(hex, &path.display().to_string())?);
+            }
+        } else {
+            txs.push(parse(prev_tx, "--prev-txs")?);
+        }
+    }
+    Ok(txs)
+}
+
+/// IDs of transactions creating the outputs spent by `tx` or referenced by `spell`.
+fn prev_txids(tx: &Transaction, spell: &Spell) -> Result<BTreeSet<Txid>> {
+    let spent = tx.input.iter().map(|tx_in| tx_in.previous_output.txid);
+    let referenced = spell
+        .refs
+        .iter()
+        .flatten()
+        .map(|input| {
+            let utxo_id = input
+                .utxo_id
+                .as_ref()
+                .ok_or(anyhow!("missing reference input utxo_id"))?;
+            Ok(Txid::from_byte_array(utxo_id.0 .0))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(spent.chain(referenced).collect())
+}
+
+pub fn check(
+    SpellCheckParams {
+        spell,
+        tx,
+        prev_txs,
+        app_bins,
+        mock,
+        chain,
+    }: SpellCheckParams,
+) -> Result<()> {
     utils::logger::setup_logger();
 
     let mut spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
@@ -85,37 +309,119 @@ pub fn check(SpellCheckParams { spell, app_bins }: SpellCheckParams) -> Result<(
         "all spell inputs must have utxo_id"
     );
 
-    let tx = tx::from_spell(&spell);
-
-    let prev_txs = cli::tx::get_prev_txs(&tx)?;
+    let tx = match tx {
+        Some(tx) => deserialize_hex::<Transaction>(&tx)?,
+        None => tx::from_spell(&spell),
+    };
 
+    let prev_txids = prev_txids(&tx, &spell)?;
+    let prev_txs = match prev_txs.is_empty() {
+        true => {
+            let chain = chain.chain_source()?;
+            prev_txids
+                .iter()
+                .map(|txid| chain.get_transaction(txid))
+                .collect::<Result<Vec<_>>>()?
+        }
+        false => {
+            let mut prev_txs = txs_by_txid(read_prev_txs(&prev_txs)?)?;
+            prev_txids
+                .iter()
+                .map(|txid| {
+                    prev_txs
+                        .remove(txid)
+                        .ok_or(anyhow!("prev tx {} is not in --prev-txs", txid))
+                })
+                .collect::<Result<Vec<_>>>()?
+        }
+    };
     eprintln!("checking prev_txs");
-    let prev_spells = charms_client::prev_spells(&prev_txs, &SPELL_VK);
+    let prev_spells = charms_client::prev_spells(&prev_txs, ProofMode::new(mock).spell_vk());
+    for (txid, (prev_spell, _)) in &prev_spells {
+        match prev_spell {
+            Ok(_) | Err(SpellError::NoEnvelope(_)) => {}
+            Err(e) => eprintln!("prev tx {} has no correct spell: {}", txid, e),
+        }
+    }
     eprintln!("checking prev_txs... done!");
 
+    spell.resolve_input_charms(&prev_spells)?;
+
     let (norm_spell, app_private_inputs) = spell.normalized()?;
     let norm_spell = spell::align_spell_to_tx(norm_spell, &tx)?;
 
-    eprintln!("checking spell is well-formed");
-    ensure!(
-        charms_client::well_formed(&norm_spell, &prev_spells),
-        "spell is not well-formed"
-    );
-    eprintln!("checking spell is well-formed... done!");
-
-    eprintln!("checking spell is correct");
+    eprintln!("checking spell");
     let app_prover = app::Prover::new();
-
     let binaries = cli::app::binaries_by_vk(&app_prover, app_bins)?;
-
-    let charms_tx = spell.to_tx()?;
-    app_prover.run_all(
+    let report = spell::check(
+        &app_prover,
+        &norm_spell,
         &binaries,
-        &charms_tx,
-        &norm_spell.app_public_inputs,
-        app_private_inputs,
-    )?;
-    eprintln!("checking spell is correct... done!");
+        &app_private_inputs,
+        &prev_txs,
+        ProofMode::new(mock).spell_vk(),
+    );
+    ensure!(report.is_ok(), "{}", report);
+    eprintln!("checking spell... done!");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{
+        absolute::LockTime, consensus::encode::serialize_hex, hashes::Hash, transaction::Version,
+        Amount, OutPoint, ScriptBuf, TxIn, TxOut,
+    };
+    use tempfile::TempDir;
+
+    fn tx(value: u64) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn reads_prev_txs_from_hex_files_and_dirs() {
+        let dir = TempDir::new().unwrap();
+        let txs_dir = dir.path().join("txs");
+        fs::create_dir_all(&txs_dir).unwrap();
+        let txs: Vec<Transaction> = (1..=5).map(|v| tx(v * 1000)).collect();
+
+        let file = dir.path().join("prev-txs.txt");
+        fs::write(
+            &file,
+            format!("{}\n{},\n", serialize_hex(&txs[1]), serialize_hex(&txs[2])),
+        )
+        .unwrap();
+        for tx in &txs[3..] {
+            fs::write(
+                txs_dir.join(format!("{}.hex", tx.compute_txid())),
+                serialize_hex(tx),
+            )
+            .unwrap();
+        }
+        fs::write(txs_dir.join("README"), "not a tx").unwrap();
+
+        let read = read_prev_txs(&[
+            serialize_hex(&txs[0]),
+            file.display().to_string(),
+            txs_dir.display().to_string(),
+        ]);
+
+        let read: BTreeSet<Txid> = read.unwrap().iter().map(|tx| tx.compute_txid()).collect();
+        assert_eq!(read, txs.iter().map(|tx| tx.compute_txid()).collect());
+
+        assert!(read_prev_txs(&["not-hex".to_string()]).is_err());
+    }
+}
@@ -1,10 +1,16 @@
-use crate::{cli, tx};
-use anyhow::{anyhow, Result};
+use crate::{
+    cli,
+    cli::ChainParams,
+    psbt,
+    spell::ProofMode,
+    tx,
+    tx::{commit_spend_info, recover_commit_tx},
+};
+use anyhow::{anyhow, ensure, Result};
 use bitcoin::{
     consensus::encode::{deserialize_hex, serialize_hex},
-    OutPoint, Transaction,
+    FeeRate, OutPoint, Psbt, Transaction,
 };
-use std::process::Command;
 
 pub(crate) fn parse_outpoint(s: &str) -> Result<OutPoint> {
     let parts: Vec<&str> = s.split(':').collect();
@@ -15,25 +21,59 @@ pub(crate) fn parse_outpoint(s: &str) -> Result<OutPoint> {
     Ok(OutPoint::new(parts[0].parse()?, parts[1].parse()?))
 }
 
-pub fn tx_show_spell(tx: String, json: bool) -> Result<()> {
+pub fn tx_show_spell(tx: String, json: bool, mock: bool) -> Result<()> {
     let tx = deserialize_hex::<Transaction>(&tx)?;
 
-    match tx::spell(&tx) {
-        Some(spell) => cli::print_output(&spell, json)?,
+    match tx::spell(&tx, ProofMode::new(mock)) {
+        Some(spell) => cli::print_output(&spell.with_decimal_amounts(), json)?,
         None => eprintln!("No spell found in the transaction"),
     }
 
     Ok(())
 }
 
-pub(crate) fn get_prev_txs(tx: &Transaction) -> Result<Vec<Transaction>> {
-    let cmd_output = Command::new("bash")
-        .args(&[
-            "-c", format!("bitcoin-cli decoderawtransaction {} | jq -r '.vin[].txid' | sort | uniq | xargs -I {{}} bitcoin-cli getrawtransaction {{}} | paste -sd, -", serialize_hex(tx)).as_str()
-        ])
-        .output()?;
-    String::from_utf8(cmd_output.stdout)?
-        .split(',')
-        .map(|s| Ok(deserialize_hex::<Transaction>(s.trim())?))
-        .collect()
+pub fn tx_finalize(psbts: Vec<String>) -> Result<()> {
+    let psbts = psbts
+        .iter()
+        .map(|psbt| {
+            psbt.parse::<Psbt>()
+                .map_err(|e| anyhow!("invalid PSBT: {}", e))
+        })
+        .collect::<Result<_>>()?;
+
+    let txs = psbt::finalize(psbts)?;
+
+    // Print JSON array of transaction hexes
+    let hex_txs: Vec<String> = txs.iter().map(serialize_hex).collect();
+    println!("{}", serde_json::to_string(&hex_txs)?);
+
+    Ok(())
+}
+
+pub fn tx_recover_commit(spell_tx: String, fee_rate: f64, chain: ChainParams) -> Result<()> {
+    ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
+    let fee_rate = FeeRate::from_sat_per_kwu((fee_rate * 250.0) as u64);
+
+    let spell_tx = deserialize_hex::<Transaction>(&spell_tx)?;
+    let info = commit_spend_info(&spell_tx)?;
+
+    let wallet = chain.wallet()?;
+    let commit_txout = wallet.get_txout(&info.out_point)?.ok_or(anyhow!(
+        "commit tx output {} not found or already spent",
+        info.out_point
+    ))?;
+
+    let address = wallet.new_change_address()?;
+    let tx = recover_commit_tx(&info, &commit_txout, address.script_pubkey(), fee_rate)?;
+    let signed_tx = wallet.sign_key_spend(
+        &tx,
+        0,
+        &[commit_txout],
+        info.internal_key,
+        Some(info.merkle_root),
+    )?;
+
+    println!("{}", serialize_hex(&signed_tx));
+
+    Ok(())
 }
@@ -1,30 +1,34 @@
 use crate::{
-    app, cli,
-    cli::{WalletCastParams, WalletListParams},
-    spell::{prove_spell_tx, KeyedCharms, Spell},
+    app,
+    chain::{get_prev_txs, ChainSource, Unspent, Wallet},
+    cli,
+    cli::{
+        ChainParams, OutputFormat, WalletBalanceParams, WalletCastParams, WalletListParams,
+        WalletLockParams, WalletSendParams,
+    },
+    psbt,
+    spell::{prove_spell_tx, Input, KeyedCharms, Output, ProofMode, Spell},
     tx,
     tx::txs_by_txid,
     utils,
     utils::str_index,
 };
-use anyhow::{ensure, Result};
-use bitcoin::{consensus::encode::serialize_hex, hashes::Hash, OutPoint, Transaction};
-use charms_data::{App, Data, TxId, UtxoId};
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, bail, ensure, Result};
+use bitcoin::{
+    address::NetworkUnchecked, consensus::encode::serialize_hex, hashes::Hash, Address, OutPoint,
+    Transaction, TxOut, Txid,
+};
+use charms_data::{
+    ref_nft_app, sum_token_amount, App, Data, TokenAmount, TokenMetadata, TxId, UtxoId, NFT, TOKEN,
+};
+use serde::Serialize;
 use std::{
     collections::{BTreeMap, BTreeSet},
-    process::{Command, Stdio},
+    path::PathBuf,
+    thread,
+    time::Duration,
 };
 
-#[derive(Debug, Deserialize)]
-struct BListUnspentItem {
-    txid: String,
-    vout: u32,
-    amount: f64,
-    confirmations: u32,
-    solvable: bool,
-}
-
 #[derive(Debug, Serialize)]
 struct OutputWithCharms {
     confirmations: u32,
@@ -40,28 +44,23 @@ struct AppsAndCharmsOutputs {
     outputs: BTreeMap<UtxoId, OutputWithCharms>,
 }
 
-pub fn list(params: WalletListParams) -> Result<()> {
-    let b_cli = Command::new("bitcoin-cli")
-        .args(&["listunspent", "0"]) // include outputs with 0 confirmations
-        .stdout(Stdio::piped())
-        .spawn()?;
-    let output = b_cli.wait_with_output()?;
-    let b_list_unspent: Vec<BListUnspentItem> = serde_json::from_slice(&output.stdout)?;
+pub fn list(WalletListParams { json, mock, chain }: WalletListParams) -> Result<()> {
+    let chain = chain.chain_source()?;
+    let unspent = chain.list_unspent()?;
 
-    let unspent_charms_outputs = outputs_with_charms(b_list_unspent)?;
+    let unspent_charms_outputs =
+        outputs_with_charms(chain.as_ref(), unspent, ProofMode::new(mock))?;
 
-    cli::print_output(&unspent_charms_outputs, params.json)?;
+    cli::print_output(&unspent_charms_outputs, json)?;
     Ok(())
 }
 
-fn outputs_with_charms(b_list_unspent: Vec<BListUnspentItem>) -> Result<AppsAndCharmsOutputs> {
-    let txid_set = b_list_unspent
-        .iter()
-        .map(|item| item.txid.clone())
-        .collect::<BTreeSet<_>>();
-    let spells = txs_with_spells(txid_set.into_iter())?;
-    let utxos_with_charms: BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)> =
-        utxos_with_charms(spells, b_list_unspent);
+fn outputs_with_charms(
+    chain: &dyn ChainSource,
+    unspent: Vec<Unspent>,
+    mode: ProofMode,
+) -> Result<AppsAndCharmsOutputs> {
+    let utxos_with_charms = charm_utxos(chain, unspent, mode)?;
     let apps = collect_apps(&utxos_with_charms);
 
     Ok(AppsAndCharmsOutputs {
@@ -70,15 +69,30 @@ fn outputs_with_charms(b_list_unspent: Vec<BListUnspentItem>) -> Result<AppsAndC
     })
 }
 
-fn txs_with_spells(txid_iter: impl Iterator<Item = String>) -> Result<BTreeMap<TxId, Spell>> {
+/// Outputs from `unspent` with charms (of spells verified in `mode`).
+fn charm_utxos(
+    chain: &dyn ChainSource,
+    unspent: Vec<Unspent>,
+    mode: ProofMode,
+) -> Result<BTreeMap<UtxoId, (Unspent, ParsedCharms)>> {
+    let txid_set = unspent
+        .iter()
+        .map(|item| item.out_point.txid)
+        .collect::<BTreeSet<_>>();
+    let spells = txs_with_spells(chain, txid_set.into_iter(), mode)?;
+    Ok(utxos_with_charms(spells, unspent))
+}
+
+fn txs_with_spells(
+    chain: &dyn ChainSource,
+    txid_iter: impl Iterator<Item = Txid>,
+    mode: ProofMode,
+) -> Result<BTreeMap<TxId, Spell>> {
     let txs_with_spells = txid_iter
-        .map(|txid| {
-            let tx: Transaction = get_tx(&txid)?;
-            Ok(tx)
-        })
-        .map(|tx_result: Result<Transaction>| {
+        .map(|txid| chain.get_transaction(&txid))
+        .map(|tx_result| {
             let tx = tx_result?;
-            let spell_opt = tx::spell(&tx);
+            let spell_opt = tx::spell(&tx, mode);
             Ok(spell_opt.map(|spell| (TxId(tx.compute_txid().to_byte_array()), spell)))
         })
         .filter_map(|tx_result| match tx_result {
@@ -93,21 +107,19 @@ fn txs_with_spells(txid_iter: impl Iterator<Item = String>) -> Result<BTreeMap<T
 
 fn utxos_with_charms(
     spells: BTreeMap<TxId, Spell>,
-    b_list_unspent: Vec<BListUnspentItem>,
-) -> BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)> {
-    b_list_unspent
+    unspent: Vec<Unspent>,
+) -> BTreeMap<UtxoId, (Unspent, ParsedCharms)> {
+    unspent
         .into_iter()
-        .filter(|item| item.solvable)
-        .filter_map(|b_utxo| {
-            let txid =
-                TxId::from_str(&b_utxo.txid).expect("txids from bitcoin-cli should be valid");
-            let i = b_utxo.vout;
+        .filter_map(|utxo| {
+            let txid = TxId(utxo.out_point.txid.to_byte_array());
+            let i = utxo.out_point.vout;
             spells
                 .get(&txid)
                 .and_then(|spell| spell.outs.get(i as usize).map(|u| (u, &spell.apps)))
                 .and_then(|(u, apps)| u.charms.as_ref().map(|keyed_charms| (keyed_charms, apps)))
                 .map(|(keyed_charms, apps)| {
-                    (UtxoId(txid, i), (b_utxo, parsed_charms(keyed_charms, apps)))
+                    (UtxoId(txid, i), (utxo, parsed_charms(keyed_charms, apps)))
                 })
         })
         .collect()
@@ -121,7 +133,7 @@ fn parsed_charms(keyed_charms: &KeyedCharms, apps: &BTreeMap<String, App>) -> Pa
 }
 
 fn collect_apps(
-    strings_of_charms: &BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)>,
+    strings_of_charms: &BTreeMap<UtxoId, (Unspent, ParsedCharms)>,
 ) -> BTreeMap<App, String> {
     let apps: BTreeSet<App> = strings_of_charms
         .iter()
@@ -141,7 +153,7 @@ fn enumerate_apps(apps: &BTreeMap<App, String>) -> BTreeMap<String, App> {
 }
 
 fn pretty_outputs(
-    utxos_with_charms: BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)>,
+    utxos_with_charms: BTreeMap<UtxoId, (Unspent, ParsedCharms)>,
     apps: &BTreeMap<App, String>,
 ) -> BTreeMap<UtxoId, OutputWithCharms> {
     utxos_with_charms
@@ -152,7 +164,7 @@ fn pretty_outputs(
                 .map(|(app, value)| (apps[app].clone(), value.clone()))
                 .collect();
             let confirmations = utxo.confirmations;
-            let sats = (utxo.amount * 100000000f64) as u64;
+            let sats = utxo.value.to_sat();
             (
                 utxo_id.clone(),
                 OutputWithCharms {
@@ -165,20 +177,151 @@ fn pretty_outputs(
         .collect()
 }
 
-fn get_tx(txid: &str) -> Result<Transaction> {
-    let b_cli = Command::new("bitcoin-cli")
-        .args(&["getrawtransaction", txid])
-        .stdout(Stdio::piped())
-        .spawn()?;
-    let output = b_cli.wait_with_output()?;
-    ensure!(
-        output.status.success(),
-        "bitcoin-cli getrawtransaction failed"
-    );
-    let tx_hex = String::from_utf8(output.stdout)?;
-    let tx_hex = tx_hex.trim();
-    let tx = bitcoin::consensus::encode::deserialize_hex(&(tx_hex))?;
-    Ok(tx)
+#[derive(Debug, PartialEq, Serialize)]
+struct AppBalance {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ticker: Option<String>,
+    /// Token amount (tokens only), with the decimal point placed according to the reference NFT's
+    /// `decimals`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<String>,
+    /// Number of NFTs (NFTs only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nfts: Option<usize>,
+    /// Number of outputs with charms of the app.
+    utxos: usize,
+}
+
+pub fn balance(
+    WalletBalanceParams {
+        format,
+        ref_nfts,
+        mock,
+        chain,
+    }: WalletBalanceParams,
+) -> Result<()> {
+    let ref_nfts = parse_utxo_ids(&ref_nfts)?;
+    let mode = ProofMode::new(mock);
+    let chain = chain.chain_source()?;
+
+    let charm_utxos = charm_utxos(chain.as_ref(), chain.list_unspent()?, mode)?;
+    let ref_charms = ref_nfts
+        .iter()
+        .map(|out_point| output_charms(chain.as_ref(), out_point, mode))
+        .collect::<Result<Vec<_>>>()?;
+    let balances = app_balances(&charm_utxos, &ref_charms)?;
+
+    match format {
+        OutputFormat::Table => print!("{}", balance_table(&balances)),
+        OutputFormat::Json => cli::print_output(&balances, true)?,
+        OutputFormat::Yaml => cli::print_output(&balances, false)?,
+    }
+    Ok(())
+}
+
+/// Charms of output `out_point` (of a spell verified in `mode`).
+fn output_charms(
+    chain: &dyn ChainSource,
+    out_point: &OutPoint,
+    mode: ProofMode,
+) -> Result<ParsedCharms> {
+    let tx = chain.get_transaction(&out_point.txid)?;
+    let spell =
+        tx::spell(&tx, mode).ok_or(anyhow!("no spell in transaction {}", out_point.txid))?;
+    let keyed_charms = spell
+        .outs
+        .get(out_point.vout as usize)
+        .and_then(|u| u.charms.as_ref())
+        .ok_or(anyhow!("no charms in output {}", out_point))?;
+    Ok(parsed_charms(keyed_charms, &spell.apps))
+}
+
+/// Balances of all apps with charms in `charm_utxos`. Metadata comes from reference NFTs (see
+/// CHIP-0420) found in `charm_utxos` or `ref_charms`.
+fn app_balances(
+    charm_utxos: &BTreeMap<UtxoId, (Unspent, ParsedCharms)>,
+    ref_charms: &[ParsedCharms],
+) -> Result<BTreeMap<App, AppBalance>> {
+    let strings_of_charms: Vec<&ParsedCharms> =
+        charm_utxos.values().map(|(_, charms)| charms).collect();
+    let apps: BTreeSet<&App> = strings_of_charms
+        .iter()
+        .flat_map(|charms| charms.keys())
+        .collect();
+
+    apps.into_iter()
+        .map(|app| {
+            let ref_nft = ref_nft_app(app);
+            let metadata = strings_of_charms
+                .iter()
+                .copied()
+                .chain(ref_charms)
+                .find_map(|charms| charms.get(&ref_nft))
+                .and_then(|data| data.value::<TokenMetadata>().ok())
+                .unwrap_or_default();
+
+            let utxos = strings_of_charms
+                .iter()
+                .filter(|charms| charms.contains_key(app))
+                .count();
+            let amount = match app.tag {
+                TOKEN => Some(
+                    sum_token_amount(app, strings_of_charms.iter().copied())?
+                        .to_decimal_string(metadata.decimals.unwrap_or(0)),
+                ),
+                _ => None,
+            };
+            let balance = AppBalance {
+                name: metadata.name,
+                ticker: metadata.ticker,
+                amount,
+                nfts: (app.tag == NFT).then_some(utxos),
+                utxos,
+            };
+            Ok((app.clone(), balance))
+        })
+        .collect()
+}
+
+fn balance_table(balances: &BTreeMap<App, AppBalance>) -> String {
+    let header = ["APP", "NAME", "TICKER", "BALANCE", "UTXOS"].map(String::from);
+    let rows: Vec<[String; 5]> = balances
+        .iter()
+        .map(|(app, balance)| {
+            let amount = match (&balance.amount, balance.nfts) {
+                (Some(amount), _) => amount.clone(),
+                (None, Some(nfts)) => format!("{} NFT(s)", nfts),
+                (None, None) => "-".to_string(),
+            };
+            [
+                app.to_string(),
+                balance.name.clone().unwrap_or_default(),
+                balance.ticker.clone().unwrap_or_default(),
+                amount,
+                balance.utxos.to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
 }
 
 pub const MIN_SATS: u64 = 1000;
@@ -189,16 +332,50 @@ pub fn cast(
         app_bins,
         funding_utxo_id,
         fee_rate,
+        mock,
+        psbt,
+        prover,
+        chain,
     }: WalletCastParams,
 ) -> Result<()> {
     utils::logger::setup_logger();
+    prover.apply();
 
-    // Parse funding UTXO early: to fail fast
-    let funding_utxo = crate::cli::tx::parse_outpoint(&funding_utxo_id)?;
+    // Parse funding UTXOs early: to fail fast
+    let funding_out_points = parse_utxo_ids(&funding_utxo_id)?;
 
     ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
-    let mut spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
+    let spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
+
+    cast_spell(
+        spell,
+        app_bins,
+        funding_out_points,
+        fee_rate,
+        ProofMode::new(mock),
+        psbt,
+        &chain,
+    )
+}
+
+fn parse_utxo_ids(utxo_ids: &[String]) -> Result<Vec<OutPoint>> {
+    utxo_ids
+        .iter()
+        .map(|utxo_id| crate::cli::tx::parse_outpoint(utxo_id))
+        .collect()
+}
 
+/// Prove `spell`, create the commit and spell transactions and sign them with the wallet (or
+/// output PSBTs if `psbt` is set).
+fn cast_spell(
+    mut spell: Spell,
+    app_bins: Vec<PathBuf>,
+    funding_out_points: Vec<OutPoint>,
+    fee_rate: f64,
+    mode: ProofMode,
+    psbt: bool,
+    chain: &ChainParams,
+) -> Result<()> {
     // make sure spell inputs all have utxo_id
     ensure!(
         spell.ins.iter().all(|u| u.utxo_id.is_some()),
@@ -217,9 +394,19 @@ pub fn cast(
 
     let tx = tx::from_spell(&spell);
 
-    let prev_txs = txs_by_txid(cli::tx::get_prev_txs(&tx)?)?;
-    let funding_utxo_value = funding_utxo_value(&funding_utxo)?;
-    let change_address = new_change_address()?;
+    let wallet = chain.wallet()?;
+    let chain = chain.chain_source()?;
+
+    let prev_txs = txs_by_txid(get_prev_txs(chain.as_ref(), &tx)?)?;
+    let funding_utxos = match funding_out_points.is_empty() {
+        true => plain_btc_utxos(chain.as_ref(), &tx)?,
+        false => funding_out_points
+            .iter()
+            .map(|out_point| Ok((*out_point, funding_txout(chain.as_ref(), out_point)?)))
+            .collect::<Result<_>>()?,
+    };
+    let change_address = wallet.new_change_address()?.to_string();
+    let refund_key = wallet.new_refund_key()?;
 
     let app_prover = app::Prover::new();
     let binaries = cli::app::binaries_by_vk(&app_prover, app_bins)?;
@@ -228,65 +415,578 @@ pub fn cast(
         spell,
         tx,
         binaries,
-        prev_txs,
-        funding_utxo,
-        funding_utxo_value,
+        prev_txs.clone(),
+        funding_utxos.clone(),
         change_address,
+        Some(refund_key),
         fee_rate,
+        mode,
     )?;
 
-    let signed_commit_tx_hex = sign_tx(&serialize_hex(&commit_tx))?;
-    let signed_spell_tx_hex = sign_spell_tx(&serialize_hex(&spell_tx), &commit_tx)?;
+    if psbt {
+        let mut psbts = psbt::spell_psbts([commit_tx, spell_tx], &funding_utxos, &prev_txs)?;
+        for psbt in psbts.iter_mut() {
+            wallet.add_key_origins(psbt)?;
+        }
+
+        // Print JSON array of base64-encoded PSBTs
+        let psbts: Vec<String> = psbts.iter().map(|psbt| psbt.to_string()).collect();
+        println!("{}", serde_json::to_string(&psbts)?);
+        return Ok(());
+    }
+
+    let signed_commit_tx = wallet.sign_tx(&commit_tx, &[])?;
+    let signed_spell_tx = wallet.sign_tx(
+        &spell_tx,
+        &[(
+            OutPoint::new(commit_tx.compute_txid(), 0),
+            commit_tx.output[0].clone(),
+        )],
+    )?;
 
     // Print JSON array of transaction hexes
     println!(
         "{}",
-        serde_json::to_string(&[signed_commit_tx_hex, signed_spell_tx_hex])?
+        serde_json::to_string(&[
+            serialize_hex(&signed_commit_tx),
+            serialize_hex(&signed_spell_tx)
+        ])?
     );
 
     Ok(())
 }
 
-fn sign_spell_tx(spell_tx_hex: &String, commit_tx: &Transaction) -> Result<String> {
-    let cmd_line = format!(
-        r#"bitcoin-cli signrawtransactionwithwallet {} '[{{"txid":"{}","vout":0,"scriptPubKey":"{}","amount":{}}}]' | jq -r '.hex'"#,
-        spell_tx_hex,
-        commit_tx.compute_txid(),
-        &commit_tx.output[0].script_pubkey.to_hex_string(),
-        commit_tx.output[0].value.to_btc()
-    );
-    let cmd_out = Command::new("bash")
-        .args(&["-c", cmd_line.as_str()])
-        .output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().to_string())
+pub fn send(
+    WalletSendParams {
+        app,
+        amount,
+        to,
+        app_bins,
+        funding_utxo_id,
+        fee_rate,
+        mock,
+        psbt,
+        prover,
+        chain,
+    }: WalletSendParams,
+) -> Result<()> {
+    utils::logger::setup_logger();
+    prover.apply();
+
+    let funding_out_points = parse_utxo_ids(&funding_utxo_id)?;
+    ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
+    let to: Address<NetworkUnchecked> = to
+        .parse()
+        .map_err(|e| anyhow!("invalid address {}: {}", to, e))?;
+
+    let mode = ProofMode::new(mock);
+    let source = chain.chain_source()?;
+    let charm_utxos = charm_utxos(source.as_ref(), source.list_unspent()?, mode)?;
+    let change_address = chain.wallet()?.new_change_address()?.into_unchecked();
+
+    let spell = match (app.tag, amount) {
+        (TOKEN, Some(amount)) => {
+            token_send_spell(&app, amount.into(), to, change_address, &charm_utxos)?
+        }
+        (TOKEN, None) => bail!("--amount is required to send tokens"),
+        (_, None) => nft_send_spell(&app, to, change_address, &charm_utxos)?,
+        (_, Some(_)) => bail!("--amount is only supported for tokens (apps with tag 't')"),
+    };
+
+    cast_spell(
+        spell,
+        app_bins,
+        funding_out_points,
+        fee_rate,
+        mode,
+        psbt,
+        &chain,
+    )
 }
 
-fn sign_tx(tx_hex: &str) -> Result<String> {
-    let cmd_out = Command::new("bash")
-        .args(&[
-            "-c",
-            format!(
-                "bitcoin-cli signrawtransactionwithwallet {} | jq -r '.hex'",
-                tx_hex
+/// Spell sending `amount` of token `app` to address `to`, and the rest of the selected inputs'
+/// tokens to `change_address`.
+///
+/// Inputs are selected from `charm_utxos` carrying only `app` tokens (so that no other charms
+/// need to be moved), largest amounts first.
+fn token_send_spell(
+    app: &App,
+    amount: TokenAmount,
+    to: Address<NetworkUnchecked>,
+    change_address: Address<NetworkUnchecked>,
+    charm_utxos: &BTreeMap<UtxoId, (Unspent, ParsedCharms)>,
+) -> Result<Spell> {
+    ensure!(amount > TokenAmount::ZERO, "amount must be positive");
+
+    let mut candidates = charm_utxos
+        .iter()
+        .filter(|(_, (_, charms))| charms.len() == 1 && charms.contains_key(app))
+        .map(|(utxo_id, (_, charms))| Ok((utxo_id, charms[app].value::<TokenAmount>()?)))
+        .collect::<Result<Vec<_>>>()?;
+    // largest amounts first, ties broken by UTXO ID to make selection deterministic
+    candidates.sort_by(|(id_a, a), (id_b, b)| b.cmp(a).then(id_a.cmp(id_b)));
+
+    let mut selected = vec![];
+    let mut total = TokenAmount::ZERO;
+    for (utxo_id, utxo_amount) in candidates.iter() {
+        if total >= amount {
+            break;
+        }
+        total = total
+            .checked_add(*utxo_amount)
+            .ok_or(anyhow!("token amount overflow"))?;
+        selected.push((*utxo_id, *utxo_amount));
+    }
+    let Some(change) = total.checked_sub(amount) else {
+        bail!(
+            "insufficient {} balance: need {}, available {} in {} UTXOs carrying only this token",
+            app,
+            amount,
+            total,
+            candidates.len()
+        );
+    };
+
+    let key = str_index(&0);
+    let keyed = |amount: TokenAmount| BTreeMap::from([(key.clone(), Data::from(&amount))]);
+    let mut spell = Spell::new();
+    spell.apps.insert(key.clone(), app.clone());
+    spell.ins = selected
+        .into_iter()
+        .map(|(utxo_id, utxo_amount)| Input {
+            utxo_id: Some(utxo_id.clone()),
+            charms: Some(keyed(utxo_amount)),
+        })
+        .collect();
+    spell.outs.push(Output {
+        address: Some(to),
+        sats: None,
+        charms: Some(keyed(amount)),
+    });
+    if change > TokenAmount::ZERO {
+        spell.outs.push(Output {
+            address: Some(change_address),
+            sats: None,
+            charms: Some(keyed(change)),
+        });
+    }
+    Ok(spell)
+}
+
+/// Spell sending the NFT `app` to address `to`. Other charms on the same UTXO stay in the wallet:
+/// they are sent to `change_address`.
+fn nft_send_spell(
+    app: &App,
+    to: Address<NetworkUnchecked>,
+    change_address: Address<NetworkUnchecked>,
+    charm_utxos: &BTreeMap<UtxoId, (Unspent, ParsedCharms)>,
+) -> Result<Spell> {
+    let (utxo_id, charms) = charm_utxos
+        .iter()
+        .find_map(|(utxo_id, (_, charms))| charms.contains_key(app).then_some((utxo_id, charms)))
+        .ok_or(anyhow!("no UTXO with {} in the wallet", app))?;
+
+    let keys: BTreeMap<&App, String> = charms
+        .keys()
+        .zip(0..)
+        .map(|(a, i)| (a, str_index(&i)))
+        .collect();
+    let keyed = |keep: &dyn Fn(&App) -> bool| -> KeyedCharms {
+        charms
+            .iter()
+            .filter(|(a, _)| keep(a))
+            .map(|(a, data)| (keys[a].clone(), data.clone()))
+            .collect()
+    };
+
+    let mut spell = Spell::new();
+    spell.apps = keys
+        .iter()
+        .map(|(&a, key)| (key.clone(), a.clone()))
+        .collect();
+    spell.ins = vec![Input {
+        utxo_id: Some(utxo_id.clone()),
+        charms: Some(keyed(&|_| true)),
+    }];
+    spell.outs.push(Output {
+        address: Some(to),
+        sats: None,
+        charms: Some(keyed(&|a| a == app)),
+    });
+    if charms.len() > 1 {
+        spell.outs.push(Output {
+            address: Some(change_address),
+            sats: None,
+            charms: Some(keyed(&|a| a != app)),
+        });
+    }
+    Ok(spell)
+}
+
+/// Wallet UTXOs that can fund `tx`: not spent by `tx`, not locked and not carrying charms.
+fn plain_btc_utxos(chain: &dyn ChainSource, tx: &Transaction) -> Result<Vec<(OutPoint, TxOut)>> {
+    let spent: BTreeSet<OutPoint> = tx.input.iter().map(|tx_in| tx_in.previous_output).collect();
+    let unspent: Vec<Unspent> = chain
+        .list_unspent()?
+        .into_iter()
+        .filter(|utxo| !utxo.locked && !spent.contains(&utxo.out_point))
+        .collect();
+
+    let (_, plain_utxos) = partition_charm_utxos(chain, unspent)?;
+    Ok(plain_utxos
+        .into_iter()
+        .map(|utxo| {
+            (
+                utxo.out_point,
+                TxOut {
+                    value: utxo.value,
+                    script_pubkey: utxo.script_pubkey,
+                },
             )
-            .as_str(),
-        ])
-        .output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().to_string())
+        })
+        .collect())
 }
 
-fn new_change_address() -> Result<String> {
-    let cmd_out = Command::new("bitcoin-cli")
-        .args(&["getrawchangeaddress"])
-        .output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().to_string())
+/// Split `unspent` into outputs that may carry charms (see [`tx::may_carry_charms`]) and the rest.
+fn partition_charm_utxos<C: ChainSource + ?Sized>(
+    chain: &C,
+    unspent: Vec<Unspent>,
+) -> Result<(Vec<Unspent>, Vec<Unspent>)> {
+    let txs = unspent
+        .iter()
+        .map(|utxo| utxo.out_point.txid)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|txid| Ok((txid, chain.get_transaction(&txid)?)))
+        .collect::<Result<BTreeMap<_, _>>>()?;
+
+    Ok(unspent
+        .into_iter()
+        .partition(|utxo| tx::may_carry_charms(&txs[&utxo.out_point.txid], utxo.out_point.vout)))
 }
 
-fn funding_utxo_value(utxo: &OutPoint) -> Result<u64> {
-    let cmd = format!(
-        "bitcoin-cli gettxout {} {} | jq -r '.value*100000000 | round'",
-        utxo.txid, utxo.vout
+fn funding_txout(chain: &dyn ChainSource, utxo: &OutPoint) -> Result<TxOut> {
+    let tx_out = chain
+        .get_txout(utxo)?
+        .ok_or(anyhow!("funding UTXO {} not found or already spent", utxo))?;
+    ensure!(
+        !tx::may_carry_charms(&chain.get_transaction(&utxo.txid)?, utxo.vout),
+        "funding UTXO {} may carry charms: spending it to pay fees would destroy them",
+        utxo
     );
-    let cmd_out = Command::new("bash").args(&["-c", &cmd]).output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().parse()?)
+    Ok(tx_out)
+}
+
+pub fn lock(WalletLockParams { json, watch, chain }: WalletLockParams) -> Result<()> {
+    let wallet = chain.wallet()?;
+
+    let Some(interval) = watch else {
+        let locked = lock_charm_utxos(wallet.as_ref())?;
+        cli::print_output(&locked, json)?;
+        return Ok(());
+    };
+
+    utils::logger::setup_logger();
+    loop {
+        watch_round(wallet.as_ref());
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+/// One round of `lock --watch`. Errors (e.g. the node being unreachable) are logged and the next
+/// round retries.
+fn watch_round(wallet: &dyn Wallet) {
+    match lock_charm_utxos(wallet) {
+        Ok(locked) => {
+            for out_point in locked {
+                tracing::info!("locked {}", out_point);
+            }
+        }
+        Err(e) => tracing::warn!("error locking charm UTXOs: {:#}", e),
+    }
+}
+
+/// Lock the wallet's (not yet locked) outputs that may carry charms. Returns the newly locked
+/// outputs.
+fn lock_charm_utxos(wallet: &dyn Wallet) -> Result<Vec<OutPoint>> {
+    let unspent: Vec<Unspent> = wallet
+        .list_unspent()?
+        .into_iter()
+        .filter(|utxo| !utxo.locked)
+        .collect();
+    let (charm_utxos, _) = partition_charm_utxos(wallet, unspent)?;
+    let out_points: Vec<OutPoint> = charm_utxos.iter().map(|utxo| utxo.out_point).collect();
+    wallet.lock_unspent(&out_points)?;
+    Ok(out_points)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chain::mock::{test::cast, MockChain};
+    use bitcoin::{Amount, Psbt, ScriptBuf, TapNodeHash, XOnlyPublicKey};
+    use charms_data::{is_simple_transfer, nft_state_preserved, B32, NFT};
+    use std::cell::Cell;
+
+    fn app(tag: char) -> App {
+        App {
+            tag,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        }
+    }
+
+    fn address(n: u8) -> Address<NetworkUnchecked> {
+        let script = ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([n; 20]));
+        Address::from_script(&script, bitcoin::Network::Regtest)
+            .unwrap()
+            .into_unchecked()
+    }
+
+    fn charm_utxos(utxos: &[&[(&App, Data)]]) -> BTreeMap<UtxoId, (Unspent, ParsedCharms)> {
+        utxos
+            .iter()
+            .zip(0..)
+            .map(|(charms, vout)| {
+                let out_point = OutPoint::new(Txid::all_zeros(), vout);
+                let unspent = Unspent {
+                    out_point,
+                    value: Amount::from_sat(1000),
+                    script_pubkey: ScriptBuf::new(),
+                    confirmations: 1,
+                    locked: false,
+                };
+                let charms = charms
+                    .iter()
+                    .map(|(app, data)| ((*app).clone(), data.clone()))
+                    .collect();
+                (UtxoId(TxId([0; 32]), vout), (unspent, charms))
+            })
+            .collect()
+    }
+
+    fn amount(n: u64) -> Data {
+        Data::from(&TokenAmount(n))
+    }
+
+    #[test]
+    fn sends_tokens_with_change() {
+        let token = app(TOKEN);
+        let nft = app(NFT);
+        let utxos = charm_utxos(&[
+            &[(&token, amount(30))],
+            &[(&token, amount(50))],
+            &[(&token, amount(1000)), (&nft, Data::empty())],
+            &[(&token, amount(20))],
+        ]);
+
+        let spell =
+            token_send_spell(&token, TokenAmount(60), address(1), address(2), &utxos).unwrap();
+        // the UTXO carrying the NFT is not selected
+        let ins: Vec<_> = spell
+            .ins
+            .iter()
+            .map(|i| i.utxo_id.clone().unwrap().1)
+            .collect();
+        assert_eq!(ins, vec![1, 0]);
+        assert_eq!(spell.outs.len(), 2);
+        assert_eq!(spell.outs[0].address, Some(address(1)));
+        assert_eq!(spell.outs[1].address, Some(address(2)));
+        let tx = spell.to_tx().unwrap();
+        assert_eq!(tx.outs[1][&token], amount(20));
+        assert!(is_simple_transfer(&token, &tx));
+
+        // exact amount: no change
+        let spell =
+            token_send_spell(&token, TokenAmount(80), address(1), address(2), &utxos).unwrap();
+        assert_eq!(spell.outs.len(), 1);
+
+        assert!(
+            token_send_spell(&token, TokenAmount(101), address(1), address(2), &utxos).is_err()
+        );
+        assert!(token_send_spell(&token, TokenAmount(0), address(1), address(2), &utxos).is_err());
+    }
+
+    #[test]
+    fn sends_nft_keeping_other_charms() {
+        let token = app(TOKEN);
+        let nft = app(NFT);
+        let nft_state = Data::from(&"state");
+        let utxos = charm_utxos(&[
+            &[(&token, amount(30))],
+            &[(&token, amount(1000)), (&nft, nft_state.clone())],
+        ]);
+
+        let spell = nft_send_spell(&nft, address(1), address(2), &utxos).unwrap();
+        let tx = spell.to_tx().unwrap();
+        assert_eq!(spell.ins.len(), 1);
+        assert_eq!(tx.outs[0], BTreeMap::from([(nft.clone(), nft_state)]));
+        assert_eq!(tx.outs[1], BTreeMap::from([(token.clone(), amount(1000))]));
+        assert!(nft_state_preserved(&nft, &tx));
+        assert!(is_simple_transfer(&token, &tx));
+
+        assert!(nft_send_spell(&app('c'), address(1), address(2), &utxos).is_err());
+    }
+
+    #[test]
+    fn balances_per_app() {
+        let token = app(TOKEN);
+        let nft = app(NFT);
+        let other_token = App {
+            identity: B32([3; 32]),
+            ..token.clone()
+        };
+        let metadata = Data::from(&BTreeMap::from([
+            ("name", Data::from(&"Toad Token")),
+            ("ticker", Data::from(&"TOAD")),
+            ("decimals", Data::from(&2u8)),
+        ]));
+        let utxos = charm_utxos(&[
+            &[(&token, amount(30))],
+            &[(&token, amount(1000)), (&other_token, amount(5))],
+        ]);
+
+        let balances = app_balances(&utxos, &[]).unwrap();
+        assert_eq!(
+            balances[&token],
+            AppBalance {
+                name: None,
+                ticker: None,
+                amount: Some("1030".to_string()),
+                nfts: None,
+                utxos: 2,
+            }
+        );
+
+        // reference NFT provided separately
+        let ref_charms = BTreeMap::from([(nft.clone(), metadata.clone())]);
+        let balances = app_balances(&utxos, &[ref_charms]).unwrap();
+        assert_eq!(balances[&token].ticker.as_deref(), Some("TOAD"));
+        assert_eq!(balances[&token].amount.as_deref(), Some("10.30"));
+        assert_eq!(balances[&other_token].amount.as_deref(), Some("5"));
+
+        // reference NFT in the wallet
+        let utxos = charm_utxos(&[&[(&token, amount(30))], &[(&nft, metadata)]]);
+        let balances = app_balances(&utxos, &[]).unwrap();
+        assert_eq!(balances[&nft].nfts, Some(1));
+        assert_eq!(balances[&nft].name.as_deref(), Some("Toad Token"));
+        assert_eq!(balances[&token].amount.as_deref(), Some("0.30"));
+
+        let table = balance_table(&balances);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("APP "));
+        assert!(lines[1].contains("1 NFT(s)"));
+        assert!(lines[2].contains("0.30"));
+    }
+
+    /// Wallet whose first `list_unspent` call fails (e.g. while the node restarts).
+    struct FlakyWallet {
+        chain: MockChain,
+        failed: Cell<bool>,
+    }
+
+    impl ChainSource for FlakyWallet {
+        fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+            self.chain.get_transaction(txid)
+        }
+
+        fn get_txout(&self, out_point: &OutPoint) -> Result<Option<TxOut>> {
+            self.chain.get_txout(out_point)
+        }
+
+        fn list_unspent(&self) -> Result<Vec<Unspent>> {
+            if !self.failed.replace(true) {
+                bail!("connection refused");
+            }
+            self.chain.list_unspent()
+        }
+
+        fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+            self.chain.broadcast(tx)
+        }
+    }
+
+    impl Wallet for FlakyWallet {
+        fn new_change_address(&self) -> Result<Address> {
+            self.chain.new_change_address()
+        }
+
+        fn sign_tx(
+            &self,
+            tx: &Transaction,
+            prev_outs: &[(OutPoint, TxOut)],
+        ) -> Result<Transaction> {
+            self.chain.sign_tx(tx, prev_outs)
+        }
+
+        fn new_refund_key(&self) -> Result<XOnlyPublicKey> {
+            self.chain.new_refund_key()
+        }
+
+        fn sign_key_spend(
+            &self,
+            tx: &Transaction,
+            input_index: usize,
+            prev_outs: &[TxOut],
+            internal_key: XOnlyPublicKey,
+            merkle_root: Option<TapNodeHash>,
+        ) -> Result<Transaction> {
+            self.chain
+                .sign_key_spend(tx, input_index, prev_outs, internal_key, merkle_root)
+        }
+
+        fn lock_unspent(&self, out_points: &[OutPoint]) -> Result<()> {
+            self.chain.lock_unspent(out_points)
+        }
+
+        fn add_key_origins(&self, psbt: &mut Psbt) -> Result<()> {
+            self.chain.add_key_origins(psbt)
+        }
+    }
+
+    #[test]
+    fn watch_retries_after_errors() {
+        let chain = MockChain::new();
+        let token = app(TOKEN);
+        let address = chain.new_address().unwrap();
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let spell_tx = cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {address}
+    sats: 1000
+    charms:
+      $00: 100
+"#
+            ),
+        )
+        .unwrap();
+        let charm_utxo = OutPoint::new(spell_tx.compute_txid(), 0);
+        let wallet = FlakyWallet {
+            chain,
+            failed: Cell::new(false),
+        };
+        let locked = |wallet: &FlakyWallet| {
+            wallet
+                .chain
+                .list_unspent()
+                .unwrap()
+                .iter()
+                .any(|utxo| utxo.out_point == charm_utxo && utxo.locked)
+        };
+
+        watch_round(&wallet);
+        assert!(!locked(&wallet));
+        watch_round(&wallet);
+        assert!(locked(&wallet));
+    }
 }
@@ -0,0 +1,261 @@
+use crate::tx::dummy_satisfaction;
+use anyhow::{bail, Result};
+use bitcoin::{Amount, FeeRate, OutPoint, TxIn, TxOut};
+
+/// Maximum number of branch-and-bound search steps before giving up and using the fallback.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Select UTXOs from `utxos` to fund a transaction needing `target` (its outputs and the fee for
+/// everything but the inputs) at `fee_rate`.
+///
+/// Each UTXO counts with its *effective value*: its value minus the fee for the input spending
+/// it. UTXOs of unsupported output types (see [`dummy_satisfaction`]) or with no positive effective
+/// value are never selected.
+///
+/// First tries branch-and-bound: looks for a set of UTXOs whose effective values add up to
+/// `target` plus less than `cost_of_change` (the cost of adding a change output), so that the
+/// transaction needs no change. Falls back to selecting UTXOs with the largest effective values
+/// first.
+pub fn select_coins(
+    utxos: &[(OutPoint, TxOut)],
+    target: Amount,
+    cost_of_change: Amount,
+    fee_rate: FeeRate,
+) -> Result<Vec<(OutPoint, TxOut)>> {
+    let mut candidates: Vec<(Amount, &(OutPoint, TxOut))> = utxos
+        .iter()
+        .filter_map(|utxo| Some((effective_value(&utxo.1, fee_rate)?, utxo)))
+        .collect();
+    // largest effective values first, ties broken by out point to make selection deterministic
+    candidates.sort_by(|(a, utxo_a), (b, utxo_b)| b.cmp(a).then(utxo_a.0.cmp(&utxo_b.0)));
+    let values: Vec<Amount> = candidates.iter().map(|(value, _)| *value).collect();
+
+    let available = values.iter().copied().sum::<Amount>();
+    if available < target {
+        bail!(
+            "insufficient funds: need {} (plus fees for inputs), available {} in {} usable UTXOs",
+            target,
+            available,
+            values.len()
+        );
+    }
+
+    let selected = branch_and_bound(&values, target, cost_of_change)
+        .unwrap_or_else(|| largest_first(&values, target, cost_of_change));
+
+    Ok(selected
+        .into_iter()
+        .map(|i| candidates[i].1.clone())
+        .collect())
+}
+
+/// Value of `utxo` minus the fee for spending it, if positive.
+fn effective_value(utxo: &TxOut, fee_rate: FeeRate) -> Option<Amount> {
+    let (script_sig, witness) = dummy_satisfaction(&utxo.script_pubkey).ok()?;
+    let input_weight = TxIn {
+        script_sig,
+        witness,
+        ..Default::default()
+    }
+    .segwit_weight();
+    let fee = fee_rate.fee_wu(input_weight)?;
+    utxo.value
+        .checked_sub(fee)
+        .filter(|value| *value > Amount::ZERO)
+}
+
+/// Depth-first search for the subset of `values` (sorted in descending order) with the smallest
+/// sum in `[target, target + cost_of_change)`. Return the indices of the selected values.
+fn branch_and_bound(
+    values: &[Amount],
+    target: Amount,
+    cost_of_change: Amount,
+) -> Option<Vec<usize>> {
+    let mut search = Search {
+        values,
+        remaining: values
+            .iter()
+            .rev()
+            .scan(Amount::ZERO, |sum, value| {
+                *sum += *value;
+                Some(*sum)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect(),
+        target,
+        upper_bound: target + cost_of_change,
+        tries: 0,
+        current: vec![],
+        best: None,
+    };
+    search.run(0, Amount::ZERO);
+    search.best.map(|(_, selected)| selected)
+}
+
+struct Search<'a> {
+    values: &'a [Amount],
+    /// `remaining[i]`: sum of `values[i..]`.
+    remaining: Vec<Amount>,
+    target: Amount,
+    upper_bound: Amount,
+    tries: usize,
+    current: Vec<usize>,
+    best: Option<(Amount, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    fn run(&mut self, i: usize, sum: Amount) {
+        if self.tries >= BNB_MAX_TRIES || self.best.as_ref().is_some_and(|(s, _)| *s == self.target)
+        {
+            return;
+        }
+        self.tries += 1;
+
+        if sum >= self.upper_bound {
+            return;
+        }
+        if sum >= self.target {
+            if self
+                .best
+                .as_ref()
+                .is_none_or(|(best_sum, _)| sum < *best_sum)
+            {
+                self.best = Some((sum, self.current.clone()));
+            }
+            return;
+        }
+        if i == self.values.len() || sum + self.remaining[i] < self.target {
+            return;
+        }
+
+        self.current.push(i);
+        self.run(i + 1, sum + self.values[i]);
+        self.current.pop();
+
+        // skip values equal to the one just excluded: they would produce the same sums
+        let mut next = i + 1;
+        while next < self.values.len() && self.values[next] == self.values[i] {
+            next += 1;
+        }
+        self.run(next, sum);
+    }
+}
+
+/// Select values (sorted in descending order) until their sum reaches `target + cost_of_change`
+/// (enough for a change output), or all of them if that's not possible.
+fn largest_first(values: &[Amount], target: Amount, cost_of_change: Amount) -> Vec<usize> {
+    let mut sum = Amount::ZERO;
+    let mut selected = vec![];
+    for (i, value) in values.iter().enumerate() {
+        if sum >= target + cost_of_change {
+            break;
+        }
+        sum += *value;
+        selected.push(i);
+    }
+    selected
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{hashes::Hash, ScriptBuf, Txid, WPubkeyHash, WScriptHash};
+    use proptest::prelude::*;
+
+    fn utxos(sats: &[u64]) -> Vec<(OutPoint, TxOut)> {
+        sats.iter()
+            .zip(0..)
+            .map(|(&sats, vout)| {
+                (
+                    OutPoint::new(Txid::all_zeros(), vout),
+                    TxOut {
+                        value: Amount::from_sat(sats),
+                        script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn fee_rate() -> FeeRate {
+        FeeRate::from_sat_per_vb(2).unwrap()
+    }
+
+    fn total_effective_value(selected: &[(OutPoint, TxOut)]) -> Amount {
+        selected
+            .iter()
+            .map(|(_, utxo)| effective_value(utxo, fee_rate()).unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn finds_changeless_solution() {
+        let utxos = utxos(&[50_000, 30_000, 20_000, 7_000, 3_000]);
+        let input_fee =
+            Amount::from_sat(50_000) - effective_value(&utxos[0].1, fee_rate()).unwrap();
+
+        // 30_000 + 7_000 + 3_000 (minus input fees) is an exact match
+        let target = Amount::from_sat(40_000) - input_fee * 3;
+        let selected = select_coins(&utxos, target, Amount::from_sat(500), fee_rate()).unwrap();
+        assert_eq!(total_effective_value(&selected), target);
+        assert_eq!(selected.len(), 3);
+    }
+
+    #[test]
+    fn falls_back_to_largest_first() {
+        let utxos = utxos(&[10_000, 10_000, 10_000]);
+        let target = Amount::from_sat(15_000);
+        // no subset sums to the target within the cost of change
+        let selected = select_coins(&utxos, target, Amount::from_sat(100), fee_rate()).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert!(total_effective_value(&selected) >= target + Amount::from_sat(100));
+    }
+
+    #[test]
+    fn skips_unusable_utxos() {
+        let mut utxos = utxos(&[10_000, 100]);
+        utxos.push((
+            OutPoint::new(Txid::all_zeros(), 2),
+            TxOut {
+                value: Amount::from_sat(1_000_000),
+                script_pubkey: ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()),
+            },
+        ));
+
+        // the dust UTXO costs more to spend than it's worth, P2WSH is unsupported
+        assert!(select_coins(&utxos, Amount::from_sat(10_000), Amount::ZERO, fee_rate()).is_err());
+        let selected =
+            select_coins(&utxos, Amount::from_sat(9_000), Amount::ZERO, fee_rate()).unwrap();
+        assert_eq!(selected, vec![utxos[0].clone()]);
+    }
+
+    proptest! {
+        #[test]
+        fn selection_covers_target(
+            sats in prop::collection::vec(1_000u64..1_000_000, 1..20),
+            target in 1_000u64..2_000_000,
+            cost_of_change in 0u64..5_000,
+        ) {
+            let utxos = utxos(&sats);
+            let target = Amount::from_sat(target);
+            let cost_of_change = Amount::from_sat(cost_of_change);
+            let available: Amount = utxos
+                .iter()
+                .filter_map(|(_, utxo)| effective_value(utxo, fee_rate()))
+                .sum();
+
+            match select_coins(&utxos, target, cost_of_change, fee_rate()) {
+                Ok(selected) => {
+                    prop_assert!(total_effective_value(&selected) >= target);
+                    let mut out_points: Vec<_> = selected.iter().map(|(o, _)| o).collect();
+                    out_points.sort();
+                    out_points.dedup();
+                    prop_assert_eq!(out_points.len(), selected.len());
+                }
+                Err(_) => prop_assert!(available < target),
+            }
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use crate::{
+    chain::{mock::MockChain, rpc::RpcChainSource, ChainSource},
+    index::{CharmUtxo, Index, MAX_REORG_DEPTH},
+    spell::Spell,
+};
+use anyhow::{anyhow, Result};
+use bitcoin::{hashes::Hash, BlockHash, ScriptBuf, Txid};
+use bitcoincore_rpc::RpcApi;
+use charms_client::tx::extract_and_verify_spell;
+use charms_data::{App, TxId, UtxoId};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::RwLock,
+};
+
+/// Source of unconfirmed transactions for the [`Watcher`].
+pub trait MempoolSource: ChainSource {
+    /// IDs of the transactions in the mempool.
+    fn mempool_txids(&self) -> Result<Vec<Txid>>;
+}
+
+impl MempoolSource for RpcChainSource {
+    fn mempool_txids(&self) -> Result<Vec<Txid>> {
+        self.client
+            .get_raw_mempool()
+            .map_err(|e| anyhow!("getrawmempool failed: {}", e))
+    }
+}
+
+impl MempoolSource for MockChain {
+    fn mempool_txids(&self) -> Result<Vec<Txid>> {
+        Ok(self.mempool())
+    }
+}
+
+/// Notification about spells and charms.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Transaction with a correct spell seen in the mempool.
+    SpellSeen {
+        txid: Txid,
+        spell: Spell,
+        /// `script_pubkey`s of the outputs the spell puts charms in.
+        script_pubkeys: Vec<ScriptBuf>,
+    },
+    /// Transaction with a correct spell confirmed (included in a block of the best chain).
+    SpellConfirmed {
+        txid: Txid,
+        height: u32,
+        block_hash: BlockHash,
+        /// Outputs carrying charms created by the transaction.
+        charm_utxos: Vec<CharmUtxo>,
+    },
+    /// Indexed output carrying charms spent by a transaction: seen in the mempool (`height` is
+    /// `None`) or confirmed.
+    CharmUtxoSpent {
+        utxo: CharmUtxo,
+        txid: Txid,
+        height: Option<u32>,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::SpellSeen { .. } => "spell_seen",
+            Event::SpellConfirmed { .. } => "spell_confirmed",
+            Event::CharmUtxoSpent { .. } => "charm_utxo_spent",
+        }
+    }
+}
+
+/// Filter for [`Event`]s: an event matches if it involves charms of `app` (if set) and outputs
+/// locked by `script_pubkey` (if set).
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    pub app: Option<App>,
+    pub script_pubkey: Option<ScriptBuf>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        let (apps, script_pubkeys): (BTreeSet<&App>, BTreeSet<&ScriptBuf>) = match event {
+            Event::SpellSeen {
+                spell,
+                script_pubkeys,
+                ..
+            } => (
+                spell.apps.values().collect(),
+                script_pubkeys.iter().collect(),
+            ),
+            Event::SpellConfirmed { charm_utxos, .. } => (
+                charm_utxos
+                    .iter()
+                    .flat_map(|utxo| utxo.charms.keys())
+                    .collect(),
+                charm_utxos.iter().map(|utxo| &utxo.script_pubkey).collect(),
+            ),
+            Event::CharmUtxoSpent { utxo, .. } => (
+                utxo.charms.keys().collect(),
+                BTreeSet::from([&utxo.script_pubkey]),
+            ),
+        };
+        self.app.as_ref().is_none_or(|app| apps.contains(app))
+            && self
+                .script_pubkey
+                .as_ref()
+                .is_none_or(|script| script_pubkeys.contains(script))
+    }
+}
+
+/// Produces [`Event`]s from new blocks in the [`Index`] and new transactions in the mempool.
+pub struct Watcher {
+    /// Blocks events have been produced for (the last [`MAX_REORG_DEPTH`] of them), by height.
+    blocks: BTreeMap<u32, BlockHash>,
+    /// Mempool transactions events have been produced for.
+    mempool: BTreeSet<Txid>,
+}
+
+impl Watcher {
+    /// Watcher starting at the last indexed block: only later blocks produce events. If the index
+    /// is empty, the watcher starts at the last block indexed before the first poll.
+    pub fn new(index: &Index) -> Self {
+        Self {
+            blocks: index.tip().into_iter().collect(),
+            mempool: BTreeSet::new(),
+        }
+    }
+
+    /// Events for blocks indexed since the last poll (call after [`sync`](crate::index::sync))
+    /// and transactions that entered the mempool of `source`.
+    pub fn poll(
+        &mut self,
+        index: &RwLock<Index>,
+        source: &dyn MempoolSource,
+    ) -> Result<Vec<Event>> {
+        let mut events = self.block_events(&index.read().unwrap());
+
+        let mempool: BTreeSet<Txid> = source.mempool_txids()?.into_iter().collect();
+        for txid in mempool.difference(&self.mempool) {
+            // the transaction may have left the mempool by now
+            let Ok(tx) = source.get_transaction(txid) else {
+                continue;
+            };
+            let index = index.read().unwrap();
+            for tx_in in &tx.input {
+                let out_point = tx_in.previous_output;
+                let utxo_id = UtxoId(TxId(out_point.txid.to_byte_array()), out_point.vout);
+                if let Some(utxo) = index.utxo(&utxo_id).filter(|utxo| utxo.spent.is_none()) {
+                    events.push(Event::CharmUtxoSpent {
+                        utxo: utxo.clone(),
+                        txid: *txid,
+                        height: None,
+                    });
+                }
+            }
+            if let Ok(norm_spell) = extract_and_verify_spell(&tx, index.spell_vk()) {
+                let script_pubkeys = norm_spell
+                    .tx
+                    .outs
+                    .iter()
+                    .zip(&tx.output)
+                    .filter(|(n_charms, _)| !n_charms.is_empty())
+                    .map(|(_, tx_out)| tx_out.script_pubkey.clone())
+                    .collect();
+                events.push(Event::SpellSeen {
+                    txid: *txid,
+                    spell: Spell::denormalized(&norm_spell),
+                    script_pubkeys,
+                });
+            }
+        }
+        self.mempool = mempool;
+
+        Ok(events)
+    }
+
+    fn block_events(&mut self, index: &Index) -> Vec<Event> {
+        // forget blocks no longer in the best chain (after a reorg)
+        while let Some((&height, &hash)) = self.blocks.last_key_value() {
+            if index.block_hash(height) == Some(hash) {
+                break;
+            }
+            self.blocks.pop_last();
+        }
+
+        let Some((&last, _)) = self.blocks.last_key_value() else {
+            // nothing to start from yet: don't replay the blocks already indexed
+            self.blocks.extend(index.tip());
+            return vec![];
+        };
+        let mut events = vec![];
+        for delta in index.changes_from(last + 1) {
+            for &txid in &delta.spells {
+                let charm_utxos = delta
+                    .created
+                    .iter()
+                    .filter(|utxo| utxo.utxo_id.0 == TxId(txid.to_byte_array()))
+                    .cloned()
+                    .collect();
+                events.push(Event::SpellConfirmed {
+                    txid,
+                    height: delta.height,
+                    block_hash: delta.hash,
+                    charm_utxos,
+                });
+            }
+            for (utxo_id, txid) in &delta.spent {
+                if let Some(utxo) = index.utxo(utxo_id) {
+                    events.push(Event::CharmUtxoSpent {
+                        utxo: utxo.clone(),
+                        txid: *txid,
+                        height: Some(delta.height),
+                    });
+                }
+            }
+            self.blocks.insert(delta.height, delta.hash);
+        }
+        while self.blocks.len() > MAX_REORG_DEPTH as usize {
+            self.blocks.pop_first();
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{mock::test::spell_txs, Wallet},
+        index::sync,
+    };
+    use bitcoin::{Amount, OutPoint, Transaction};
+    use charms_client::MOCK_SPELL_VK;
+    use charms_data::{B32, TOKEN};
+    use tempfile::TempDir;
+
+    #[test]
+    fn produces_events() {
+        let dir = TempDir::new().unwrap();
+        let chain = MockChain::new();
+        let index = RwLock::new(Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap());
+        let mut watcher = Watcher::new(&index.read().unwrap());
+        let poll = |watcher: &mut Watcher| {
+            sync(&index, &chain).unwrap();
+            watcher.poll(&index, &chain).unwrap()
+        };
+
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let address = chain.new_address().unwrap();
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let [commit_tx, spell_tx] = spell_txs(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {address}
+    sats: 1000
+    charms:
+      $00: 100
+"#
+            ),
+        )
+        .unwrap();
+        let spell_txid = spell_tx.compute_txid();
+        assert!(poll(&mut watcher).is_empty());
+
+        chain
+            .broadcast(&chain.sign_tx(&commit_tx, &[]).unwrap())
+            .unwrap();
+        chain.broadcast(&spell_tx).unwrap();
+        let events = poll(&mut watcher);
+        assert!(matches!(
+            &events[..],
+            [Event::SpellSeen { txid, script_pubkeys, .. }]
+                if *txid == spell_txid && *script_pubkeys == [address.script_pubkey()]
+        ));
+        assert!(poll(&mut watcher).is_empty());
+
+        let height = chain.mine().unwrap();
+        let events = poll(&mut watcher);
+        let [Event::SpellConfirmed {
+            txid,
+            height: confirmed_height,
+            charm_utxos,
+            ..
+        }] = &events[..]
+        else {
+            panic!("unexpected events: {:?}", events);
+        };
+        assert_eq!((*txid, *confirmed_height), (spell_txid, height));
+        assert_eq!(charm_utxos.len(), 1);
+        let token_utxo = charm_utxos[0].utxo_id.clone();
+
+        // spending the charm UTXO is notified when seen and when confirmed
+        let funding = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let spend_tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: [OutPoint::new(spell_txid, 0), funding]
+                .into_iter()
+                .map(|previous_output| bitcoin::TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![bitcoin::TxOut {
+                value: Amount::from_sat(5_000),
+                script_pubkey: chain.new_change_address().unwrap().script_pubkey(),
+            }],
+        };
+        chain.broadcast(&spend_tx).unwrap();
+        let events = poll(&mut watcher);
+        assert!(matches!(
+            &events[..],
+            [Event::CharmUtxoSpent { utxo, height: None, .. }] if utxo.utxo_id == token_utxo
+        ));
+        let height = chain.mine().unwrap();
+        let events = poll(&mut watcher);
+        assert!(matches!(
+            &events[..],
+            [Event::CharmUtxoSpent { utxo, txid, height: Some(spent_height) }]
+                if utxo.utxo_id == token_utxo
+                    && *txid == spend_tx.compute_txid()
+                    && *spent_height == height
+        ));
+
+        // filters
+        let seen = Event::SpellSeen {
+            txid: spell_txid,
+            spell: crate::tx::spell(&spell_tx, crate::spell::ProofMode::Mock).unwrap(),
+            script_pubkeys: vec![address.script_pubkey()],
+        };
+        let other_app = App {
+            identity: B32([3; 32]),
+            ..token.clone()
+        };
+        for (filter, matches) in [
+            (EventFilter::default(), true),
+            (
+                EventFilter {
+                    app: Some(token.clone()),
+                    script_pubkey: Some(address.script_pubkey()),
+                },
+                true,
+            ),
+            (
+                EventFilter {
+                    app: Some(other_app),
+                    script_pubkey: None,
+                },
+                false,
+            ),
+            (
+                EventFilter {
+                    app: None,
+                    script_pubkey: Some(ScriptBuf::new()),
+                },
+                false,
+            ),
+        ] {
+            assert_eq!(filter.matches(&seen), matches);
+            assert_eq!(filter.matches(&events[0]), matches);
+        }
+    }
+
+    #[test]
+    fn starts_after_initial_sync() {
+        let dir = TempDir::new().unwrap();
+        let chain = MockChain::new();
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let address = chain.new_address().unwrap();
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let [commit_tx, spell_tx] = spell_txs(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {address}
+    sats: 1000
+    charms:
+      $00: 100
+"#
+            ),
+        )
+        .unwrap();
+        chain
+            .broadcast(&chain.sign_tx(&commit_tx, &[]).unwrap())
+            .unwrap();
+        chain.broadcast(&spell_tx).unwrap();
+        chain.mine().unwrap();
+
+        // the spell is confirmed before the watcher's first poll: no events for it
+        let index = RwLock::new(Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap());
+        let mut watcher = Watcher::new(&index.read().unwrap());
+        sync(&index, &chain).unwrap();
+        assert!(watcher.poll(&index, &chain).unwrap().is_empty());
+        assert!(index.read().unwrap().tip().is_some());
+
+        chain.mine().unwrap();
+        sync(&index, &chain).unwrap();
+        assert!(watcher.poll(&index, &chain).unwrap().is_empty());
+    }
+}
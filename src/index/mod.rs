@@ -0,0 +1,1090 @@
+pub(crate) mod store;
+
+use crate::{
+    chain::{mock::MockChain, rpc::RpcChainSource},
+    index::store::Log,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use bitcoin::{hashes::Hash, Address, BlockHash, Network, Script, ScriptBuf, Transaction, Txid};
+use bitcoincore_rpc::RpcApi;
+use charms_client::{charms, tx::extract_and_verify_spell};
+use charms_data::{
+    metadata::{ref_nft_app, TokenMetadata},
+    sum_token_amount, App, Charms, TokenAmount, TxId, UtxoId, NFT, TOKEN,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    sync::RwLock,
+};
+
+/// Source of blocks for the [`Index`]: bitcoind ([`RpcChainSource`]) or the chain simulator
+/// ([`MockChain`]).
+pub trait BlockSource {
+    /// Height of the best chain tip.
+    fn tip_height(&self) -> Result<u32>;
+
+    /// Hash of the block at `height` in the best chain.
+    fn block_hash(&self, height: u32) -> Result<BlockHash>;
+
+    /// Hash of the previous block and the transactions (in block order) of the block with hash
+    /// `hash`.
+    fn block(&self, hash: &BlockHash) -> Result<(BlockHash, Vec<Transaction>)>;
+}
+
+impl BlockSource for RpcChainSource {
+    fn tip_height(&self) -> Result<u32> {
+        let height = self
+            .client
+            .get_block_count()
+            .map_err(|e| anyhow!("getblockcount failed: {}", e))?;
+        Ok(height as u32)
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash> {
+        self.client
+            .get_block_hash(height as u64)
+            .map_err(|e| anyhow!("getblockhash {} failed: {}", height, e))
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<(BlockHash, Vec<Transaction>)> {
+        let block = self
+            .client
+            .get_block(hash)
+            .map_err(|e| anyhow!("getblock {} failed: {}", hash, e))?;
+        Ok((block.header.prev_blockhash, block.txdata))
+    }
+}
+
+impl BlockSource for MockChain {
+    fn tip_height(&self) -> Result<u32> {
+        Ok(MockChain::tip_height(self))
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash> {
+        MockChain::block_hash(self, height).ok_or(anyhow!("no block at height {}", height))
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<(BlockHash, Vec<Transaction>)> {
+        let height = (1..=MockChain::tip_height(self))
+            .rev()
+            .find(|&height| MockChain::block_hash(self, height) == Some(*hash))
+            .ok_or(anyhow!("block {} not found", hash))?;
+        let prev_hash = MockChain::block_hash(self, height - 1).unwrap_or(BlockHash::all_zeros());
+        let txs = MockChain::block(self, height).expect("block should exist");
+        Ok((prev_hash, txs))
+    }
+}
+
+/// Transaction output carrying charms, as indexed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CharmUtxo {
+    pub utxo_id: UtxoId,
+    /// Value of the output in sats.
+    pub sats: u64,
+    pub script_pubkey: ScriptBuf,
+    pub charms: Charms,
+    /// Height of the block the output was created in.
+    pub height: u32,
+    /// Spending transaction (`None` if the output is unspent).
+    pub spent: Option<Spent>,
+}
+
+/// Spending of a [`CharmUtxo`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Spent {
+    /// ID of the spending transaction.
+    pub txid: Txid,
+    /// Height of the block the spending transaction is in.
+    pub height: u32,
+}
+
+/// Index status.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexStatus {
+    /// Height of the last indexed block.
+    pub height: Option<u32>,
+    /// Hash of the last indexed block.
+    pub hash: Option<BlockHash>,
+    /// Number of indexed outputs carrying charms, including ones spent in the last
+    /// [`MAX_REORG_DEPTH`] blocks.
+    pub charm_utxos: usize,
+    /// Number of unspent indexed outputs carrying charms.
+    pub unspent_charm_utxos: usize,
+}
+
+/// Unspent charms of an app, as indexed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AppInfo {
+    pub app: App,
+    /// Total amount of the token in unspent outputs (`None` if the app is not a token).
+    pub supply: Option<TokenAmount>,
+    /// Number of unspent outputs with the NFT (`None` if the app is not an NFT).
+    pub nfts: Option<usize>,
+    /// Number of unspent outputs with charms of the app.
+    pub utxos: usize,
+    /// Number of distinct `script_pubkey`s of these outputs.
+    pub holders: usize,
+    /// Metadata (see CHIP-0420): data of the app's reference NFT, if it is in an unspent indexed
+    /// output and is valid token metadata.
+    pub metadata: Option<TokenMetadata>,
+}
+
+/// Holder of unspent charms of an app: outputs with the same `script_pubkey`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Holder {
+    pub script_pubkey: ScriptBuf,
+    /// Address of `script_pubkey` (`None` if it has no address form).
+    pub address: Option<String>,
+    /// Amount of the token held (`None` if the app is not a token).
+    pub amount: Option<TokenAmount>,
+    /// Number of outputs with the NFT held (`None` if the app is not an NFT).
+    pub nfts: Option<usize>,
+    /// Number of outputs held.
+    pub utxos: usize,
+}
+
+/// Maximum number of blocks that can be disconnected in a chain reorganization: undo data is kept
+/// for this many of the last indexed blocks. Outputs spent in earlier blocks are forgotten.
+pub const MAX_REORG_DEPTH: u32 = 100;
+
+/// Number of records appended to the index [`Log`] after which it is compacted: rewritten as a
+/// snapshot of the index.
+const COMPACT_AFTER_RECORDS: usize = 1000;
+
+/// Changes a block makes to the index: also the data to undo them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockDelta {
+    pub height: u32,
+    pub hash: BlockHash,
+    /// IDs of transactions with correct spells in the block.
+    #[serde(default)]
+    pub spells: Vec<Txid>,
+    /// Outputs carrying charms created in the block.
+    pub created: Vec<CharmUtxo>,
+    /// Outputs carrying charms spent in the block, with the spending transaction IDs.
+    pub spent: Vec<(UtxoId, Txid)>,
+}
+
+/// Record in the index [`Log`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Record {
+    /// Parameters the index is built with: always the first record.
+    Init { spell_vk: String, start_height: u32 },
+    /// Block added to the index.
+    Connect(BlockDelta),
+    /// Last indexed block removed from the index (no longer in the best chain).
+    Disconnect { height: u32, hash: BlockHash },
+    /// State of the index (replacing the one built from the previous records), written when the
+    /// log is compacted.
+    Snapshot {
+        blocks: Vec<(u32, BlockHash)>,
+        undo: Vec<BlockDelta>,
+        utxos: Vec<CharmUtxo>,
+    },
+}
+
+/// Index of transaction outputs carrying charms: built by scanning blocks (see [`sync`]) and
+/// verifying their spells. Persisted in a directory (as a log of changes made by each block,
+/// periodically compacted into a snapshot).
+///
+/// Follows chain reorganizations (up to [`MAX_REORG_DEPTH`] blocks deep): blocks no longer in the
+/// best chain are disconnected, undoing their changes.
+pub struct Index {
+    log: Log<Record>,
+    /// Number of records in the log after the last snapshot.
+    log_records: usize,
+    spell_vk: String,
+    start_height: u32,
+    /// Hashes of the last [`MAX_REORG_DEPTH`] + 1 indexed blocks by height.
+    blocks: BTreeMap<u32, BlockHash>,
+    /// Changes made by the last [`MAX_REORG_DEPTH`] indexed blocks, by height.
+    undo: BTreeMap<u32, BlockDelta>,
+    utxos: BTreeMap<UtxoId, CharmUtxo>,
+    unspent: UnspentIndex,
+}
+
+/// Unspent indexed outputs by app and by `script_pubkey`.
+#[derive(Default)]
+struct UnspentIndex {
+    by_app: BTreeMap<App, BTreeSet<UtxoId>>,
+    by_script: BTreeMap<ScriptBuf, BTreeSet<UtxoId>>,
+}
+
+impl UnspentIndex {
+    fn insert(&mut self, utxo: &CharmUtxo) {
+        for app in utxo.charms.keys() {
+            let utxo_ids = self.by_app.entry(app.clone()).or_default();
+            utxo_ids.insert(utxo.utxo_id.clone());
+        }
+        let utxo_ids = self
+            .by_script
+            .entry(utxo.script_pubkey.clone())
+            .or_default();
+        utxo_ids.insert(utxo.utxo_id.clone());
+    }
+
+    fn remove(&mut self, utxo: &CharmUtxo) {
+        for app in utxo.charms.keys() {
+            remove_from(&mut self.by_app, app, &utxo.utxo_id);
+        }
+        remove_from(&mut self.by_script, &utxo.script_pubkey, &utxo.utxo_id);
+    }
+}
+
+/// Remove `utxo_id` from the set at `key` (and the set if it becomes empty).
+fn remove_from<K: Ord>(sets: &mut BTreeMap<K, BTreeSet<UtxoId>>, key: &K, utxo_id: &UtxoId) {
+    if let Some(utxo_ids) = sets.get_mut(key) {
+        utxo_ids.remove(utxo_id);
+        if utxo_ids.is_empty() {
+            sets.remove(key);
+        }
+    }
+}
+
+impl Index {
+    /// Open the index in directory `dir` (created if it does not exist).
+    /// Spells are verified with `spell_vk`. Blocks are indexed starting from `start_height`:
+    /// charms created in earlier blocks are not indexed.
+    pub fn open(dir: &Path, spell_vk: &str, start_height: u32) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let (mut log, records) = Log::open(&dir.join("index.log"))?;
+        let mut records = records.into_iter();
+        match records.next() {
+            None => log.append(&Record::Init {
+                spell_vk: spell_vk.to_string(),
+                start_height,
+            })?,
+            Some(Record::Init {
+                spell_vk: index_spell_vk,
+                start_height: index_start_height,
+            }) => ensure!(
+                index_spell_vk == spell_vk && index_start_height == start_height,
+                "index in {} is built with spell VK {} from height {}, not {} from height {}",
+                dir.display(),
+                index_spell_vk,
+                index_start_height,
+                spell_vk,
+                start_height
+            ),
+            Some(_) => bail!("index in {} has no init record", dir.display()),
+        }
+
+        let mut index = Self {
+            log,
+            log_records: 0,
+            spell_vk: spell_vk.to_string(),
+            start_height,
+            blocks: BTreeMap::new(),
+            undo: BTreeMap::new(),
+            utxos: BTreeMap::new(),
+            unspent: UnspentIndex::default(),
+        };
+        for record in records {
+            index.log_records += 1;
+            match record {
+                Record::Connect(delta) => index.apply(delta),
+                Record::Disconnect { height, hash } => {
+                    ensure!(
+                        index.tip() == Some((height, hash)),
+                        "disconnected block {} at height {} is not the last indexed block in {}",
+                        hash,
+                        height,
+                        dir.display()
+                    );
+                    index.unapply()?;
+                }
+                Record::Snapshot {
+                    blocks,
+                    undo,
+                    utxos,
+                } => index.restore(blocks, undo, utxos),
+                Record::Init { .. } => bail!("unexpected init record in {}", dir.display()),
+            }
+        }
+        if index.log_records > COMPACT_AFTER_RECORDS {
+            index.compact()?;
+        }
+        Ok(index)
+    }
+
+    /// Replace the state of the index with the one from a snapshot.
+    fn restore(
+        &mut self,
+        blocks: Vec<(u32, BlockHash)>,
+        undo: Vec<BlockDelta>,
+        utxos: Vec<CharmUtxo>,
+    ) {
+        self.blocks = blocks.into_iter().collect();
+        self.undo = undo
+            .into_iter()
+            .map(|delta| (delta.height, delta))
+            .collect();
+        self.unspent = UnspentIndex::default();
+        for utxo in utxos.iter().filter(|utxo| utxo.spent.is_none()) {
+            self.unspent.insert(utxo);
+        }
+        self.utxos = utxos
+            .into_iter()
+            .map(|utxo| (utxo.utxo_id.clone(), utxo))
+            .collect();
+        self.log_records = 0;
+    }
+
+    /// Rewrite the log as a snapshot of the index, so that it doesn't grow with every block.
+    fn compact(&mut self) -> Result<()> {
+        self.log.rewrite(&[
+            Record::Init {
+                spell_vk: self.spell_vk.clone(),
+                start_height: self.start_height,
+            },
+            Record::Snapshot {
+                blocks: self
+                    .blocks
+                    .iter()
+                    .map(|(&height, &hash)| (height, hash))
+                    .collect(),
+                undo: self.undo.values().cloned().collect(),
+                utxos: self.utxos.values().cloned().collect(),
+            },
+        ])?;
+        self.log_records = 0;
+        Ok(())
+    }
+
+    /// Count a record appended to the log (and applied), compacting the log if it has grown too
+    /// long.
+    fn logged(&mut self) -> Result<()> {
+        self.log_records += 1;
+        if self.log_records > COMPACT_AFTER_RECORDS {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Height of the last indexed block (`None` if no blocks are indexed yet).
+    pub fn height(&self) -> Option<u32> {
+        self.blocks.last_key_value().map(|(&height, _)| height)
+    }
+
+    /// Height and hash of the last indexed block.
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.blocks
+            .last_key_value()
+            .map(|(&height, &hash)| (height, hash))
+    }
+
+    /// Hash of the indexed block at `height`. Available for the last [`MAX_REORG_DEPTH`] + 1
+    /// blocks only.
+    pub fn block_hash(&self, height: u32) -> Option<BlockHash> {
+        self.blocks.get(&height).copied()
+    }
+
+    /// Spell verification key spells are verified with.
+    pub fn spell_vk(&self) -> &str {
+        &self.spell_vk
+    }
+
+    /// Changes made by indexed blocks from `height` on, in block order. Available for the last
+    /// [`MAX_REORG_DEPTH`] blocks only.
+    pub fn changes_from(&self, height: u32) -> impl Iterator<Item = &BlockDelta> {
+        self.undo.range(height..).map(|(_, delta)| delta)
+    }
+
+    fn next_height(&self) -> u32 {
+        self.height().map_or(self.start_height, |height| height + 1)
+    }
+
+    pub fn status(&self) -> IndexStatus {
+        IndexStatus {
+            height: self.height(),
+            hash: self.tip().map(|(_, hash)| hash),
+            charm_utxos: self.utxos.len(),
+            unspent_charm_utxos: self
+                .utxos
+                .values()
+                .filter(|utxo| utxo.spent.is_none())
+                .count(),
+        }
+    }
+
+    /// Indexed output carrying charms: unspent, or spent in the last [`MAX_REORG_DEPTH`] blocks.
+    pub fn utxo(&self, utxo_id: &UtxoId) -> Option<&CharmUtxo> {
+        self.utxos.get(utxo_id)
+    }
+
+    /// Unspent indexed outputs with charms of `app`.
+    pub fn app_utxos(&self, app: &App) -> Vec<&CharmUtxo> {
+        self.unspent_utxos(self.unspent.by_app.get(app))
+    }
+
+    /// Unspent indexed outputs locked by `script_pubkey`.
+    pub fn script_utxos(&self, script_pubkey: &Script) -> Vec<&CharmUtxo> {
+        self.unspent_utxos(self.unspent.by_script.get(script_pubkey))
+    }
+
+    fn unspent_utxos(&self, utxo_ids: Option<&BTreeSet<UtxoId>>) -> Vec<&CharmUtxo> {
+        utxo_ids
+            .into_iter()
+            .flatten()
+            .map(|utxo_id| &self.utxos[utxo_id])
+            .collect()
+    }
+
+    /// Unspent charms of `app`: token supply, number of NFTs and holders, and metadata.
+    /// `None` if there are no unspent indexed outputs with charms of `app`.
+    pub fn app_info(&self, app: &App) -> Result<Option<AppInfo>> {
+        let utxos = self.app_utxos(app);
+        if utxos.is_empty() {
+            return Ok(None);
+        }
+        let supply = match app.tag {
+            TOKEN => Some(sum_token_amount(
+                app,
+                utxos.iter().map(|utxo| &utxo.charms),
+            )?),
+            _ => None,
+        };
+        let holders: BTreeSet<&ScriptBuf> = utxos.iter().map(|utxo| &utxo.script_pubkey).collect();
+        Ok(Some(AppInfo {
+            app: app.clone(),
+            supply,
+            nfts: (app.tag == NFT).then_some(utxos.len()),
+            utxos: utxos.len(),
+            holders: holders.len(),
+            metadata: self.metadata(app),
+        }))
+    }
+
+    /// Metadata of `app` from its reference NFT (see CHIP-0420), if there's an unspent indexed
+    /// output with it.
+    fn metadata(&self, app: &App) -> Option<TokenMetadata> {
+        let ref_nft = ref_nft_app(app);
+        self.app_utxos(&ref_nft)
+            .into_iter()
+            .find_map(|utxo| TokenMetadata::from_data(&utxo.charms[&ref_nft]).ok())
+    }
+
+    /// Holders of unspent charms of `app`, largest first. Addresses are for `network`.
+    pub fn holders(&self, app: &App, network: Network) -> Result<Vec<Holder>> {
+        let mut utxos_by_script: BTreeMap<&ScriptBuf, Vec<&CharmUtxo>> = BTreeMap::new();
+        for utxo in self.app_utxos(app) {
+            let utxos = utxos_by_script.entry(&utxo.script_pubkey).or_default();
+            utxos.push(utxo);
+        }
+        let mut holders = utxos_by_script
+            .into_iter()
+            .map(|(script_pubkey, utxos)| {
+                let amount = match app.tag {
+                    TOKEN => Some(sum_token_amount(
+                        app,
+                        utxos.iter().map(|utxo| &utxo.charms),
+                    )?),
+                    _ => None,
+                };
+                Ok(Holder {
+                    script_pubkey: script_pubkey.clone(),
+                    address: Address::from_script(script_pubkey, network)
+                        .ok()
+                        .map(|address| address.to_string()),
+                    amount,
+                    nfts: (app.tag == NFT).then_some(utxos.len()),
+                    utxos: utxos.len(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        holders.sort_by(|a, b| (b.amount, b.utxos).cmp(&(a.amount, a.utxos)));
+        Ok(holders)
+    }
+
+    /// Add the block at `height` with hash `hash`, previous block hash `prev_hash` and
+    /// transactions `txs` to the index. The block must be the child of the last indexed block.
+    pub fn connect(
+        &mut self,
+        height: u32,
+        hash: BlockHash,
+        prev_hash: BlockHash,
+        txs: &[Transaction],
+    ) -> Result<()> {
+        ensure!(
+            height == self.next_height(),
+            "expected block at height {}, got {}",
+            self.next_height(),
+            height
+        );
+        if let Some((_, tip_hash)) = self.tip() {
+            ensure!(
+                prev_hash == tip_hash,
+                "block {} is not a child of the last indexed block {}",
+                hash,
+                tip_hash
+            );
+        }
+        let delta = self.block_delta(height, hash, txs);
+        self.commit(delta)
+    }
+
+    /// Whether the block at `height` with previous block hash `prev_hash` is the next block to
+    /// index.
+    fn is_next(&self, height: u32, prev_hash: BlockHash) -> bool {
+        height == self.next_height() && self.tip().is_none_or(|(_, tip_hash)| tip_hash == prev_hash)
+    }
+
+    /// Add the block with changes `delta` (computed by [`Index::block_delta`] for the next block
+    /// to index) to the index.
+    fn commit(&mut self, delta: BlockDelta) -> Result<()> {
+        self.log.append(&Record::Connect(delta.clone()))?;
+        self.apply(delta);
+        self.logged()
+    }
+
+    /// Remove the last indexed block from the index, undoing its changes.
+    pub fn disconnect(&mut self) -> Result<()> {
+        let (height, hash) = self.tip().ok_or(anyhow!("no blocks to disconnect"))?;
+        ensure!(
+            self.undo.contains_key(&height),
+            "can't disconnect block {} at height {}: reorgs deeper than {} blocks are not \
+            supported, reindex from scratch",
+            hash,
+            height,
+            MAX_REORG_DEPTH
+        );
+        self.log.append(&Record::Disconnect { height, hash })?;
+        self.unapply()?;
+        self.logged()
+    }
+
+    /// Changes the block makes to the index: outputs carrying charms created by correct spells,
+    /// and indexed outputs spent (by any transactions, with or without spells).
+    fn block_delta(&self, height: u32, hash: BlockHash, txs: &[Transaction]) -> BlockDelta {
+        // outputs created in the block can be spent in the same block
+        let mut created: BTreeMap<UtxoId, CharmUtxo> = BTreeMap::new();
+        let mut spent = vec![];
+        let mut spells = vec![];
+        for tx in txs {
+            let txid = tx.compute_txid();
+            for tx_in in &tx.input {
+                let out_point = tx_in.previous_output;
+                let utxo_id = UtxoId(TxId(out_point.txid.to_byte_array()), out_point.vout);
+                let unspent = created.contains_key(&utxo_id)
+                    || self
+                        .utxos
+                        .get(&utxo_id)
+                        .is_some_and(|utxo| utxo.spent.is_none());
+                if unspent {
+                    spent.push((utxo_id, txid));
+                }
+            }
+
+            let Ok(spell) = extract_and_verify_spell(tx, &self.spell_vk) else {
+                continue;
+            };
+            spells.push(txid);
+            for (n_charms, (tx_out, vout)) in spell.tx.outs.iter().zip(tx.output.iter().zip(0..)) {
+                if n_charms.is_empty() {
+                    continue;
+                }
+                let utxo_id = UtxoId(TxId(txid.to_byte_array()), vout);
+                created.insert(
+                    utxo_id.clone(),
+                    CharmUtxo {
+                        utxo_id,
+                        sats: tx_out.value.to_sat(),
+                        script_pubkey: tx_out.script_pubkey.clone(),
+                        charms: charms(&spell, n_charms),
+                        height,
+                        spent: None,
+                    },
+                );
+            }
+        }
+        BlockDelta {
+            height,
+            hash,
+            spells,
+            created: created.into_values().collect(),
+            spent,
+        }
+    }
+
+    fn apply(&mut self, delta: BlockDelta) {
+        for utxo in &delta.created {
+            self.unspent.insert(utxo);
+            self.utxos.insert(utxo.utxo_id.clone(), utxo.clone());
+        }
+        for (utxo_id, txid) in &delta.spent {
+            if let Some(utxo) = self.utxos.get_mut(utxo_id) {
+                self.unspent.remove(utxo);
+                utxo.spent = Some(Spent {
+                    txid: *txid,
+                    height: delta.height,
+                });
+            }
+        }
+        self.blocks.insert(delta.height, delta.hash);
+        self.undo.insert(delta.height, delta);
+        while self.undo.len() > MAX_REORG_DEPTH as usize {
+            let (_, delta) = self
+                .undo
+                .pop_first()
+                .expect("undo data should not be empty");
+            // these outputs can no longer become unspent
+            for (utxo_id, _) in delta.spent {
+                self.utxos.remove(&utxo_id);
+            }
+        }
+        while self.blocks.len() > MAX_REORG_DEPTH as usize + 1 {
+            self.blocks.pop_first();
+        }
+    }
+
+    /// Undo the changes made by the last indexed block.
+    fn unapply(&mut self) -> Result<()> {
+        let (height, _) = self.blocks.pop_last().ok_or(anyhow!("no blocks to undo"))?;
+        let delta = self
+            .undo
+            .remove(&height)
+            .ok_or(anyhow!("no undo data for block at height {}", height))?;
+        for (utxo_id, _) in delta.spent {
+            if let Some(utxo) = self.utxos.get_mut(&utxo_id) {
+                utxo.spent = None;
+                self.unspent.insert(utxo);
+            }
+        }
+        for utxo in delta.created {
+            if let Some(utxo) = self.utxos.remove(&utxo.utxo_id) {
+                self.unspent.remove(&utxo);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bring `index` up to date with the best chain of `source`: disconnect indexed blocks no longer
+/// in the best chain (after a reorg), then index new blocks up to the tip. Blocks are fetched and
+/// their spells verified without holding the write lock on `index`. Returns the number of blocks
+/// indexed.
+pub fn sync(index: &RwLock<Index>, source: &dyn BlockSource) -> Result<u32> {
+    let mut connected = 0;
+    'reorg: loop {
+        let tip_height = source.tip_height()?;
+        loop {
+            let Some((height, hash)) = index.read().unwrap().tip() else {
+                break;
+            };
+            if height <= tip_height && source.block_hash(height)? == hash {
+                break;
+            }
+            tracing::warn!("disconnecting block {} at height {}: reorg", hash, height);
+            index.write().unwrap().disconnect()?;
+        }
+
+        loop {
+            let height = index.read().unwrap().next_height();
+            if height > tip_height {
+                return Ok(connected);
+            }
+            let hash = source.block_hash(height)?;
+            let (prev_hash, txs) = source.block(&hash)?;
+            let delta = {
+                let index = index.read().unwrap();
+                if !index.is_next(height, prev_hash) {
+                    // reorg while syncing
+                    continue 'reorg;
+                }
+                index.block_delta(height, hash, &txs)
+            };
+            let mut index = index.write().unwrap();
+            if !index.is_next(height, prev_hash) {
+                // the index changed while the block was being verified
+                continue 'reorg;
+            }
+            index.commit(delta)?;
+            connected += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chain::{mock::test::cast, ChainSource, Wallet};
+    use bitcoin::{Amount, OutPoint};
+    use charms_client::MOCK_SPELL_VK;
+    use charms_data::{App, Data, TokenAmount, B32, NFT, TOKEN};
+    use tempfile::TempDir;
+
+    fn utxo_id(tx: &Transaction, vout: u32) -> UtxoId {
+        UtxoId(TxId(tx.compute_txid().to_byte_array()), vout)
+    }
+
+    #[test]
+    fn indexes_charm_utxos() {
+        let dir = TempDir::new().unwrap();
+        let chain = MockChain::new();
+        let address = || chain.new_address().unwrap().to_string();
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let nft = App {
+            tag: NFT,
+            ..token.clone()
+        };
+
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let mint_tx = cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {nft}
+  $01: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {addr_0}
+    sats: 1000
+    charms:
+      $00:
+        ticker: TOAD
+  - address: {addr_1}
+    sats: 1000
+    charms:
+      $01: 100
+"#,
+                addr_0 = address(),
+                addr_1 = address(),
+            ),
+        )
+        .unwrap();
+
+        let index = RwLock::new(Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap());
+        assert_eq!(sync(&index, &chain).unwrap(), chain.tip_height());
+        assert_eq!(sync(&index, &chain).unwrap(), 0);
+        {
+            let index = index.read().unwrap();
+            let nft_utxo = index.utxo(&utxo_id(&mint_tx, 0)).unwrap();
+            assert_eq!(nft_utxo.sats, 1000);
+            assert!(nft_utxo.charms.contains_key(&nft));
+            assert_eq!(nft_utxo.height, chain.tip_height());
+            let token_utxo = index.utxo(&utxo_id(&mint_tx, 1)).unwrap();
+            assert_eq!(token_utxo.charms[&token], Data::from(&TokenAmount(100)));
+            // change
+            assert!(index.utxo(&utxo_id(&mint_tx, 2)).is_none());
+        }
+
+        // spending without a spell destroys the charms: the output is spent all the same
+        let funding = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let spend_tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: [OutPoint::new(mint_tx.compute_txid(), 1), funding]
+                .into_iter()
+                .map(|previous_output| bitcoin::TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![bitcoin::TxOut {
+                value: Amount::from_sat(5_000),
+                script_pubkey: chain.new_change_address().unwrap().script_pubkey(),
+            }],
+        };
+        chain.broadcast(&spend_tx).unwrap();
+        chain.mine().unwrap();
+        assert_eq!(sync(&index, &chain).unwrap(), 2);
+
+        let status = index.read().unwrap().status();
+        assert_eq!(
+            status,
+            IndexStatus {
+                height: Some(chain.tip_height()),
+                hash: chain.block_hash(chain.tip_height()),
+                charm_utxos: 2,
+                unspent_charm_utxos: 1,
+            }
+        );
+        assert_eq!(
+            index
+                .read()
+                .unwrap()
+                .utxo(&utxo_id(&mint_tx, 1))
+                .unwrap()
+                .spent,
+            Some(Spent {
+                txid: spend_tx.compute_txid(),
+                height: chain.tip_height()
+            })
+        );
+
+        // the index is persisted
+        drop(index);
+        let index = Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap();
+        assert_eq!(index.status(), status);
+        assert!(Index::open(dir.path(), crate::SPELL_VK, 1).is_err());
+
+        // outputs spent more than MAX_REORG_DEPTH blocks ago are forgotten
+        let index = RwLock::new(index);
+        for _ in 0..MAX_REORG_DEPTH {
+            chain.mine().unwrap();
+        }
+        sync(&index, &chain).unwrap();
+        let index = index.into_inner().unwrap();
+        assert!(index.utxo(&utxo_id(&mint_tx, 1)).is_none());
+        assert!(index.utxo(&utxo_id(&mint_tx, 0)).is_some());
+        let status = index.status();
+        assert_eq!((status.charm_utxos, status.unspent_charm_utxos), (1, 1));
+    }
+
+    #[test]
+    fn tracks_app_supply_and_holders() {
+        let dir = TempDir::new().unwrap();
+        let chain = MockChain::new();
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let nft = App {
+            tag: NFT,
+            ..token.clone()
+        };
+        let (alice, bob) = (chain.new_address().unwrap(), chain.new_address().unwrap());
+
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {nft}
+  $01: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {alice}
+    sats: 1000
+    charms:
+      $00:
+        ticker: TOAD
+        decimals: 2
+  - address: {alice}
+    sats: 1000
+    charms:
+      $01: 100
+  - address: {bob}
+    sats: 1000
+    charms:
+      $01: 50
+  - address: {alice}
+    sats: 1000
+    charms:
+      $01: 20
+"#
+            ),
+        )
+        .unwrap();
+
+        let index = RwLock::new(Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap());
+        sync(&index, &chain).unwrap();
+        let index = index.into_inner().unwrap();
+
+        let token_info = index.app_info(&token).unwrap().unwrap();
+        assert_eq!(token_info.supply, Some(TokenAmount(170)));
+        assert_eq!(token_info.nfts, None);
+        assert_eq!((token_info.utxos, token_info.holders), (3, 2));
+        let metadata = token_info.metadata.unwrap();
+        assert_eq!(metadata.ticker.as_deref(), Some("TOAD"));
+        assert_eq!(metadata.decimals, Some(2));
+
+        let nft_info = index.app_info(&nft).unwrap().unwrap();
+        assert_eq!((nft_info.supply, nft_info.nfts), (None, Some(1)));
+
+        let holders = index.holders(&token, Network::Regtest).unwrap();
+        assert_eq!(
+            holders
+                .iter()
+                .map(|holder| (holder.address.clone().unwrap(), holder.amount, holder.utxos))
+                .collect::<Vec<_>>(),
+            vec![
+                (alice.to_string(), Some(TokenAmount(120)), 2),
+                (bob.to_string(), Some(TokenAmount(50)), 1),
+            ]
+        );
+
+        assert_eq!(index.script_utxos(&alice.script_pubkey()).len(), 3);
+        assert_eq!(index.script_utxos(&bob.script_pubkey()).len(), 1);
+        let other = App {
+            identity: B32([3; 32]),
+            ..token
+        };
+        assert_eq!(index.app_info(&other).unwrap(), None);
+        assert_eq!(index.holders(&other, Network::Regtest).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn follows_reorgs() {
+        let dir = TempDir::new().unwrap();
+        let chain = MockChain::new();
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let in_utxo = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let mint_tx = cast(
+            &chain,
+            &format!(
+                r#"
+version: 2
+apps:
+  $00: {token}
+ins:
+  - utxo_id: {in_utxo}
+    charms: {{}}
+outs:
+  - address: {addr}
+    sats: 1000
+    charms:
+      $00: 100
+"#,
+                addr = chain.new_address().unwrap(),
+            ),
+        )
+        .unwrap();
+        let funding = chain.fund(Amount::from_sat(5_000)).unwrap();
+        let spend_tx = chain
+            .sign_tx(
+                &Transaction {
+                    version: bitcoin::transaction::Version::TWO,
+                    lock_time: bitcoin::absolute::LockTime::ZERO,
+                    input: [OutPoint::new(mint_tx.compute_txid(), 0), funding]
+                        .into_iter()
+                        .map(|previous_output| bitcoin::TxIn {
+                            previous_output,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    output: vec![bitcoin::TxOut {
+                        value: Amount::from_sat(5_000),
+                        script_pubkey: chain.new_change_address().unwrap().script_pubkey(),
+                    }],
+                },
+                &[],
+            )
+            .unwrap();
+        chain.broadcast(&spend_tx).unwrap();
+        chain.mine().unwrap();
+
+        let index = RwLock::new(Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap());
+        sync(&index, &chain).unwrap();
+        let token_utxo = utxo_id(&mint_tx, 0);
+        assert!(index
+            .read()
+            .unwrap()
+            .utxo(&token_utxo)
+            .unwrap()
+            .spent
+            .is_some());
+
+        // the spending transaction is dropped from the best chain
+        chain.reorg(1, &[spend_tx.compute_txid()]).unwrap();
+        chain.mine().unwrap();
+        assert_eq!(sync(&index, &chain).unwrap(), 1);
+        {
+            let index = index.read().unwrap();
+            assert_eq!(index.utxo(&token_utxo).unwrap().spent, None);
+            assert_eq!(index.status().hash, chain.block_hash(chain.tip_height()));
+        }
+
+        // so is the spell transaction: its charms are gone
+        chain.reorg(3, &[mint_tx.compute_txid()]).unwrap();
+        chain.mine().unwrap();
+        assert_eq!(sync(&index, &chain).unwrap(), 1);
+        assert_eq!(index.read().unwrap().app_info(&token).unwrap(), None);
+        let status = index.read().unwrap().status();
+        assert_eq!(
+            status,
+            IndexStatus {
+                height: Some(chain.tip_height()),
+                hash: chain.block_hash(chain.tip_height()),
+                charm_utxos: 0,
+                unspent_charm_utxos: 0,
+            }
+        );
+
+        // disconnected blocks are persisted too
+        drop(index);
+        let index = Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap();
+        assert_eq!(index.status(), status);
+    }
+
+    #[test]
+    fn connects_blocks_in_order() {
+        let dir = TempDir::new().unwrap();
+        let mut index = Index::open(dir.path(), MOCK_SPELL_VK, 10).unwrap();
+        let hash = |n: u8| BlockHash::from_byte_array([n; 32]);
+        assert!(index.connect(11, hash(11), hash(10), &[]).is_err());
+        index.connect(10, hash(10), hash(9), &[]).unwrap();
+        assert!(index.connect(11, hash(11), hash(1), &[]).is_err());
+        index.connect(11, hash(11), hash(10), &[]).unwrap();
+        assert_eq!(index.tip(), Some((11, hash(11))));
+        index.disconnect().unwrap();
+        index.disconnect().unwrap();
+        assert!(index.disconnect().is_err());
+
+        // undo data is kept for the last MAX_REORG_DEPTH blocks only
+        let hash = |n: u32| BlockHash::hash(&n.to_le_bytes());
+        for height in 10..=10 + MAX_REORG_DEPTH {
+            index
+                .connect(height, hash(height), hash(height - 1), &[])
+                .unwrap();
+        }
+        for _ in 0..MAX_REORG_DEPTH {
+            index.disconnect().unwrap();
+        }
+        assert!(index.disconnect().is_err());
+        assert_eq!(index.tip(), Some((10, hash(10))));
+    }
+
+    #[test]
+    fn compacts_the_log() {
+        let dir = TempDir::new().unwrap();
+        let mut index = Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap();
+        let hash = |n: u32| BlockHash::hash(&n.to_le_bytes());
+        let tip_height = COMPACT_AFTER_RECORDS as u32 + 10;
+        for height in 1..=tip_height {
+            index
+                .connect(height, hash(height), hash(height - 1), &[])
+                .unwrap();
+        }
+        index.disconnect().unwrap();
+        let status = index.status();
+        drop(index);
+
+        // init and snapshot records, then the 10 records appended after the snapshot
+        let (_, records) = Log::<Record>::open(&dir.path().join("index.log")).unwrap();
+        assert_eq!(records.len(), 12);
+        assert!(matches!(records[1], Record::Snapshot { .. }));
+
+        let mut index = Index::open(dir.path(), MOCK_SPELL_VK, 1).unwrap();
+        assert_eq!(index.status(), status);
+        assert_eq!(index.block_hash(1), None);
+
+        // undo data survives compaction
+        for _ in 1..MAX_REORG_DEPTH {
+            index.disconnect().unwrap();
+        }
+        assert!(index.disconnect().is_err());
+        let height = tip_height - MAX_REORG_DEPTH;
+        assert_eq!(index.tip(), Some((height, hash(height))));
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+use charms_data::util;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+/// Append-only log of records persisted in a file: the embedded store of the
+/// [`Index`](super::Index). Can be compacted by [rewriting](Log::rewrite) it.
+///
+/// Each record is CBOR-encoded and prefixed with its length (4 bytes, big-endian). An incomplete
+/// last record (e.g. after a crash mid-write) is discarded when the log is opened.
+pub(crate) struct Log<T> {
+    file: File,
+    path: PathBuf,
+    _record: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Log<T> {
+    /// Open the log in the file at `path` (created if it does not exist). Returns the log and the
+    /// records in it, oldest first.
+    pub(crate) fn open(path: &Path) -> Result<(Self, Vec<T>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| anyhow!("error opening {}: {}", path.display(), e))?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = vec![];
+        let mut pos = 0;
+        while let Some(len_bytes) = bytes.get(pos..pos + 4) {
+            let len = u32::from_be_bytes(len_bytes.try_into()?) as usize;
+            let Some(record_bytes) = bytes.get(pos + 4..pos + 4 + len) else {
+                break;
+            };
+            let record = util::read(record_bytes)
+                .map_err(|e| anyhow!("corrupt record at {} in {}: {}", pos, path.display(), e))?;
+            records.push(record);
+            pos += 4 + len;
+        }
+        if pos < bytes.len() {
+            tracing::warn!(
+                "discarding incomplete record at {} in {}",
+                pos,
+                path.display()
+            );
+            file.set_len(pos as u64)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+
+        let log = Self {
+            file,
+            path: path.to_path_buf(),
+            _record: PhantomData,
+        };
+        Ok((log, records))
+    }
+
+    /// Append `record` to the log.
+    pub(crate) fn append(&mut self, record: &T) -> Result<()> {
+        let bytes = encode(record)?;
+        self.file
+            .write_all(&bytes)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| anyhow!("error writing to {}: {}", self.path.display(), e))
+    }
+
+    /// Replace the records in the log with `records`. Atomic: the new log is written to a
+    /// temporary file first, which then replaces the log file.
+    pub(crate) fn rewrite(&mut self, records: &[T]) -> Result<()> {
+        let mut bytes = vec![];
+        for record in records {
+            bytes.extend(encode(record)?);
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let write_log = || -> std::io::Result<File> {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &self.path)?;
+            if let Some(dir) = self.path.parent().filter(|dir| dir.is_dir()) {
+                File::open(dir)?.sync_all()?;
+            }
+            OpenOptions::new().read(true).append(true).open(&self.path)
+        };
+        self.file =
+            write_log().map_err(|e| anyhow!("error rewriting {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+/// `record` CBOR-encoded and prefixed with its length.
+fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>> {
+    let record_bytes = util::write(record)?;
+    let len: u32 = record_bytes.len().try_into()?;
+    let mut bytes = len.to_be_bytes().to_vec();
+    bytes.extend(record_bytes);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn appends_and_reads_records() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index.log");
+        {
+            let (mut log, records) = Log::<(u32, String)>::open(&path).unwrap();
+            assert_eq!(records, vec![]);
+            log.append(&(1, "one".to_string())).unwrap();
+            log.append(&(2, "two".to_string())).unwrap();
+        }
+        {
+            let (mut log, records) = Log::<(u32, String)>::open(&path).unwrap();
+            assert_eq!(records, vec![(1, "one".into()), (2, "two".into())]);
+            log.append(&(3, "three".to_string())).unwrap();
+        }
+
+        // a record cut short is discarded
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        {
+            let (mut log, records) = Log::<(u32, String)>::open(&path).unwrap();
+            assert_eq!(records.len(), 2);
+            log.append(&(4, "four".to_string())).unwrap();
+        }
+        let (_, records) = Log::<(u32, String)>::open(&path).unwrap();
+        assert_eq!(
+            records,
+            vec![(1, "one".into()), (2, "two".into()), (4, "four".into())]
+        );
+    }
+
+    #[test]
+    fn rewrites_records() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index.log");
+        {
+            let (mut log, _) = Log::<u32>::open(&path).unwrap();
+            for n in 0..10 {
+                log.append(&n).unwrap();
+            }
+            log.rewrite(&[100, 200]).unwrap();
+            log.append(&300).unwrap();
+        }
+        let (_, records) = Log::<u32>::open(&path).unwrap();
+        assert_eq!(records, vec![100, 200, 300]);
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
@@ -0,0 +1,409 @@
+use crate::spell::Spell;
+use anyhow::{anyhow, Result};
+use bitcoin::secp256k1::rand;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Maximum number of jobs waiting to be run: more are not accepted.
+pub const MAX_QUEUED_JOBS: usize = 100;
+
+/// Request to prove a spell: `charms spell prove` arguments, with transactions and app binaries
+/// passed inline.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProveRequest {
+    pub spell: Spell,
+    /// Bitcoin transaction (hex-encoded). If not provided, will be created from the spell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx: Option<String>,
+    /// Pre-requisite transactions (hex-encoded): see `charms spell prove --prev-txs`.
+    #[serde(default)]
+    pub prev_txs: Vec<String>,
+    /// App binaries (RISC-V ELF files, base64-encoded) referenced by the spell.
+    #[serde(default)]
+    pub app_bins: Vec<String>,
+    /// VKs (hex-encoded) of app binaries registered with the server, referenced by the spell.
+    #[serde(default)]
+    pub app_vks: Vec<String>,
+    /// UTXO IDs of the funding transaction outputs (`txid:vout`).
+    pub funding_utxos: Vec<String>,
+    /// Values of the funding UTXOs in sats: see `charms spell prove --funding-utxo-value`.
+    #[serde(default)]
+    pub funding_utxo_values: Vec<u64>,
+    pub change_address: String,
+    /// Refund key (hex-encoded x-only public key): see `charms spell prove --refund-key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund_key: Option<String>,
+    /// Fee rate in sats/vB.
+    #[serde(default = "default_fee_rate")]
+    pub fee_rate: f64,
+    /// Return unsigned PSBTs instead of transactions.
+    #[serde(default)]
+    pub psbt: bool,
+}
+
+fn default_fee_rate() -> f64 {
+    2.0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Proving job. Times are in seconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// Number of jobs to be run before this one (only for queued jobs).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    /// Commit and spell transactions (hex-encoded) or their PSBTs (base64-encoded), if done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Vec<String>>,
+    /// Why the job failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Queue of proving jobs, persisted in a directory: each job is kept in `{id}.json` and its
+/// request (until the job is finished) in `{id}.request.json`. Jobs interrupted by a restart are
+/// run again. Finished jobs are removed once they are older than the retention period.
+pub struct Jobs {
+    dir: PathBuf,
+    /// How long finished jobs are kept.
+    retention: Duration,
+    state: Mutex<JobsState>,
+    /// Notified when a job is queued.
+    queued: Condvar,
+}
+
+struct JobsState {
+    jobs: BTreeMap<String, Job>,
+    queue: VecDeque<String>,
+}
+
+impl Jobs {
+    /// Open the job queue in directory `dir` (created if it does not exist). Jobs are kept for
+    /// `retention` after they finish.
+    pub fn open(dir: &Path, retention: Duration) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut jobs = BTreeMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.ends_with(".request.json") || !name.ends_with(".json") {
+                continue;
+            }
+            let job: Job = read(&path)?;
+            jobs.insert(job.id.clone(), job);
+        }
+
+        let mut unfinished: Vec<&mut Job> = jobs
+            .values_mut()
+            .filter(|job| matches!(job.status, JobStatus::Queued | JobStatus::Running))
+            .collect();
+        unfinished.sort_by_key(|job| job.created_at);
+        let mut queue = VecDeque::new();
+        for job in unfinished {
+            job.status = JobStatus::Queued;
+            job.started_at = None;
+            queue.push_back(job.id.clone());
+        }
+
+        let jobs = Self {
+            dir: dir.to_path_buf(),
+            retention,
+            state: Mutex::new(JobsState { jobs, queue }),
+            queued: Condvar::new(),
+        };
+        jobs.remove_expired(&mut jobs.state.lock().unwrap())?;
+        Ok(jobs)
+    }
+
+    /// Queue a job for `request`. Returns the job ID, or `None` if there are already
+    /// [`MAX_QUEUED_JOBS`] jobs waiting.
+    pub fn submit(&self, request: &ProveRequest) -> Result<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        self.remove_expired(&mut state)?;
+        if state.queue.len() >= MAX_QUEUED_JOBS {
+            return Ok(None);
+        }
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        let job = Job {
+            id: id.clone(),
+            status: JobStatus::Queued,
+            queue_position: None,
+            created_at: now(),
+            started_at: None,
+            finished_at: None,
+            result: None,
+            error: None,
+        };
+        write(&self.request_path(&id), request)?;
+        write(&self.job_path(&id), &job)?;
+        state.jobs.insert(id.clone(), job);
+        state.queue.push_back(id.clone());
+        self.queued.notify_one();
+        Ok(Some(id))
+    }
+
+    /// Job with ID `id` (`None` if there's no such job or it has expired).
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let state = self.state.lock().unwrap();
+        let mut job = state.jobs.get(id)?.clone();
+        job.queue_position = state.queue.iter().position(|queued_id| queued_id == id);
+        Some(job)
+    }
+
+    /// Start `workers` threads running queued jobs with `prove`: up to `workers` jobs are run
+    /// concurrently.
+    pub fn run<F>(self: &Arc<Self>, workers: usize, prove: F)
+    where
+        F: Fn(&ProveRequest) -> Result<Vec<String>> + Send + Sync + 'static,
+    {
+        let prove = Arc::new(prove);
+        for _ in 0..workers {
+            let jobs = self.clone();
+            let prove = prove.clone();
+            thread::spawn(move || loop {
+                let id = jobs.next();
+                tracing::info!("running job {}", id);
+                let result = read(&jobs.request_path(&id)).and_then(|request| {
+                    panic::catch_unwind(AssertUnwindSafe(|| prove(&request)))
+                        .unwrap_or_else(|_| Err(anyhow!("proving panicked")))
+                });
+                if let Err(e) = &result {
+                    tracing::warn!("job {} failed: {}", id, e);
+                }
+                if let Err(e) = jobs.finish(&id, result) {
+                    tracing::error!("error saving job {}: {}", id, e);
+                }
+            });
+        }
+    }
+
+    /// Wait for a queued job and mark it as running. Returns its ID.
+    fn next(&self) -> String {
+        let mut state = self.state.lock().unwrap();
+        let id = loop {
+            match state.queue.pop_front() {
+                Some(id) => break id,
+                None => state = self.queued.wait(state).unwrap(),
+            }
+        };
+        let job = state.jobs.get_mut(&id).expect("queued job should exist");
+        job.status = JobStatus::Running;
+        job.started_at = Some(now());
+        if let Err(e) = write(&self.job_path(&id), job) {
+            tracing::warn!("error saving job {}: {}", id, e);
+        }
+        id
+    }
+
+    fn finish(&self, id: &str, result: Result<Vec<String>>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let job = state.jobs.get_mut(id).expect("running job should exist");
+        job.finished_at = Some(now());
+        match result {
+            Ok(result) => {
+                job.status = JobStatus::Done;
+                job.result = Some(result);
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(e.to_string());
+            }
+        }
+        write(&self.job_path(id), job)?;
+        match fs::remove_file(self.request_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove jobs finished more than the retention period ago.
+    fn remove_expired(&self, state: &mut JobsState) -> Result<()> {
+        let now = now();
+        let expired: Vec<String> = state
+            .jobs
+            .values()
+            .filter(|job| {
+                job.finished_at
+                    .is_some_and(|finished_at| finished_at + self.retention.as_secs() <= now)
+            })
+            .map(|job| job.id.clone())
+            .collect();
+        for id in expired {
+            fs::remove_file(self.job_path(&id))
+                .map_err(|e| anyhow!("error removing job {}: {}", id, e))?;
+            state.jobs.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn job_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn request_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.request.json", id))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = fs::read(path).map_err(|e| anyhow!("error reading {}: {}", path.display(), e))?;
+    serde_json::from_slice(&bytes).map_err(|e| anyhow!("error parsing {}: {}", path.display(), e))
+}
+
+/// Write `value` to `path` via a temporary file: a crash never leaves a partially written file.
+fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(value)?)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| anyhow!("error writing {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::bail;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const RETENTION: Duration = Duration::from_secs(3600);
+
+    fn request(change_address: &str) -> ProveRequest {
+        serde_json::from_value(serde_json::json!({
+            "spell": { "version": 2, "apps": {}, "ins": [], "outs": [] },
+            "funding_utxos": [],
+            "change_address": change_address,
+        }))
+        .unwrap()
+    }
+
+    fn prove(request: &ProveRequest) -> Result<Vec<String>> {
+        match request.change_address.as_str() {
+            "fail" => bail!("no luck"),
+            "panic" => panic!("no luck at all"),
+            address => Ok(vec![address.to_string(); 2]),
+        }
+    }
+
+    fn wait_until_finished(jobs: &Jobs, id: &str) -> Job {
+        for _ in 0..500 {
+            let job = jobs.get(id).unwrap();
+            if matches!(job.status, JobStatus::Done | JobStatus::Failed) {
+                return job;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("job {} not finished", id);
+    }
+
+    #[test]
+    fn runs_jobs() {
+        let dir = TempDir::new().unwrap();
+        let jobs = Arc::new(Jobs::open(dir.path(), RETENTION).unwrap());
+        let ids = ["ok", "fail", "panic"]
+            .map(|change_address| jobs.submit(&request(change_address)).unwrap().unwrap());
+        assert_eq!(jobs.get(&ids[2]).unwrap().queue_position, Some(2));
+        assert_eq!(jobs.get("nope"), None);
+        jobs.run(2, prove);
+
+        let job = wait_until_finished(&jobs, &ids[0]);
+        assert_eq!(job.status, JobStatus::Done);
+        assert_eq!(job.result, Some(vec!["ok".to_string(); 2]));
+        assert!(job.started_at.is_some() && job.finished_at.is_some());
+        let job = wait_until_finished(&jobs, &ids[1]);
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("no luck"));
+        let job = wait_until_finished(&jobs, &ids[2]);
+        assert_eq!(job.error.as_deref(), Some("proving panicked"));
+
+        // finished jobs keep their results, but not their requests
+        assert!(!jobs.request_path(&ids[0]).exists());
+        assert_eq!(
+            Jobs::open(dir.path(), RETENTION)
+                .unwrap()
+                .get(&ids[0])
+                .unwrap(),
+            jobs.get(&ids[0]).unwrap()
+        );
+    }
+
+    #[test]
+    fn resumes_jobs_after_restart() {
+        let dir = TempDir::new().unwrap();
+        let (queued, running) = {
+            let jobs = Jobs::open(dir.path(), RETENTION).unwrap();
+            let running = jobs.submit(&request("running")).unwrap().unwrap();
+            let queued = jobs.submit(&request("queued")).unwrap().unwrap();
+            assert_eq!(jobs.next(), running);
+            (queued, running)
+        };
+
+        let jobs = Arc::new(Jobs::open(dir.path(), RETENTION).unwrap());
+        assert_eq!(jobs.get(&running).unwrap().status, JobStatus::Queued);
+        jobs.run(1, prove);
+        for (id, address) in [(running, "running"), (queued, "queued")] {
+            let job = wait_until_finished(&jobs, &id);
+            assert_eq!(job.result, Some(vec![address.to_string(); 2]));
+        }
+    }
+
+    #[test]
+    fn removes_expired_jobs() {
+        let dir = TempDir::new().unwrap();
+        let jobs = Arc::new(Jobs::open(dir.path(), Duration::ZERO).unwrap());
+        let finished = jobs.submit(&request("ok")).unwrap().unwrap();
+        jobs.run(1, prove);
+        wait_until_finished(&jobs, &finished);
+
+        // expired jobs are removed when the next job is submitted
+        let queued = jobs.submit(&request("ok")).unwrap().unwrap();
+        assert_eq!(jobs.get(&finished), None);
+        assert!(!jobs.job_path(&finished).exists());
+        wait_until_finished(&jobs, &queued);
+
+        // or when the queue is opened
+        let jobs = Jobs::open(dir.path(), Duration::ZERO).unwrap();
+        assert_eq!(jobs.get(&queued), None);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn limits_queued_jobs() {
+        let dir = TempDir::new().unwrap();
+        let jobs = Jobs::open(dir.path(), RETENTION).unwrap();
+        for _ in 0..MAX_QUEUED_JOBS {
+            assert!(jobs.submit(&request("ok")).unwrap().is_some());
+        }
+        assert_eq!(jobs.submit(&request("ok")).unwrap(), None);
+    }
+}
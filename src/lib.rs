@@ -1,5 +1,12 @@
 pub mod app;
+pub mod cache;
+pub mod chain;
 pub mod cli;
+pub mod coin_select;
+pub mod events;
+pub mod index;
+pub mod jobs;
+pub mod psbt;
 pub mod script;
 pub mod spell;
 pub mod tx;
@@ -0,0 +1,274 @@
+use anyhow::{anyhow, bail, ensure, Result};
+use bitcoin::{
+    psbt::{Input, Psbt},
+    OutPoint, Transaction, TxOut, Txid, Witness,
+};
+use std::collections::BTreeMap;
+
+/// Create PSBTs (BIP-174, with BIP-371 Taproot fields) for the `[commit_tx, spell_tx]` pair
+/// produced by [`crate::tx::add_spell`], so that they can be signed by external signers (e.g.
+/// hardware wallets).
+///
+/// All inputs get their witness UTXOs: from `funding_utxos` for the commit tx inputs, outputs of
+/// `prev_txs` for the spell tx inputs. The spell tx input spending the commit tx output is already
+/// finalized: it only has its final witness (signature, Taproot leaf script and control block).
+///
+/// Signers also need to know which keys sign the other inputs: use [`Wallet::add_key_origins`]
+/// (crate::chain::Wallet::add_key_origins) for the inputs spending wallet outputs.
+pub fn spell_psbts(
+    [commit_tx, spell_tx]: [Transaction; 2],
+    funding_utxos: &[(OutPoint, TxOut)],
+    prev_txs: &BTreeMap<Txid, Transaction>,
+) -> Result<[Psbt; 2]> {
+    let mut commit_psbt = Psbt::from_unsigned_tx(commit_tx.clone())?;
+    for (psbt_input, tx_in) in commit_psbt.inputs.iter_mut().zip(&commit_tx.input) {
+        let funding_utxo = funding_utxos
+            .iter()
+            .find(|(out_point, _)| *out_point == tx_in.previous_output)
+            .ok_or(anyhow!("funding UTXO {} not found", tx_in.previous_output))?;
+        psbt_input.witness_utxo = Some(funding_utxo.1.clone());
+    }
+
+    let mut unsigned_spell_tx = spell_tx.clone();
+    for tx_in in unsigned_spell_tx.input.iter_mut() {
+        tx_in.witness = Witness::new();
+    }
+    let mut spell_psbt = Psbt::from_unsigned_tx(unsigned_spell_tx)?;
+
+    let Some((spell_tx_in, tx_ins)) = spell_tx.input.split_last() else {
+        unreachable!("spell tx has at least the spell input");
+    };
+    for (psbt_input, tx_in) in spell_psbt.inputs.iter_mut().zip(tx_ins) {
+        psbt_input.witness_utxo = Some(prev_txout(prev_txs, &tx_in.previous_output)?);
+    }
+
+    let commit_txid = commit_tx.compute_txid();
+    ensure!(
+        spell_tx_in.previous_output == OutPoint::new(commit_txid, 0),
+        "the spell tx does not spend the commit tx output"
+    );
+    let spell_input = spell_psbt
+        .inputs
+        .last_mut()
+        .expect("spell input should exist");
+    *spell_input = committed_spell_input(spell_tx_in.witness.clone(), &commit_tx.output[0])?;
+
+    Ok([commit_psbt, spell_psbt])
+}
+
+fn prev_txout(prev_txs: &BTreeMap<Txid, Transaction>, out_point: &OutPoint) -> Result<TxOut> {
+    prev_txs
+        .get(&out_point.txid)
+        .and_then(|tx| tx.output.get(out_point.vout as usize))
+        .cloned()
+        .ok_or(anyhow!("prev tx output {} not found", out_point))
+}
+
+/// Finalized PSBT input spending the committed spell output, with the witness `[signature,
+/// script, control_block]` made by [`crate::tx::add_spell`]. Like any finalized input (BIP-174),
+/// it has no signing fields: signers leave it alone.
+fn committed_spell_input(witness: Witness, commit_txout: &TxOut) -> Result<Input> {
+    ensure!(
+        witness.len() == 3,
+        "spell input witness should be [signature, script, control_block]"
+    );
+    Ok(Input {
+        witness_utxo: Some(commit_txout.clone()),
+        final_script_witness: Some(witness),
+        ..Default::default()
+    })
+}
+
+/// Combine signed PSBTs and finalize them into transactions ready to be broadcast.
+///
+/// PSBTs of the same transaction (e.g. signed by different signers) are combined. Transactions are
+/// returned in the order their PSBTs first appear in `psbts`.
+///
+/// Inputs are finalized if they are Taproot key path spends (with `tap_key_sig`) or P2WPKH spends
+/// (with a single partial signature). Already finalized inputs are kept as is.
+pub fn finalize(psbts: Vec<Psbt>) -> Result<Vec<Transaction>> {
+    let mut combined: Vec<Psbt> = vec![];
+    for psbt in psbts {
+        let txid = psbt.unsigned_tx.compute_txid();
+        match combined
+            .iter_mut()
+            .find(|p| p.unsigned_tx.compute_txid() == txid)
+        {
+            Some(existing) => existing.combine(psbt)?,
+            None => combined.push(psbt),
+        }
+    }
+
+    combined
+        .into_iter()
+        .map(|mut psbt| {
+            let txid = psbt.unsigned_tx.compute_txid();
+            for (i, input) in psbt.inputs.iter_mut().enumerate() {
+                finalize_input(input).map_err(|e| anyhow!("tx {} input {}: {}", txid, i, e))?;
+            }
+            Ok(psbt.extract_tx_unchecked_fee_rate())
+        })
+        .collect()
+}
+
+fn finalize_input(input: &mut Input) -> Result<()> {
+    if input.final_script_witness.is_some() {
+        return Ok(());
+    }
+    let witness = match (&input.tap_key_sig, input.partial_sigs.len()) {
+        (Some(signature), _) => Witness::from_slice(&[signature.to_vec()]),
+        (None, 1) => {
+            let Some(script_pubkey) = input.witness_utxo.as_ref().map(|u| &u.script_pubkey) else {
+                bail!("missing witness UTXO");
+            };
+            ensure!(
+                script_pubkey.is_p2wpkh(),
+                "only P2WPKH inputs can be finalized with a partial signature"
+            );
+            let (public_key, signature) = input.partial_sigs.first_key_value().unwrap();
+            Witness::from_slice(&[signature.to_vec(), public_key.to_bytes()])
+        }
+        (None, 0) => bail!("not signed"),
+        (None, _) => bail!("can't finalize inputs with more than one partial signature"),
+    };
+    *input = Input {
+        witness_utxo: input.witness_utxo.take(),
+        non_witness_utxo: input.non_witness_utxo.take(),
+        final_script_witness: Some(witness),
+        unknown: std::mem::take(&mut input.unknown),
+        proprietary: std::mem::take(&mut input.proprietary),
+        ..Default::default()
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tx::add_spell;
+    use bitcoin::{
+        absolute::LockTime,
+        key::{Keypair, Secp256k1, TapTweak},
+        secp256k1::{rand::thread_rng, Message},
+        sighash::{Prevouts, SighashCache},
+        taproot,
+        transaction::Version,
+        Address, Amount, FeeRate, Network, ScriptBuf, TapSighashType, TxIn,
+    };
+
+    fn p2tr_script(keypair: &Keypair) -> ScriptBuf {
+        let secp256k1 = Secp256k1::new();
+        let (public_key, _) = keypair.x_only_public_key();
+        Address::p2tr(&secp256k1, public_key, None, Network::Regtest).script_pubkey()
+    }
+
+    fn tx(input: Vec<OutPoint>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: input
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output,
+        }
+    }
+
+    fn sign_key_spends(psbt: &mut Psbt, keypair: &Keypair) {
+        let secp256k1 = Secp256k1::new();
+        let tweaked = keypair.tap_tweak(&secp256k1, None).to_inner();
+        let prevouts: Vec<TxOut> = psbt
+            .inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone().unwrap())
+            .collect();
+        let script = p2tr_script(keypair);
+        for i in 0..psbt.inputs.len() {
+            if psbt.inputs[i].final_script_witness.is_some() || prevouts[i].script_pubkey != script
+            {
+                continue;
+            }
+            let sighash = SighashCache::new(&psbt.unsigned_tx)
+                .taproot_key_spend_signature_hash(
+                    i,
+                    &Prevouts::All(&prevouts),
+                    TapSighashType::Default,
+                )
+                .unwrap();
+            let signature = secp256k1.sign_schnorr(
+                &Message::from_digest_slice(sighash.as_ref()).unwrap(),
+                &tweaked,
+            );
+            psbt.inputs[i].tap_key_sig = Some(taproot::Signature {
+                signature,
+                sighash_type: TapSighashType::Default,
+            });
+        }
+    }
+
+    #[test]
+    fn psbts_roundtrip() {
+        let secp256k1 = Secp256k1::new();
+        let keypair = Keypair::new(&secp256k1, &mut thread_rng());
+        let script = p2tr_script(&keypair);
+        let txout = |sats| TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: script.clone(),
+        };
+
+        let prev_tx = tx(vec![OutPoint::null()], vec![txout(5_000), txout(20_000)]);
+        let prev_txid = prev_tx.compute_txid();
+        let prev_txs = BTreeMap::from([(prev_txid, prev_tx.clone())]);
+        let spell_tx = tx(vec![OutPoint::new(prev_txid, 0)], vec![txout(1_000)]);
+
+        let funding_utxos = [(OutPoint::new(prev_txid, 1), txout(20_000))];
+        let txs = add_spell(
+            spell_tx,
+            b"spell data",
+            &funding_utxos,
+            script.clone(),
+            Some(keypair.x_only_public_key().0),
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &prev_txs,
+        )
+        .unwrap();
+        let [mut commit_psbt, mut spell_psbt] =
+            spell_psbts(txs.clone(), &funding_utxos, &prev_txs).unwrap();
+
+        assert_eq!(commit_psbt.inputs[0].witness_utxo, Some(txout(20_000)));
+        assert_eq!(
+            spell_psbt.inputs[0].witness_utxo,
+            Some(prev_tx.output[0].clone())
+        );
+        // the spell input is finalized
+        assert_eq!(
+            spell_psbt.inputs[1],
+            Input {
+                witness_utxo: Some(txs[0].output[0].clone()),
+                final_script_witness: Some(txs[1].input[1].witness.clone()),
+                ..Default::default()
+            }
+        );
+
+        // PSBTs survive serialization
+        let spell_psbt_b64 = spell_psbt.to_string();
+        assert_eq!(spell_psbt_b64.parse::<Psbt>().unwrap(), spell_psbt);
+
+        assert!(finalize(vec![commit_psbt.clone(), spell_psbt.clone()]).is_err());
+
+        sign_key_spends(&mut commit_psbt, &keypair);
+        let unsigned_spell_psbt = spell_psbt.clone();
+        sign_key_spends(&mut spell_psbt, &keypair);
+
+        let finalized = finalize(vec![commit_psbt, unsigned_spell_psbt, spell_psbt]).unwrap();
+        assert_eq!(finalized.len(), 2);
+        assert_eq!(finalized[0].compute_txid(), txs[0].compute_txid());
+        assert_eq!(finalized[1].compute_txid(), txs[1].compute_txid());
+        assert_eq!(finalized[0].input[0].witness.len(), 1);
+        assert_eq!(finalized[1].input[0].witness.len(), 1);
+        assert_eq!(finalized[1].input[1].witness, txs[1].input[1].witness);
+    }
+}
@@ -4,14 +4,14 @@ use bitcoin::{
         all::{OP_CHECKSIG, OP_ENDIF, OP_IF},
         OP_FALSE,
     },
-    script::{Builder, PushBytes},
+    script::{Builder, Instruction, PushBytes},
     secp256k1::Secp256k1,
     taproot::{ControlBlock, LeafVersion, TaprootBuilder, TaprootSpendInfo},
-    ScriptBuf, XOnlyPublicKey,
+    Script, ScriptBuf, XOnlyPublicKey,
 };
 
-pub fn control_block(public_key: XOnlyPublicKey, script: ScriptBuf) -> ControlBlock {
-    taproot_spend_info(public_key, script.clone())
+pub fn control_block(internal_key: XOnlyPublicKey, script: ScriptBuf) -> ControlBlock {
+    taproot_spend_info(internal_key, script.clone())
         .control_block(&(script, LeafVersion::TapScript))
         .unwrap()
 }
@@ -35,11 +35,22 @@ fn push_envelope(builder: Builder, data: &[u8]) -> Builder {
     builder.push_opcode(OP_ENDIF)
 }
 
-pub fn taproot_spend_info(public_key: XOnlyPublicKey, script: ScriptBuf) -> TaprootSpendInfo {
+pub fn taproot_spend_info(internal_key: XOnlyPublicKey, script: ScriptBuf) -> TaprootSpendInfo {
     let secp256k1 = Secp256k1::new();
     TaprootBuilder::new()
         .add_leaf(0, script)
         .unwrap()
-        .finalize(&secp256k1, public_key)
+        .finalize(&secp256k1, internal_key)
         .unwrap()
 }
+
+/// Public key checked by `script` created by [`data_script`].
+pub fn data_script_public_key(script: &Script) -> Option<XOnlyPublicKey> {
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    match instructions.as_slice() {
+        [.., Instruction::PushBytes(public_key), Instruction::Op(OP_CHECKSIG)] => {
+            XOnlyPublicKey::from_slice(public_key.as_bytes()).ok()
+        }
+        _ => None,
+    }
+}
@@ -1,13 +1,23 @@
 use crate::{app, tx::add_spell, utils, SPELL_CHECKER_BINARY, SPELL_VK};
 use anyhow::{anyhow, ensure, Error};
-use bitcoin::{address::NetworkUnchecked, hashes::Hash, Address, Amount, FeeRate, OutPoint, Txid};
+use bitcoin::{
+    address::NetworkUnchecked, hashes::Hash, Address, FeeRate, OutPoint, TxOut, Txid,
+    XOnlyPublicKey,
+};
 pub use charms_client::{
-    to_tx, NormalizedCharms, NormalizedSpell, NormalizedTransaction, Proof, SpellProverInput,
-    CURRENT_VERSION,
+    to_tx, NormalizedCharms, NormalizedSpell, NormalizedTransaction, Proof, SpellCheckReport,
+    SpellProverInput, CURRENT_VERSION,
+};
+use charms_client::{
+    tx::{mock_proof, SpellError},
+    MOCK_SPELL_VK,
+};
+use charms_data::{
+    ref_nft_app, util, App, Charms, Data, TokenAmount, TokenMetadata, Transaction, TxId, UtxoId,
+    B32, TOKEN,
 };
-use charms_data::{util, App, Charms, Data, Transaction, TxId, UtxoId, B32};
 use serde::{Deserialize, Serialize};
-use sp1_sdk::{HashableKey, ProverClient, SP1Stdin};
+use sp1_sdk::{ProverClient, SP1Stdin};
 use std::{
     collections::{BTreeMap, BTreeSet},
     str::FromStr,
@@ -113,7 +123,7 @@ impl Spell {
             .iter()
             .map(|(k, v)| {
                 let app = self.apps.get(k).ok_or(anyhow!("missing app {}", k))?;
-                Ok((app.clone(), Data::from(v)))
+                Ok((app.clone(), self.charm_data(app, v)?))
             })
             .collect::<Result<Charms, _>>()
     }
@@ -165,7 +175,7 @@ impl Spell {
                         let i: usize = *app_to_index
                             .get(app)
                             .expect("app should be in app_to_index");
-                        Ok((i, Data::from(v)))
+                        Ok((i, self.charm_data(app, v)?))
                     })
                     .collect::<Result<NormalizedCharms, Error>>()?;
                 Ok(n_charms)
@@ -184,6 +194,156 @@ impl Spell {
         Ok((norm_spell, app_private_inputs))
     }
 
+    /// Fill in missing charms of inputs and reference inputs with the charms they carry on chain:
+    /// from the spells of their transactions in `prev_spells` (see [`charms_client::prev_spells`]).
+    /// Apps not yet in the spell are added to `apps`.
+    ///
+    /// Fails if charms written in the spell don't match the charms on chain, or if the transaction
+    /// of an input is not in `prev_spells`.
+    pub fn resolve_input_charms(&mut self, prev_spells: &PrevSpells) -> anyhow::Result<()> {
+        let mut resolved = vec![];
+        for (i, input) in self
+            .ins
+            .iter()
+            .chain(self.refs.iter().flatten())
+            .enumerate()
+        {
+            let utxo_id = input
+                .utxo_id
+                .as_ref()
+                .ok_or(anyhow!("missing input utxo_id"))?;
+            let on_chain = on_chain_charms(prev_spells, utxo_id)?;
+            match &input.charms {
+                None => resolved.push((i, on_chain)),
+                Some(_) => {
+                    let charms = self.charms(&input.charms)?;
+                    ensure!(
+                        same_charms(&charms, &on_chain),
+                        "charms of input {} in the spell don't match the charms on chain:\n\
+                         in the spell:\n{}on chain:\n{}",
+                        utxo_id,
+                        serde_yaml::to_string(&charms)?,
+                        serde_yaml::to_string(&on_chain)?
+                    );
+                }
+            }
+        }
+
+        for (i, charms) in resolved {
+            let keyed_charms = charms
+                .into_iter()
+                .map(|(app, data)| (self.app_key(app), data))
+                .collect();
+            let n_ins = self.ins.len();
+            let input = match i < n_ins {
+                true => &mut self.ins[i],
+                false => &mut self.refs.as_mut().expect("refs should be present")[i - n_ins],
+            };
+            input.charms = Some(keyed_charms);
+        }
+        Ok(())
+    }
+
+    /// Key of `app` in `apps`: the existing one, or a new one if `app` is not in the spell yet.
+    fn app_key(&mut self, app: App) -> String {
+        if let Some(key) = self.apps.iter().find_map(|(k, a)| (*a == app).then_some(k)) {
+            return key.clone();
+        }
+        let key = (0..)
+            .map(|i| utils::str_index(&i))
+            .find(|k| !self.apps.contains_key(k))
+            .expect("there should be an unused key");
+        self.apps.insert(key.clone(), app);
+        key
+    }
+
+    /// Charm data of `app` as it goes into the transaction: token amounts written as decimal
+    /// strings (e.g. `"1.5"`) are converted to integer amounts of the smallest denomination,
+    /// according to the token's `decimals` (see [`Spell::token_decimals`]).
+    fn charm_data(&self, app: &App, data: &Data) -> anyhow::Result<Data> {
+        if app.tag != TOKEN || data.value::<TokenAmount>().is_ok() {
+            return Ok(data.clone());
+        }
+        if let Ok(s) = data.value::<String>() {
+            let decimals = self.token_decimals(app)?.ok_or(anyhow!(
+                "amount '{}' of {}: decimal amounts need the token's reference NFT (with \
+                 `decimals`) in the spell",
+                s,
+                app
+            ))?;
+            let amount = TokenAmount::from_decimal_str(&s, decimals)
+                .map_err(|e| anyhow!("amount of {}: {}", app, e))?;
+            return Ok(Data::from(&amount));
+        }
+        ensure!(
+            data.value::<f64>().is_err(),
+            "amount of {}: write decimal amounts as strings (e.g. \"1.5\")",
+            app
+        );
+        Ok(data.clone())
+    }
+
+    /// Decimals of token `app` (see CHIP-0420): from the data of its reference NFT, if the spell
+    /// has it in its inputs, reference inputs or outputs. `None` if the reference NFT is not in the
+    /// spell.
+    pub fn token_decimals(&self, app: &App) -> anyhow::Result<Option<u8>> {
+        let ref_nft = ref_nft_app(app);
+        let Some(key) = self
+            .apps
+            .iter()
+            .find_map(|(k, a)| (*a == ref_nft).then_some(k))
+        else {
+            return Ok(None);
+        };
+        let data = self
+            .ins
+            .iter()
+            .chain(self.refs.iter().flatten())
+            .filter_map(|input| input.charms.as_ref())
+            .chain(self.outs.iter().filter_map(|output| output.charms.as_ref()))
+            .find_map(|charms| charms.get(key));
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        let metadata: TokenMetadata = data
+            .value()
+            .map_err(|e| anyhow!("invalid metadata of {}: {}", ref_nft, e))?;
+        Ok(Some(metadata.decimals.unwrap_or(0)))
+    }
+
+    /// Spell with token amounts written as decimal strings (e.g. `"1.50000000"`), where the token's
+    /// `decimals` are known (see [`Spell::token_decimals`]) and not `0`. For display: the reverse
+    /// of the conversion in [`Spell::normalized`].
+    pub fn with_decimal_amounts(&self) -> Self {
+        let decimals: BTreeMap<&String, u8> = self
+            .apps
+            .iter()
+            .filter(|(_, app)| app.tag == TOKEN)
+            .filter_map(|(k, app)| match self.token_decimals(app) {
+                Ok(Some(decimals)) if decimals > 0 => Some((k, decimals)),
+                _ => None,
+            })
+            .collect();
+        let format = |charms: &mut Option<KeyedCharms>| {
+            for (k, data) in charms.iter_mut().flatten() {
+                if let (Some(&decimals), Ok(amount)) =
+                    (decimals.get(k), data.value::<TokenAmount>())
+                {
+                    *data = Data::from(&amount.to_decimal_string(decimals));
+                }
+            }
+        };
+
+        let mut spell = self.clone();
+        for input in spell.ins.iter_mut().chain(spell.refs.iter_mut().flatten()) {
+            format(&mut input.charms);
+        }
+        for output in spell.outs.iter_mut() {
+            format(&mut output.charms);
+        }
+        spell
+    }
+
     /// De-normalize a normalized spell.
     pub fn denormalized(norm_spell: &NormalizedSpell) -> Self {
         let apps = (0..)
@@ -266,6 +426,44 @@ impl Spell {
     }
 }
 
+/// Spells of previous transactions, as returned by [`charms_client::prev_spells`].
+pub type PrevSpells = BTreeMap<TxId, (Result<NormalizedSpell, SpellError>, usize)>;
+
+/// Charms of the output `utxo_id` according to `prev_spells`: none if its transaction has no
+/// (valid) spell.
+fn on_chain_charms(prev_spells: &PrevSpells, utxo_id: &UtxoId) -> anyhow::Result<Charms> {
+    let (prev_spell, _) = prev_spells
+        .get(&utxo_id.0)
+        .ok_or(anyhow!("prev tx {} not found", utxo_id.0))?;
+    Ok(prev_spell
+        .as_ref()
+        .ok()
+        .and_then(|prev_spell| {
+            prev_spell
+                .tx
+                .outs
+                .get(utxo_id.1 as usize)
+                .map(|n_charms| charms_client::charms(prev_spell, n_charms))
+        })
+        .unwrap_or_default())
+}
+
+/// Compare charms ignoring the order of map entries in their data (e.g. NFT state fields written
+/// in a different order).
+fn same_charms(a: &Charms, b: &Charms) -> bool {
+    let same_data = |a: &Data, b: &Data| {
+        a == b
+            || matches!(
+                (a.value::<serde_json::Value>(), b.value::<serde_json::Value>()),
+                (Ok(a), Ok(b)) if a == b
+            )
+    };
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|((app_a, a), (app_b, b))| app_a == app_b && same_data(a, b))
+}
+
 fn app_inputs(
     keyed_apps: &BTreeMap<String, App>,
     keyed_inputs: &BTreeMap<String, Data>,
@@ -281,26 +479,108 @@ fn app_inputs(
         .collect()
 }
 
+/// How spells are proven (and which proofs are accepted when verifying spells).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProofMode {
+    /// Groth16 proofs: the only ones accepted on mainnet and testnets.
+    #[default]
+    Groth16,
+    /// Mock (dev) proofs: the spell is [checked](check) natively, including its app contracts
+    /// (app binaries run in SP1's executor), and the proof is a marker followed by the SHA-256
+    /// hash of the public values (see [`mock_proof`]). Fast to produce, but **not** actual proofs.
+    /// Only accepted where explicitly enabled (e.g. on regtest).
+    Mock,
+}
+
+impl ProofMode {
+    /// Mock mode if `mock` is `true`, Groth16 otherwise.
+    pub fn new(mock: bool) -> Self {
+        match mock {
+            true => ProofMode::Mock,
+            false => ProofMode::Groth16,
+        }
+    }
+
+    /// Spell verification key spells are proven and verified with in this mode.
+    pub fn spell_vk(&self) -> &'static str {
+        match self {
+            ProofMode::Groth16 => SPELL_VK,
+            ProofMode::Mock => MOCK_SPELL_VK,
+        }
+    }
+}
+
+/// Check a spell (provided as [`NormalizedSpell`]) natively, without proving: perform the same
+/// checks as the spell checker in the zkVM, running app contracts with `app_prover`.
+/// Returns a report of all problems found.
+pub fn check(
+    app_prover: &app::Prover,
+    norm_spell: &NormalizedSpell,
+    app_binaries: &BTreeMap<B32, Vec<u8>>,
+    app_private_inputs: &BTreeMap<App, Data>,
+    prev_txs: &Vec<bitcoin::Transaction>,
+    spell_vk: &str,
+) -> SpellCheckReport {
+    let prev_spells = charms_client::prev_spells(prev_txs, spell_vk);
+    let empty = Data::empty();
+    charms_client::check_spell(norm_spell, &prev_spells, |app, tx, x| {
+        let w = app_private_inputs.get(app).unwrap_or(&empty);
+        app_prover
+            .check(app_binaries, app, tx, x, w)
+            .map_err(|e| format!("{:#}", e))
+    })
+}
+
 /// Prove a spell (provided as [`NormalizedSpell`]).
 /// Returns the normalized spell and the proof (which is a Groth16 proof of checking if the spell is
-/// correct inside a zkVM).
+/// correct inside a zkVM, or a mock proof in [`ProofMode::Mock`]).
 ///
 /// Requires the binaries of the apps used in the spell, the private inputs to the apps, and the
 /// pre-requisite transactions (`prev_txs`).
+///
+/// The spell is [checked](check) before proving: if it is not correct, the returned error
+/// explains why.
 pub fn prove(
     norm_spell: NormalizedSpell,
     app_binaries: &BTreeMap<B32, Vec<u8>>,
     app_private_inputs: BTreeMap<App, Data>,
     prev_txs: Vec<bitcoin::Transaction>,
+    mode: ProofMode,
 ) -> anyhow::Result<(NormalizedSpell, Proof)> {
-    let client = ProverClient::from_env();
-    let (pk, vk) = client.setup(SPELL_CHECKER_BINARY);
+    let app_prover = match mode {
+        ProofMode::Groth16 => app::Prover::new(),
+        ProofMode::Mock => app::Prover::mock(),
+    };
+
+    let report = check(
+        &app_prover,
+        &norm_spell,
+        app_binaries,
+        &app_private_inputs,
+        &prev_txs,
+        mode.spell_vk(),
+    );
+    ensure!(
+        report.is_ok(),
+        "spell is not correct, not proving:\n{}",
+        report
+    );
+
+    if mode == ProofMode::Mock {
+        // the spell checker binary is not run (it predates mock proofs, so it can't check spells
+        // building on mock-proven ones): the spell and its app contracts have been checked above
+        let proof = mock_proof(&util::write(&(mode.spell_vk(), &norm_spell))?);
+        let mut norm_spell = norm_spell;
+        norm_spell.tx.ins = None;
+        return Ok((norm_spell, proof));
+    }
+
     let mut stdin = SP1Stdin::new();
 
-    let prev_spells = charms_client::prev_spells(&prev_txs, SPELL_VK);
+    let prev_spells = charms_client::prev_spells(&prev_txs, mode.spell_vk());
 
     let prover_input = SpellProverInput {
-        self_spell_vk: vk.bytes32(),
+        self_spell_vk: mode.spell_vk().to_string(),
         prev_txs,
         spell: norm_spell.clone(),
         app_contract_proofs: norm_spell
@@ -319,7 +599,7 @@ pub fn prove(
     let tx = to_tx(&norm_spell, &prev_spells);
     let app_public_inputs = &norm_spell.app_public_inputs;
 
-    app::Prover::new().prove(
+    app_prover.prove(
         app_binaries,
         tx,
         app_public_inputs,
@@ -327,6 +607,8 @@ pub fn prove(
         &mut stdin,
     )?;
 
+    let client = ProverClient::from_env();
+    let (pk, _) = app_prover.setup(SPELL_CHECKER_BINARY);
     let proof = client.prove(&pk, &stdin).groth16().run()?;
     let proof = proof.bytes().into_boxed_slice();
 
@@ -360,18 +642,244 @@ $TOAD: 9
         let utxo_id: UtxoId = utxo_id_data.value().unwrap();
         assert_eq!(utxo_id_0, dbg!(utxo_id));
     }
+
+    const IDENTITY: &str = "6c730ee5b2ae7a4e9bf5b5b4a3c4e0f0a7b6f8c3e1d2c4b5a69788796a5b4c3d";
+    const VK: &str = "8e877d70518a5b28f5221e70bd7ff7692a603f3a26d7076a5253e21c304a354f";
+
+    fn token_spell(amount: &str, nft_state: &str) -> Spell {
+        serde_yaml::from_str(&format!(
+            r#"
+version: 2
+apps:
+  $00: n/{IDENTITY}/{VK}
+  $01: t/{IDENTITY}/{VK}
+ins:
+  - utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2
+    charms:
+      $00: {nft_state}
+outs:
+  - charms:
+      $01: {amount}
+  - charms:
+      $00: {nft_state}
+"#
+        ))
+        .unwrap()
+    }
+
+    fn out_amount(spell: &Spell) -> anyhow::Result<TokenAmount> {
+        let (norm_spell, _) = spell.normalized()?;
+        norm_spell.tx.outs[0][&1].value()
+    }
+
+    #[test]
+    fn converts_decimal_amounts() {
+        let nft_state = "{ticker: TOAD, decimals: 8}";
+        let spell = token_spell(r#""1.5""#, nft_state);
+        assert_eq!(out_amount(&spell).unwrap(), TokenAmount(150000000));
+        let token = spell.apps["$01"].clone();
+        assert_eq!(
+            spell.to_tx().unwrap().outs[0][&token],
+            Data::from(&TokenAmount(150000000))
+        );
+
+        // integer amounts are taken as is
+        assert_eq!(
+            out_amount(&token_spell("1500", nft_state)).unwrap(),
+            TokenAmount(1500)
+        );
+
+        // excess precision, unquoted decimal amounts
+        assert!(out_amount(&token_spell(r#""0.000000001""#, nft_state)).is_err());
+        assert!(out_amount(&token_spell("1.5", nft_state)).is_err());
+
+        // decimals not known: the reference NFT has none (0) or is not in the spell
+        assert!(out_amount(&token_spell(r#""1.5""#, "{ticker: TOAD}")).is_err());
+        assert_eq!(
+            out_amount(&token_spell(r#""15""#, "{ticker: TOAD}")).unwrap(),
+            TokenAmount(15)
+        );
+        let mut spell = token_spell(r#""1.5""#, nft_state);
+        spell.ins[0].charms = None;
+        spell.outs.pop();
+        assert!(out_amount(&spell).is_err());
+    }
+
+    const PREV_TXID: &str = "f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f";
+
+    /// Prev spells with the spell of `token_spell("1500", nft_state)` in tx `PREV_TXID`: its output
+    /// 0 has 1500 tokens, output 1 has the NFT.
+    fn prev_spells(nft_state: &str) -> PrevSpells {
+        let (norm_spell, _) = token_spell("1500", nft_state).normalized().unwrap();
+        BTreeMap::from([(TxId::from_str(PREV_TXID).unwrap(), (Ok(norm_spell), 2))])
+    }
+
+    fn spell_spending(ins: &str) -> Spell {
+        serde_yaml::from_str(&format!(
+            r#"
+version: 2
+apps:
+  $01: t/{IDENTITY}/{VK}
+ins: {ins}
+outs:
+  - charms:
+      $01: 1500
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolves_input_charms() {
+        let prev_spells = prev_spells("{ticker: TOAD, remaining: 5}");
+        let mut spell = spell_spending(&format!(
+            "[{{utxo_id: '{PREV_TXID}:0'}}, {{utxo_id: '{PREV_TXID}:1'}}]"
+        ));
+        spell.resolve_input_charms(&prev_spells).unwrap();
+
+        // the NFT app is added
+        assert_eq!(spell.apps.len(), 2);
+        let tx = spell.to_tx().unwrap();
+        let token = spell.apps["$01"].clone();
+        let nft = ref_nft_app(&token);
+        let ins: Vec<&Charms> = tx.ins.values().collect();
+        assert_eq!(ins[0], &Charms::from([(token, Data::from(&1500))]));
+        assert_eq!(
+            ins[1][&nft]
+                .value::<BTreeMap<String, Data>>()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // matching charms written in the spell (with map fields in a different order)
+        let mut spell = spell_spending(&format!(
+            "[{{utxo_id: '{PREV_TXID}:1', charms: {{$00: {{remaining: 5, ticker: TOAD}}}}}}]"
+        ));
+        spell.apps.insert("$00".to_string(), nft.clone());
+        spell.resolve_input_charms(&prev_spells).unwrap();
+
+        // mismatched charms
+        let mut spell = spell_spending(&format!(
+            "[{{utxo_id: '{PREV_TXID}:0', charms: {{$01: 1000}}}}]"
+        ));
+        let error = spell.resolve_input_charms(&prev_spells).unwrap_err();
+        assert!(error.to_string().contains("don't match"));
+
+        // output without charms
+        let mut spell = spell_spending(&format!(
+            "[{{utxo_id: '{PREV_TXID}:2', charms: {{$01: 1}}}}]"
+        ));
+        assert!(spell.resolve_input_charms(&prev_spells).is_err());
+
+        // missing prev tx
+        let mut spell = spell_spending(&format!("[{{utxo_id: '{}:0'}}]", "ab".repeat(32)));
+        assert!(spell.resolve_input_charms(&prev_spells).is_err());
+    }
+
+    #[test]
+    fn formats_decimal_amounts() {
+        let spell = token_spell("150000000", "{ticker: TOAD, decimals: 8}");
+        let formatted = spell.with_decimal_amounts();
+        assert_eq!(
+            formatted.outs[0].charms.as_ref().unwrap()["$01"],
+            Data::from(&"1.50000000")
+        );
+        assert_eq!(out_amount(&formatted).unwrap(), TokenAmount(150000000));
+
+        let spell = token_spell("150000000", "{ticker: TOAD}");
+        assert_eq!(
+            spell.with_decimal_amounts().outs[0].charms,
+            spell.outs[0].charms
+        );
+    }
+
+    /// Transaction with one output and no spell.
+    fn plain_tx() -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: bitcoin::Amount::from_sat(1000),
+                script_pubkey: bitcoin::ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn refuses_to_prove_incorrect_spells() {
+        let prev_tx = plain_tx();
+        let utxo_id = UtxoId(TxId(prev_tx.compute_txid().to_byte_array()), 0);
+        // the deployed spell checker accepts duplicate inputs: the native check doesn't
+        let norm_spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: Some(vec![utxo_id.clone(), utxo_id]),
+                refs: BTreeSet::new(),
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let e = prove(
+            norm_spell,
+            &BTreeMap::new(),
+            BTreeMap::new(),
+            vec![prev_tx],
+            ProofMode::Mock,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("spent more than once"), "{}", e);
+    }
+
+    #[test]
+    fn checks_app_contracts_before_mock_proving() {
+        let prev_tx = plain_tx();
+        let token = App {
+            tag: TOKEN,
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        // minting tokens without the app binary: not a simple transfer
+        let norm_spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: Some(vec![UtxoId(
+                    TxId(prev_tx.compute_txid().to_byte_array()),
+                    0,
+                )]),
+                refs: BTreeSet::new(),
+                outs: vec![BTreeMap::from([(0, Data::from(&100u64))])],
+            },
+            app_public_inputs: BTreeMap::from([(token, Data::empty())]),
+        };
+        let e = prove(
+            norm_spell,
+            &BTreeMap::new(),
+            BTreeMap::new(),
+            vec![prev_tx],
+            ProofMode::Mock,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("not a simple transfer"), "{}", e);
+    }
 }
 
 pub fn prove_spell_tx(
-    spell: Spell,
+    mut spell: Spell,
     tx: bitcoin::Transaction,
     binaries: BTreeMap<B32, Vec<u8>>,
     prev_txs: BTreeMap<Txid, bitcoin::Transaction>,
-    funding_utxo: OutPoint,
-    funding_utxo_value: u64,
+    funding_utxos: Vec<(OutPoint, TxOut)>,
     change_address: String,
+    refund_key: Option<XOnlyPublicKey>,
     fee_rate: f64,
+    mode: ProofMode,
 ) -> anyhow::Result<[bitcoin::Transaction; 2]> {
+    let prev_spells =
+        charms_client::prev_spells(&prev_txs.values().cloned().collect(), mode.spell_vk());
+    spell.resolve_input_charms(&prev_spells)?;
+
     let (norm_spell, app_private_inputs) = spell.normalized()?;
     let norm_spell = align_spell_to_tx(norm_spell, &tx)?;
 
@@ -380,6 +888,7 @@ pub fn prove_spell_tx(
         &binaries,
         app_private_inputs,
         prev_txs.values().cloned().collect(),
+        mode,
     )?;
 
     // Serialize spell into CBOR
@@ -397,12 +906,12 @@ pub fn prove_spell_tx(
     let transactions = add_spell(
         tx,
         &spell_data,
-        funding_utxo,
-        Amount::from_sat(funding_utxo_value),
+        &funding_utxos,
         change_script_pubkey,
+        refund_key,
         fee_rate,
         &prev_txs,
-    );
+    )?;
     Ok(transactions)
 }
 
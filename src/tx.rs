@@ -1,22 +1,24 @@
 use crate::{
+    coin_select::select_coins,
     script::{control_block, data_script, taproot_spend_info},
-    spell::{Input, Output, Spell},
-    SPELL_VK,
+    spell::{Input, Output, ProofMode, Spell},
 };
+use anyhow::{anyhow, bail, ensure};
 use bitcoin::{
     self,
     absolute::LockTime,
     hashes::Hash,
     key::Secp256k1,
+    script::{Builder, PushBytes},
     secp256k1::{rand::thread_rng, schnorr, Keypair, Message},
     sighash::{Prevouts, SighashCache},
     taproot,
-    taproot::LeafVersion,
+    taproot::{ControlBlock, LeafVersion},
     transaction::Version,
-    Amount, FeeRate, OutPoint, ScriptBuf, TapLeafHash, TapSighashType, Transaction, TxIn, TxOut,
-    Txid, Weight, Witness, XOnlyPublicKey,
+    Amount, FeeRate, OutPoint, Script, ScriptBuf, TapLeafHash, TapNodeHash, TapSighashType,
+    Transaction, TxIn, TxOut, Txid, WPubkeyHash, Weight, Witness, XOnlyPublicKey,
 };
-use charms_client::NormalizedSpell;
+use charms_client::{tx::parse_spell_and_proof, NormalizedSpell};
 use std::collections::BTreeMap;
 
 /// `add_spell` adds `spell` to `tx`:
@@ -24,136 +26,252 @@ use std::collections::BTreeMap;
 /// 2. then appends an input spending the *committed spell* to `tx`, and adds a witness for it.
 ///
 /// `fee_rate` is used to compute the amount of sats necessary to fund the commit and spell
-/// transactions.
+/// transactions: the *committed spell* output gets just enough to pay for the spell tx. The commit
+/// tx spends UTXOs selected from `funding_utxos` (see [`select_coins`]), the rest of their value
+/// goes to the commit tx change output. If `tx` inputs provide more sats than `tx` outputs need,
+/// the excess goes to the spell tx change output. Change below the dust limit is left to fees.
 ///
-/// Return `[commit_tx, tx]`.
+/// Fees are computed from the exact weight of the transactions (with signature sizes at their
+/// maximum), so the scripts of the funding outputs and of the outputs spent by `tx` (found in
+/// `prev_txs`) need to be of a known type (see [`dummy_satisfaction`]).
+///
+/// `refund_key` (if provided) is used as the Taproot internal key of the *committed spell*
+/// output: if `tx` never makes it to the chain, the output can be spent via the key path by the
+/// owner of `refund_key` (see [`recover_commit_tx`]). Otherwise, the output is only spendable with
+/// `tx`.
+///
+/// Return `[commit_tx, tx]`, or an error if the funding UTXOs are insufficient.
 ///
 /// Both `commit_tx` and `tx` need to be signed.
 pub fn add_spell(
     tx: Transaction,
     spell_data: &[u8],
-    funding_out_point: OutPoint,
-    funding_output_value: Amount,
+    funding_utxos: &[(OutPoint, TxOut)],
     change_script_pubkey: ScriptBuf,
+    refund_key: Option<XOnlyPublicKey>,
     fee_rate: FeeRate,
     prev_txs: &BTreeMap<Txid, Transaction>,
-) -> [Transaction; 2] {
+) -> anyhow::Result<[Transaction; 2]> {
     let secp256k1 = Secp256k1::new();
     let keypair = Keypair::new(&secp256k1, &mut thread_rng());
     let (public_key, _) = XOnlyPublicKey::from_keypair(&keypair);
+    let internal_key = refund_key.unwrap_or(public_key);
 
     let script = data_script(public_key, &spell_data);
+    let commit_script_pubkey =
+        ScriptBuf::new_p2tr_tweaked(taproot_spend_info(internal_key, script.clone()).output_key());
 
-    let commit_tx = create_commit_tx(
-        funding_out_point,
-        funding_output_value,
-        public_key,
+    let tx_amount_in = tx_total_amount_in(prev_txs, &tx)?;
+    let (commit_value, change) = spell_tx_funding(
+        &tx,
+        &commit_script_pubkey,
+        internal_key,
         &script,
+        change_script_pubkey.clone(),
+        tx_amount_in,
         fee_rate,
-    );
-    let commit_txout = &commit_tx.output[0];
+        prev_txs,
+    )?;
 
-    let tx_amount_in = tx_total_amount_in(prev_txs, &tx);
-    let change_amount = compute_change_amount(
+    let commit_tx = create_commit_tx(
+        funding_utxos,
+        TxOut {
+            value: commit_value,
+            script_pubkey: commit_script_pubkey,
+        },
+        change_script_pubkey,
         fee_rate,
-        script.len(),
-        &tx,
-        tx_amount_in + commit_txout.value,
-    );
+    )?;
+    let commit_txout = &commit_tx.output[0];
 
     let mut tx = tx;
-    modify_tx(
-        &mut tx,
-        commit_tx.compute_txid(),
-        change_script_pubkey,
-        change_amount,
-    );
+    tx.input.push(TxIn {
+        previous_output: OutPoint::new(commit_tx.compute_txid(), 0),
+        script_sig: Default::default(),
+        sequence: Default::default(),
+        witness: Witness::new(),
+    });
+    tx.output.extend(change);
     let spell_input = tx.input.len() - 1;
 
     let signature = create_tx_signature(keypair, &mut tx, spell_input, &commit_txout, &script);
 
     append_witness_data(
         &mut tx.input[spell_input].witness,
-        public_key,
+        internal_key,
         script,
         signature,
     );
 
-    [commit_tx, tx]
+    Ok([commit_tx, tx])
 }
 
-/// fee covering only the marginal cost of spending the committed spell output.
-fn compute_change_amount(
-    fee_rate: FeeRate,
-    script_len: usize,
+/// Compute the value the *committed spell* output needs to have to pay for `tx` with the input
+/// spending it, and the change output `tx` needs (if any).
+fn spell_tx_funding(
     tx: &Transaction,
-    total_amount_in: Amount,
-) -> Amount {
-    // script input: (41 * 4) + (L + 99) = 164 + L + 99 = L + 263 wu
-    // change output: 42 * 4 = 168 wu
-    let added_weight =
-        Weight::from_witness_data_size(script_len as u64) + Weight::from_wu(263 + 168);
+    commit_script_pubkey: &ScriptBuf,
+    internal_key: XOnlyPublicKey,
+    script: &ScriptBuf,
+    change_script_pubkey: ScriptBuf,
+    tx_amount_in: Amount,
+    fee_rate: FeeRate,
+    prev_txs: &BTreeMap<Txid, Transaction>,
+) -> anyhow::Result<(Amount, Option<TxOut>)> {
+    // `tx` with placeholder signatures of the final size
+    let mut tx = tx.clone();
+    for tx_in in tx.input.iter_mut() {
+        let prev_out = prev_txout(prev_txs, &tx_in.previous_output)?;
+        (tx_in.script_sig, tx_in.witness) = dummy_satisfaction(&prev_out.script_pubkey)?;
+    }
+
+    let mut witness = Witness::new();
+    witness.push([0; 65]); // Schnorr signature with the sighash type byte
+    witness.push(script);
+    witness.push(control_block(internal_key, script.clone()).serialize());
+    tx.input.push(TxIn {
+        previous_output: OutPoint::null(),
+        script_sig: Default::default(),
+        sequence: Default::default(),
+        witness,
+    });
 
-    let total_tx_weight = tx.weight() + added_weight;
-    let fee = fee_rate.fee_wu(total_tx_weight).unwrap();
+    let tx_amount_out = tx_total_amount_out(&tx);
+    let fee = tx_fee(fee_rate, &tx)?;
 
-    let tx_amount_out = tx.output.iter().map(|tx_out| tx_out.value).sum::<Amount>();
+    // the committed spell output must pay for what `tx` inputs don't, and not be dust itself
+    let commit_value = (tx_amount_out + fee)
+        .checked_sub(tx_amount_in)
+        .unwrap_or(Amount::ZERO)
+        .max(commit_script_pubkey.minimal_non_dust());
 
-    total_amount_in - tx_amount_out - fee
+    tx.output.push(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change_script_pubkey,
+    });
+    let fee_with_change = tx_fee(fee_rate, &tx)?;
+    let mut change = tx.output.pop().expect("change output should exist");
+    change.value = (tx_amount_in + commit_value)
+        .checked_sub(tx_amount_out + fee_with_change)
+        .unwrap_or(Amount::ZERO);
+
+    match change.value >= change.script_pubkey.minimal_non_dust() {
+        true => Ok((commit_value, Some(change))),
+        false => Ok((commit_value, None)),
+    }
 }
 
 fn create_commit_tx(
-    funding_out_point: OutPoint,
-    funding_output_value: Amount,
-    public_key: XOnlyPublicKey,
-    script: &ScriptBuf,
+    funding_utxos: &[(OutPoint, TxOut)],
+    commit_txout: TxOut,
+    change_script_pubkey: ScriptBuf,
     fee_rate: FeeRate,
-) -> Transaction {
-    let fee = fee_rate.fee_vb(111).unwrap(); // tx is 111 vbytes when spending a Taproot output
-
-    let commit_tx = Transaction {
+) -> anyhow::Result<Transaction> {
+    let mut commit_tx = Transaction {
         version: Version::TWO,
         lock_time: LockTime::ZERO,
-        input: vec![TxIn {
-            previous_output: funding_out_point,
-            script_sig: Default::default(),
+        input: vec![],
+        output: vec![commit_txout],
+    };
+    let change = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change_script_pubkey,
+    };
+
+    // fee for the tx without inputs, plus segwit marker and flag
+    let fee = fee_rate
+        .fee_wu(commit_tx.weight() + Weight::from_wu(2))
+        .ok_or(anyhow!("fee computation overflow"))?;
+    let target = commit_tx.output[0].value + fee;
+    let cost_of_change = fee_rate.fee_wu(change.weight()).unwrap_or(Amount::MAX)
+        + change.script_pubkey.minimal_non_dust();
+    let selected = select_coins(funding_utxos, target, cost_of_change, fee_rate)?;
+
+    // placeholder signatures of the final size
+    for (out_point, utxo) in &selected {
+        let (script_sig, witness) = dummy_satisfaction(&utxo.script_pubkey)?;
+        commit_tx.input.push(TxIn {
+            previous_output: *out_point,
+            script_sig,
             sequence: Default::default(),
-            witness: Default::default(),
-        }],
-        output: vec![TxOut {
-            value: funding_output_value - fee,
-            script_pubkey: ScriptBuf::new_p2tr_tweaked(
-                taproot_spend_info(public_key, script.clone()).output_key(),
-            ),
-        }],
+            witness,
+        });
+    }
+
+    let amount_in = selected.iter().map(|(_, utxo)| utxo.value).sum::<Amount>();
+    let fee = tx_fee(fee_rate, &commit_tx)?;
+    let amount_out = commit_tx.output[0].value;
+    let Some(change_amount) = amount_in.checked_sub(amount_out + fee) else {
+        bail!(
+            "insufficient funds: selected UTXOs have {}, need at least {} (at {} sat/vB)",
+            amount_in,
+            amount_out + fee,
+            fee_rate.to_sat_per_vb_ceil()
+        );
     };
 
-    commit_tx
+    commit_tx.output.push(change);
+    let change_fee = tx_fee(fee_rate, &commit_tx)? - fee;
+    match change_amount.checked_sub(change_fee) {
+        Some(change_amount)
+            if change_amount >= commit_tx.output[1].script_pubkey.minimal_non_dust() =>
+        {
+            commit_tx.output[1].value = change_amount;
+        }
+        _ => {
+            commit_tx.output.pop(); // dust: leave to fees
+        }
+    }
+
+    for tx_in in commit_tx.input.iter_mut() {
+        tx_in.script_sig = ScriptBuf::new();
+        tx_in.witness = Witness::new();
+    }
+
+    Ok(commit_tx)
 }
 
-fn modify_tx(
-    tx: &mut Transaction,
-    commit_txid: Txid,
-    change_script_pubkey: ScriptBuf,
-    change_amount: Amount,
-) {
-    tx.input.push(TxIn {
-        previous_output: OutPoint {
-            txid: commit_txid,
-            vout: 0,
-        },
-        script_sig: Default::default(),
-        sequence: Default::default(),
-        witness: Witness::new(),
-    });
-    tx.output.push(TxOut {
-        value: change_amount,
-        script_pubkey: change_script_pubkey,
-    });
+fn tx_fee(fee_rate: FeeRate, tx: &Transaction) -> anyhow::Result<Amount> {
+    fee_rate
+        .fee_wu(tx.weight())
+        .ok_or(anyhow!("fee computation overflow"))
+}
+
+/// Placeholder `script_sig` and witness of the size of those spending an output with
+/// `script_pubkey`, with signatures of the maximum size. Supported output types: P2TR (key path
+/// spend), P2WPKH, P2SH-wrapped P2WPKH and P2PKH.
+pub fn dummy_satisfaction(script_pubkey: &Script) -> anyhow::Result<(ScriptBuf, Witness)> {
+    const ECDSA_SIGNATURE: [u8; 73] = [0; 73]; // DER-encoded, with the sighash type byte
+    const PUBLIC_KEY: [u8; 33] = [0; 33];
 
-    if change_amount >= Amount::from_sat(546) {
-        // dust limit
+    if script_pubkey.is_p2tr() {
+        // Schnorr signature with the default sighash type (no sighash type byte)
+        return Ok((ScriptBuf::new(), Witness::from_slice(&[[0; 64]])));
+    }
+    if script_pubkey.is_p2wpkh() {
+        return Ok((
+            ScriptBuf::new(),
+            Witness::from_slice(&[&ECDSA_SIGNATURE[..], &PUBLIC_KEY[..]]),
+        ));
+    }
+    if script_pubkey.is_p2sh() {
+        let redeem_script = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let script_sig = Builder::new()
+            .push_slice(<&PushBytes>::try_from(redeem_script.as_bytes())?)
+            .into_script();
+        return Ok((
+            script_sig,
+            Witness::from_slice(&[&ECDSA_SIGNATURE[..], &PUBLIC_KEY[..]]),
+        ));
     }
+    if script_pubkey.is_p2pkh() {
+        let script_sig = Builder::new()
+            .push_slice(ECDSA_SIGNATURE)
+            .push_slice(PUBLIC_KEY)
+            .into_script();
+        return Ok((script_sig, Witness::new()));
+    }
+    bail!("unsupported output type: {}", script_pubkey)
 }
 
 fn create_tx_signature(
@@ -184,7 +302,7 @@ fn create_tx_signature(
 
 fn append_witness_data(
     witness: &mut Witness,
-    public_key: XOnlyPublicKey,
+    internal_key: XOnlyPublicKey,
     script: ScriptBuf,
     signature: schnorr::Signature,
 ) {
@@ -196,15 +314,111 @@ fn append_witness_data(
         .to_vec(),
     );
     witness.push(script.clone());
-    witness.push(control_block(public_key, script).serialize());
+    witness.push(control_block(internal_key, script).serialize());
+}
+
+/// The *committed spell* output spent by a spell tx (made by [`add_spell`]), and what's needed to
+/// spend it via the Taproot key path instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitSpendInfo {
+    pub out_point: OutPoint,
+    /// Taproot internal key: the refund key passed to [`add_spell`].
+    pub internal_key: XOnlyPublicKey,
+    /// Merkle root of the output's script tree (the single spell leaf).
+    pub merkle_root: TapNodeHash,
+}
+
+/// Get the [`CommitSpendInfo`] for the *committed spell* output spent by the last input of
+/// `spell_tx` (with the witness `[signature, script, control_block]`).
+pub fn commit_spend_info(spell_tx: &Transaction) -> anyhow::Result<CommitSpendInfo> {
+    let spell_input = spell_tx.input.last().ok_or(anyhow!("tx has no inputs"))?;
+    let [_signature, script, control_block] =
+        spell_input.witness.to_vec().try_into().map_err(|_| {
+            anyhow!("spell input witness should be [signature, script, control_block]")
+        })?;
+    let control_block = ControlBlock::decode(&control_block)?;
+    ensure!(
+        control_block.merkle_branch.is_empty(),
+        "committed spell output should have a single leaf script"
+    );
+    let script = ScriptBuf::from_bytes(script);
+
+    Ok(CommitSpendInfo {
+        out_point: spell_input.previous_output,
+        internal_key: control_block.internal_key,
+        merkle_root: TapNodeHash::from_script(&script, LeafVersion::TapScript),
+    })
 }
 
-pub fn norm_spell(tx: &Transaction) -> Option<NormalizedSpell> {
-    charms_client::tx::extract_and_verify_spell(&tx, SPELL_VK).ok()
+/// Create an (unsigned) transaction sweeping the *committed spell* output `commit_txout` (see
+/// [`commit_spend_info`]) to `script_pubkey`, paying the fee at `fee_rate`.
+///
+/// The transaction spends the output via the Taproot key path: it needs to be signed with the
+/// refund key ([`CommitSpendInfo::internal_key`]) tweaked with [`CommitSpendInfo::merkle_root`].
+pub fn recover_commit_tx(
+    info: &CommitSpendInfo,
+    commit_txout: &TxOut,
+    script_pubkey: ScriptBuf,
+    fee_rate: FeeRate,
+) -> anyhow::Result<Transaction> {
+    let secp256k1 = Secp256k1::new();
+    ensure!(
+        commit_txout.script_pubkey
+            == ScriptBuf::new_p2tr(&secp256k1, info.internal_key, Some(info.merkle_root)),
+        "output {} is not the committed spell output of the spell tx",
+        info.out_point
+    );
+
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: info.out_point,
+            script_sig: Default::default(),
+            sequence: Default::default(),
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        }],
+    };
+
+    // segwit marker and flag: 2 wu, key path witness (64-byte signature): 1 + 1 + 64 = 66 wu
+    let weight = tx.weight() + Weight::from_wu(2 + 66);
+    let fee = fee_rate
+        .fee_wu(weight)
+        .ok_or(anyhow!("fee computation overflow"))?;
+    let value = commit_txout
+        .value
+        .checked_sub(fee)
+        .ok_or(anyhow!("committed spell output value is less than the fee"))?;
+    ensure!(
+        value >= tx.output[0].script_pubkey.minimal_non_dust(),
+        "recovered amount {} is below the dust limit",
+        value
+    );
+    tx.output[0].value = value;
+
+    Ok(tx)
 }
 
-pub fn spell(tx: &Transaction) -> Option<Spell> {
-    match norm_spell(tx) {
+/// Whether output `vout` of `tx` may carry charms: `tx` has a spell assigning charms to the output,
+/// even if the spell proof is not valid (or not checked).
+pub fn may_carry_charms(tx: &Transaction, vout: u32) -> bool {
+    tx.input
+        .last()
+        .and_then(|tx_in| parse_spell_and_proof(tx_in).ok())
+        .and_then(|(spell, _)| spell.tx.outs.get(vout as usize).map(|c| !c.is_empty()))
+        .unwrap_or(false)
+}
+
+pub fn norm_spell(tx: &Transaction, mode: ProofMode) -> Option<NormalizedSpell> {
+    charms_client::tx::extract_and_verify_spell(&tx, mode.spell_vk()).ok()
+}
+
+pub fn spell(tx: &Transaction, mode: ProofMode) -> Option<Spell> {
+    match norm_spell(tx, mode) {
         Some(norm_spell) => Some(Spell::denormalized(&norm_spell)),
         None => None,
     }
@@ -217,12 +431,25 @@ pub fn txs_by_txid(prev_txs: Vec<Transaction>) -> anyhow::Result<BTreeMap<Txid,
         .collect::<anyhow::Result<BTreeMap<_, _>>>()
 }
 
-pub fn tx_total_amount_in(prev_txs: &BTreeMap<Txid, Transaction>, tx: &Transaction) -> Amount {
+pub fn tx_total_amount_in(
+    prev_txs: &BTreeMap<Txid, Transaction>,
+    tx: &Transaction,
+) -> anyhow::Result<Amount> {
     tx.input
         .iter()
-        .map(|tx_in| (tx_in.previous_output.txid, tx_in.previous_output.vout))
-        .map(|(tx_id, i)| prev_txs[&tx_id].output[i as usize].value)
-        .sum::<Amount>()
+        .map(|tx_in| Ok(prev_txout(prev_txs, &tx_in.previous_output)?.value))
+        .sum::<anyhow::Result<Amount>>()
+}
+
+fn prev_txout(
+    prev_txs: &BTreeMap<Txid, Transaction>,
+    out_point: &OutPoint,
+) -> anyhow::Result<TxOut> {
+    prev_txs
+        .get(&out_point.txid)
+        .and_then(|tx| tx.output.get(out_point.vout as usize))
+        .cloned()
+        .ok_or(anyhow!("prev tx output {} not found", out_point))
 }
 
 pub fn tx_total_amount_out(tx: &Transaction) -> Amount {
@@ -272,3 +499,215 @@ pub fn from_spell(spell: &Spell) -> Transaction {
     };
     tx
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{PubkeyHash, WScriptHash};
+
+    fn p2tr() -> ScriptBuf {
+        let secp256k1 = Secp256k1::new();
+        let (public_key, _) = Keypair::new(&secp256k1, &mut thread_rng()).x_only_public_key();
+        ScriptBuf::new_p2tr(&secp256k1, public_key, None)
+    }
+
+    fn tx(input: Vec<OutPoint>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: input
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output,
+        }
+    }
+
+    fn txout(sats: u64, script_pubkey: &ScriptBuf) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: script_pubkey.clone(),
+        }
+    }
+
+    struct Setup {
+        prev_txs: BTreeMap<Txid, Transaction>,
+        spell_tx: Transaction,
+        funding: OutPoint,
+    }
+
+    /// Spell tx spending an output of `in_sats` and creating an output of `out_sats`. The prev tx
+    /// also creates the funding output of `funding`.
+    fn setup(in_sats: u64, out_sats: u64, funding: &TxOut) -> Setup {
+        let prev_tx = tx(
+            vec![OutPoint::null()],
+            vec![txout(in_sats, &p2tr()), funding.clone()],
+        );
+        let prev_txid = prev_tx.compute_txid();
+        Setup {
+            prev_txs: BTreeMap::from([(prev_txid, prev_tx)]),
+            spell_tx: tx(
+                vec![OutPoint::new(prev_txid, 0)],
+                vec![txout(out_sats, &p2tr())],
+            ),
+            funding: OutPoint::new(prev_txid, 1),
+        }
+    }
+
+    fn add_spell_with(setup: &Setup, funding: &TxOut) -> anyhow::Result<[Transaction; 2]> {
+        add_spell(
+            setup.spell_tx.clone(),
+            b"spell data",
+            &[(setup.funding, funding.clone())],
+            p2tr(),
+            None,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &setup.prev_txs,
+        )
+    }
+
+    /// Fee paid by `tx` (with placeholder signatures of the final size) and the fee it should pay.
+    fn fees(tx: &Transaction, prev_outs: &[TxOut]) -> (Amount, Amount) {
+        let mut tx = tx.clone();
+        for (tx_in, prev_out) in tx.input.iter_mut().zip(prev_outs) {
+            if tx_in.witness.is_empty() {
+                (tx_in.script_sig, tx_in.witness) =
+                    dummy_satisfaction(&prev_out.script_pubkey).unwrap();
+            }
+        }
+        let amount_in = prev_outs.iter().map(|tx_out| tx_out.value).sum::<Amount>();
+        (
+            amount_in - tx_total_amount_out(&tx),
+            FeeRate::from_sat_per_vb(2)
+                .unwrap()
+                .fee_wu(tx.weight())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn fees_match_tx_weights() {
+        let funding_scripts = [
+            p2tr(),
+            ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros()),
+            ScriptBuf::new_p2sh(&ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()).script_hash()),
+        ];
+        for funding_script in funding_scripts {
+            let funding = txout(20_000, &funding_script);
+            let setup = setup(5_000, 1_000, &funding);
+            let [commit_tx, spell_tx] = add_spell_with(&setup, &funding).unwrap();
+
+            // spell tx inputs pay for the spell tx: the committed spell output is just not dust
+            let commit_txout = &commit_tx.output[0];
+            assert_eq!(
+                commit_txout.value,
+                commit_txout.script_pubkey.minimal_non_dust()
+            );
+            assert_eq!(commit_tx.output.len(), 2); // with change
+
+            let (paid, expected) = fees(&commit_tx, &[funding.clone()]);
+            assert_eq!(paid, expected);
+
+            assert_eq!(spell_tx.output.len(), 2); // with change
+            let prev_outs = [
+                setup.prev_txs.values().next().unwrap().output[0].clone(),
+                commit_txout.clone(),
+            ];
+            let (paid, expected) = fees(&spell_tx, &prev_outs);
+            assert_eq!(paid, expected);
+        }
+    }
+
+    #[test]
+    fn committed_spell_output_pays_for_spell_tx() {
+        let funding = txout(20_000, &p2tr());
+        let setup = setup(1_000, 1_000, &funding);
+        let [commit_tx, spell_tx] = add_spell_with(&setup, &funding).unwrap();
+
+        // no change: it would be dust
+        assert_eq!(spell_tx.output.len(), 1);
+        let prev_outs = [
+            setup.prev_txs.values().next().unwrap().output[0].clone(),
+            commit_tx.output[0].clone(),
+        ];
+        let (paid, expected) = fees(&spell_tx, &prev_outs);
+        assert_eq!(paid, expected);
+    }
+
+    #[test]
+    fn dust_change_goes_to_fees() {
+        let funding = txout(20_000, &p2tr());
+        let setup = setup(5_000, 1_000, &funding);
+        let [commit_tx, _] = add_spell_with(&setup, &funding).unwrap();
+        let change = commit_tx.output[1].value;
+
+        // change would be below the dust limit
+        let funding = txout(20_000 - change.to_sat() + 100, &p2tr());
+        let [commit_tx, _] = add_spell_with(&setup, &funding).unwrap();
+        assert_eq!(commit_tx.output.len(), 1);
+        let (paid, expected) = fees(&commit_tx, &[funding]);
+        assert!(paid > expected);
+    }
+
+    #[test]
+    fn funds_from_several_utxos() {
+        let setup = setup(1_000, 5_000, &txout(0, &p2tr()));
+        let funding_utxos: Vec<(OutPoint, TxOut)> = [3_000, 3_000, 3_000, 100]
+            .into_iter()
+            .zip(0..)
+            .map(|(sats, vout)| {
+                (
+                    OutPoint::new(Txid::all_zeros(), vout),
+                    txout(sats, &ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros())),
+                )
+            })
+            .collect();
+        let [commit_tx, spell_tx] = add_spell(
+            setup.spell_tx.clone(),
+            b"spell data",
+            &funding_utxos,
+            p2tr(),
+            None,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &setup.prev_txs,
+        )
+        .unwrap();
+
+        // the committed spell output needs more than any single UTXO has
+        assert!(commit_tx.output[0].value > Amount::from_sat(3_000));
+        assert_eq!(commit_tx.input.len(), 2);
+        let prev_outs: Vec<TxOut> = commit_tx
+            .input
+            .iter()
+            .map(|tx_in| funding_utxos[tx_in.previous_output.vout as usize].1.clone())
+            .collect();
+        let (paid, expected) = fees(&commit_tx, &prev_outs);
+        assert!(paid >= expected);
+
+        let prev_outs = [
+            setup.prev_txs.values().next().unwrap().output[0].clone(),
+            commit_tx.output[0].clone(),
+        ];
+        let (paid, expected) = fees(&spell_tx, &prev_outs);
+        assert!(paid >= expected);
+    }
+
+    #[test]
+    fn insufficient_funding_is_an_error() {
+        let funding = txout(500, &p2tr());
+        let setup = setup(1_000, 1_000, &funding);
+        let error = add_spell_with(&setup, &funding).unwrap_err();
+        assert!(error.to_string().contains("insufficient funds"));
+    }
+
+    #[test]
+    fn unsupported_funding_output_type_is_an_error() {
+        let funding = txout(20_000, &ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()));
+        let setup = setup(1_000, 1_000, &funding);
+        assert!(add_spell_with(&setup, &funding).is_err());
+    }
+}
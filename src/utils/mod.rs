@@ -1,6 +1,82 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
 pub(crate) mod logger;
 
 /// Create a string representation of the index `i` in the format `$xxxx`.
 pub fn str_index(i: &usize) -> String {
     format!("${:04}", i)
 }
+
+/// Apply `f` to all `items` using up to `workers` threads.
+/// Results are in the order of `items`.
+pub(crate) fn parallel_map<T, R, F>(items: &[T], workers: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<R>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|s| {
+        for _ in 0..workers.clamp(1, items.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                let result = f(item);
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| {
+            let result = result.into_inner().unwrap();
+            result.expect("all items should be processed")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use std::time::Duration;
+    use test_strategy::proptest;
+
+    #[proptest]
+    fn parallel_map_keeps_order(items: Vec<u8>, #[strategy(0..8usize)] workers: usize) {
+        let results = parallel_map(&items, workers, |&item| {
+            // finish out of order
+            thread::sleep(Duration::from_micros((item % 7) as u64 * 10));
+            item as u32 * 2
+        });
+        prop_assert_eq!(
+            results,
+            items
+                .iter()
+                .map(|&item| item as u32 * 2)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parallel_map_bounds_workers() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        parallel_map(&[(); 16], 3, |_| {
+            let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(n, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+    }
+}